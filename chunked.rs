@@ -0,0 +1,192 @@
+use std::io::{self, Read};
+
+use crate::{find_subsequence, is_token_char, ParserLimits};
+
+// Why a chunked request body couldn't be read
+#[derive(Debug)]
+pub enum ChunkedError {
+    // Reading from the connection failed
+    Io(io::Error),
+    // Bad chunk size, missing CRLF, or a bad or folded trailer line
+    Malformed,
+    // The decoded body is larger than `max_body`
+    TooLarge,
+}
+
+impl From<io::Error> for ChunkedError {
+    fn from(e: io::Error) -> Self {
+        ChunkedError::Io(e)
+    }
+}
+
+// A decoded chunked body, with any trailer fields that followed it
+pub struct ChunkedBody {
+    pub body: Vec<u8>,
+    pub trailers: Vec<(String, String)>,
+    // Offset in the buffer just past the body's final CRLF
+    pub end: usize,
+}
+
+// Decode a `Transfer-Encoding: chunked` body starting at `start` in the
+// buffer, reading more from the stream as needed. Bytes after the body stay
+// in the buffer for the next request.
+pub fn read_chunked_body(
+    stream: &mut impl Read,
+    buffer: &mut Vec<u8>,
+    start: usize,
+    limits: &ParserLimits,
+) -> Result<ChunkedBody, ChunkedError> {
+    let mut body = Vec::new();
+    let mut pos = start;
+
+    loop {
+        // Chunk size in hex, optionally followed by `;extensions`. Only hex
+        // digits count, since `from_str_radix` alone would take a sign and
+        // other parsers might read `+5` or ` 5` differently.
+        let line_end = read_line(stream, buffer, pos, limits.max_header_line)?;
+        let line = String::from_utf8_lossy(&buffer[pos..line_end]);
+        let size = line.split(';').next().unwrap_or("");
+        if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(ChunkedError::Malformed);
+        }
+        let size = usize::from_str_radix(size, 16).map_err(|_| ChunkedError::Malformed)?;
+        pos = line_end + 2;
+
+        if size == 0 {
+            break;
+        }
+        if body.len().saturating_add(size) > limits.max_body {
+            return Err(ChunkedError::TooLarge);
+        }
+
+        fill(stream, buffer, pos + size + 2)?;
+        if &buffer[pos + size..pos + size + 2] != b"\r\n" {
+            return Err(ChunkedError::Malformed);
+        }
+        body.extend_from_slice(&buffer[pos..pos + size]);
+        pos += size + 2;
+    }
+
+    // Trailer fields up to the blank line ending the message
+    let mut trailers = Vec::new();
+    loop {
+        let line_end = read_line(stream, buffer, pos, limits.max_header_line)?;
+        let line = String::from_utf8_lossy(&buffer[pos..line_end]).into_owned();
+        pos = line_end + 2;
+        if line.is_empty() {
+            break;
+        }
+
+        // Folded lines are refused here just as in the head
+        if trailers.len() >= limits.max_headers || line.starts_with([' ', '\t']) {
+            return Err(ChunkedError::Malformed);
+        }
+        // As in the head, a name is a token with nothing before the colon
+        let (key, value) = line.split_once(':')
+            .filter(|(key, _)| !key.is_empty() && key.bytes().all(is_token_char))
+            .ok_or(ChunkedError::Malformed)?;
+        trailers.push((key.to_string(), value.trim_matches([' ', '\t']).to_string()));
+    }
+
+    Ok(ChunkedBody { body, trailers, end: pos })
+}
+
+// Read until a CRLF follows `start`, returning its offset. Lines longer
+// than `max_len` are malformed.
+fn read_line(
+    stream: &mut impl Read,
+    buffer: &mut Vec<u8>,
+    start: usize,
+    max_len: usize,
+) -> Result<usize, ChunkedError> {
+    loop {
+        if let Some(pos) = find_subsequence(&buffer[start..], b"\r\n") {
+            return Ok(start + pos);
+        }
+        if buffer.len() - start > max_len {
+            return Err(ChunkedError::Malformed);
+        }
+        read_more(stream, buffer)?;
+    }
+}
+
+// Read until the buffer holds at least `len` bytes
+fn fill(stream: &mut impl Read, buffer: &mut Vec<u8>, len: usize) -> Result<(), ChunkedError> {
+    while buffer.len() < len {
+        read_more(stream, buffer)?;
+    }
+    Ok(())
+}
+
+fn read_more(stream: &mut impl Read, buffer: &mut Vec<u8>) -> io::Result<()> {
+    let mut chunk = [0; 4096];
+    let size = stream.read(&mut chunk)?;
+    if size == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    buffer.extend_from_slice(&chunk[..size]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(body: &[u8]) -> Result<ChunkedBody, ChunkedError> {
+        let mut buffer = body.to_vec();
+        read_chunked_body(&mut io::empty(), &mut buffer, 0, &ParserLimits::default())
+    }
+
+    #[test]
+    fn decodes_chunks_and_trailers() {
+        let raw = b"5\r\nhello\r\na;name=value\r\n, world!!!\r\n0\r\nChecksum: abc\r\n\r\nNEXT";
+        let decoded = decode(raw).unwrap();
+        assert_eq!(decoded.body, b"hello, world!!!");
+        assert_eq!(decoded.trailers, vec![("Checksum".to_string(), "abc".to_string())]);
+        assert_eq!(&raw[decoded.end..], b"NEXT");
+    }
+
+    #[test]
+    fn reads_the_rest_from_the_stream() {
+        let mut buffer = b"3\r\nab".to_vec();
+        let decoded = read_chunked_body(&mut &b"c\r\n0\r\n\r\n"[..], &mut buffer, 0, &ParserLimits::default()).unwrap();
+        assert_eq!(decoded.body, b"abc");
+    }
+
+    #[test]
+    fn chunk_sizes_are_hex_digits_only() {
+        for size in ["+5", "-5", " 5", "5 ", "0x5", "", "g", "5\t"] {
+            let body = format!("{}\r\nhello\r\n0\r\n\r\n", size);
+            assert!(matches!(decode(body.as_bytes()), Err(ChunkedError::Malformed)), "accepted {:?}", size);
+        }
+        let overflow = format!("{}\r\n", "f".repeat(40));
+        assert!(matches!(decode(overflow.as_bytes()), Err(ChunkedError::Malformed)));
+        assert_eq!(decode(b"0005\r\nhello\r\n0\r\n\r\n").unwrap().body, b"hello");
+    }
+
+    #[test]
+    fn refuses_bad_framing() {
+        assert!(matches!(decode(b"5\r\nhelloXX0\r\n\r\n"), Err(ChunkedError::Malformed)));
+        assert!(matches!(decode(b"0\r\n folded: x\r\n\r\n"), Err(ChunkedError::Malformed)));
+        assert!(matches!(decode(b"0\r\nno colon\r\n\r\n"), Err(ChunkedError::Malformed)));
+        assert!(matches!(decode(b"5\r\nhel"), Err(ChunkedError::Io(_))));
+    }
+
+    #[test]
+    fn trailer_names_are_tokens() {
+        for line in [": x", "Check sum: x", "Checksum : x", " Checksum: x", "Check\u{e9}: x", "Check[sum]: x"] {
+            let body = format!("0\r\n{}\r\n\r\n", line);
+            assert!(matches!(decode(body.as_bytes()), Err(ChunkedError::Malformed)), "accepted {:?}", line);
+        }
+        let decoded = decode(b"0\r\nX-Check.sum~1:abc \r\n\r\n").unwrap();
+        assert_eq!(decoded.trailers, vec![("X-Check.sum~1".to_string(), "abc".to_string())]);
+    }
+
+    #[test]
+    fn caps_the_decoded_size() {
+        let limits = ParserLimits { max_body: 4, ..ParserLimits::default() };
+        let mut buffer = b"3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n".to_vec();
+        let result = read_chunked_body(&mut io::empty(), &mut buffer, 0, &limits);
+        assert!(matches!(result, Err(ChunkedError::TooLarge)));
+    }
+}
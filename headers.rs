@@ -0,0 +1,148 @@
+use crate::HttpRequest;
+
+// The request's `Content-Length`, `None` when it has none or it isn't valid:
+// anything but decimal digits, or several values that disagree, which is
+// how a body's length gets read differently by two parsers
+pub fn content_length(request: &HttpRequest) -> Option<u64> {
+    let mut length = None;
+    for value in headers_named(request, "Content-Length").flat_map(|value| value.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        let value = value.parse().ok()?;
+        if length.is_some_and(|length| length != value) {
+            return None;
+        }
+        length = Some(value);
+    }
+    length
+}
+
+// A `bytes=` range set as sent, before it's fit to a resource's length
+#[derive(Debug, PartialEq)]
+pub struct RangeSpec(pub Vec<ByteRange>);
+
+// One range of a `Range` header
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    // `start-end`, both inclusive
+    FromTo(u64, u64),
+    // `start-`, to the end
+    From(u64),
+    // `-len`, the last `len` bytes
+    Suffix(u64),
+}
+
+// The request's `Range`, `None` when it has none or it isn't a valid set
+// of byte ranges, such as another unit, a range ending before it starts or
+// a missing number
+pub fn range(request: &HttpRequest) -> Option<RangeSpec> {
+    let set = request.header("Range")?.trim().strip_prefix("bytes=")?;
+    let number = |text: &str| -> Option<u64> {
+        let text = text.trim();
+        (!text.is_empty() && text.bytes().all(|byte| byte.is_ascii_digit())).then(|| text.parse().ok())?
+    };
+
+    let mut ranges = Vec::new();
+    for spec in set.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        let (start, end) = spec.split_once('-')?;
+        let range = match (start.trim(), end.trim()) {
+            ("", end) => ByteRange::Suffix(number(end)?),
+            (start, "") => ByteRange::From(number(start)?),
+            (start, end) => {
+                let (start, end) = (number(start)?, number(end)?);
+                if start > end {
+                    return None;
+                }
+                ByteRange::FromTo(start, end)
+            },
+        };
+        ranges.push(range);
+    }
+    (!ranges.is_empty()).then_some(RangeSpec(ranges))
+}
+
+// The codings of the request's `Accept-Encoding` with their quality, in the
+// order sent, names lowercased. A coding without `q` has 1. Entries with a
+// quality outside 0 to 1, or one that isn't a number, are left out.
+pub fn accept_encoding(request: &HttpRequest) -> Vec<(String, f32)> {
+    let Some(accept) = request.header("Accept-Encoding") else {
+        return Vec::new();
+    };
+
+    accept
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let name = params.next().unwrap_or("").trim();
+            if name.is_empty() {
+                return None;
+            }
+            let quality = match params.find_map(|param| param.trim().strip_prefix("q=")) {
+                Some(quality) => quality.trim().parse::<f32>().ok().filter(|quality| (0.0..=1.0).contains(quality))?,
+                None => 1.0,
+            };
+            Some((name.to_ascii_lowercase(), quality))
+        })
+        .collect()
+}
+
+fn headers_named<'a>(request: &'a HttpRequest, name: &'a str) -> impl Iterator<Item = &'a str> {
+    request.headers.iter()
+        .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ParserLimits;
+
+    // A request with these header lines, set directly so that values the
+    // parser would refuse still reach the functions under test
+    fn with_headers(lines: &[&str]) -> HttpRequest {
+        let mut request = HttpRequest::parse(b"GET / HTTP/1.1\r\n\r\n", &ParserLimits::default()).unwrap();
+        request.headers = lines.iter()
+            .map(|line| line.split_once(':').unwrap())
+            .map(|(key, value)| (key.to_string(), value.trim().to_string()))
+            .collect();
+        request
+    }
+
+    #[test]
+    fn content_length_is_decimal_digits_that_agree() {
+        let length = |lines: &[&str]| content_length(&with_headers(lines));
+        assert_eq!(length(&[]), None);
+        assert_eq!(length(&["Content-Length: 42"]), Some(42));
+        assert_eq!(length(&["content-length: 007"]), Some(7));
+        assert_eq!(length(&["Content-Length: 5, 5", "Content-Length: 5"]), Some(5));
+        for bad in ["+5", "-5", "0x5", "5 5", "", "5,", "99999999999999999999999"] {
+            assert_eq!(length(&[&format!("Content-Length: {}", bad)]), None, "{:?}", bad);
+        }
+        assert_eq!(length(&["Content-Length: 5", "Content-Length: 6"]), None);
+    }
+
+    #[test]
+    fn range_parses_byte_range_sets() {
+        let parsed = |value: &str| range(&with_headers(&[&format!("Range: {}", value)]));
+        assert_eq!(
+            parsed("bytes=0-99, 200-, -50"),
+            Some(RangeSpec(vec![ByteRange::FromTo(0, 99), ByteRange::From(200), ByteRange::Suffix(50)])),
+        );
+        assert_eq!(parsed("bytes=,5-5,"), Some(RangeSpec(vec![ByteRange::FromTo(5, 5)])));
+        for bad in ["items=0-1", "bytes=", "bytes=5-4", "bytes=-", "bytes=a-b", "bytes=1", "bytes=+1-2", "bytes=0-1,x"] {
+            assert_eq!(parsed(bad), None, "{:?}", bad);
+        }
+        assert_eq!(range(&with_headers(&[])), None);
+    }
+
+    #[test]
+    fn accept_encoding_keeps_valid_qualities_in_order() {
+        let accepted = |value: &str| accept_encoding(&with_headers(&[&format!("Accept-Encoding: {}", value)]));
+        let expected = [("gzip", 1.0), ("br", 0.5), ("identity", 0.0)].map(|(name, q)| (name.to_string(), q));
+        assert_eq!(accepted("GZIP, br;q=0.5 , identity; q=0"), expected);
+        assert_eq!(accepted("gzip;q=2, br;q=abc, deflate;q=-1, , *;q=0.1"), [("*".to_string(), 0.1)]);
+        assert!(accept_encoding(&with_headers(&[])).is_empty());
+    }
+}
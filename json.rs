@@ -0,0 +1,391 @@
+use std::fmt;
+use std::io::{self, Read};
+
+// A parsed JSON document. Its `Display` writes it back as compact JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    // Members in document order
+    Object(Vec<(String, JsonValue)>),
+}
+
+// Bounds on the documents `parse_json` accepts
+pub struct JsonLimits {
+    // Arrays and objects nested deeper than this are refused
+    pub max_depth: usize,
+    // Largest document in bytes, whitespace included
+    pub max_size: usize,
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonValue::Null => f.write_str("null"),
+            JsonValue::Bool(value) => write!(f, "{}", value),
+            JsonValue::Number(value) => write!(f, "{}", value),
+            JsonValue::String(value) => write_string(f, value),
+            JsonValue::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            },
+            JsonValue::Object(members) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            },
+        }
+    }
+}
+
+// `value` as a quoted JSON string, escaping quotes, backslashes and control
+// characters
+fn write_string(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c < ' ' => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl Default for JsonLimits {
+    fn default() -> Self {
+        JsonLimits { max_depth: 64, max_size: 1024 * 1024 }
+    }
+}
+
+// Why a document couldn't be parsed
+#[derive(Debug)]
+pub enum JsonError {
+    // Reading the input failed
+    Io(io::Error),
+    // Not valid JSON, or more than one value
+    Syntax,
+    // Nested deeper than `max_depth`
+    TooDeep,
+    // Longer than `max_size`
+    TooLarge,
+}
+
+impl From<io::Error> for JsonError {
+    fn from(e: io::Error) -> Self {
+        JsonError::Io(e)
+    }
+}
+
+// Parse a single JSON value from a reader as its bytes arrive, without
+// needing the whole document in memory first. The limits are enforced while
+// reading, so an oversized or overly nested document is refused as soon as
+// it crosses them.
+pub fn parse_json(reader: impl Read, limits: &JsonLimits) -> Result<JsonValue, JsonError> {
+    let mut parser = Parser {
+        reader,
+        limits,
+        chunk: [0; 4096],
+        chunk_len: 0,
+        chunk_pos: 0,
+        consumed: 0,
+    };
+    let value = parser.value(0)?;
+    match parser.next_non_whitespace()? {
+        None => Ok(value),
+        Some(_) => Err(JsonError::Syntax),
+    }
+}
+
+struct Parser<'a, R> {
+    reader: R,
+    limits: &'a JsonLimits,
+    // Bytes read ahead from `reader`, consumed from `chunk_pos`
+    chunk: [u8; 4096],
+    chunk_len: usize,
+    chunk_pos: usize,
+    // Bytes consumed so far, for `max_size`
+    consumed: usize,
+}
+
+impl<R: Read> Parser<'_, R> {
+    fn peek(&mut self) -> Result<Option<u8>, JsonError> {
+        if self.chunk_pos == self.chunk_len {
+            self.chunk_len = loop {
+                match self.reader.read(&mut self.chunk) {
+                    Ok(size) => break size,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    Err(e) => return Err(e.into()),
+                }
+            };
+            self.chunk_pos = 0;
+            if self.chunk_len == 0 {
+                return Ok(None);
+            }
+        }
+        Ok(Some(self.chunk[self.chunk_pos]))
+    }
+
+    fn next(&mut self) -> Result<Option<u8>, JsonError> {
+        let byte = self.peek()?;
+        if byte.is_some() {
+            self.chunk_pos += 1;
+            self.consumed += 1;
+            if self.consumed > self.limits.max_size {
+                return Err(JsonError::TooLarge);
+            }
+        }
+        Ok(byte)
+    }
+
+    fn next_non_whitespace(&mut self) -> Result<Option<u8>, JsonError> {
+        loop {
+            match self.next()? {
+                Some(b' ' | b'\t' | b'\n' | b'\r') => {},
+                byte => return Ok(byte),
+            }
+        }
+    }
+
+    // Require the next bytes to be `literal`
+    fn expect(&mut self, literal: &[u8]) -> Result<(), JsonError> {
+        for &expected in literal {
+            if self.next()? != Some(expected) {
+                return Err(JsonError::Syntax);
+            }
+        }
+        Ok(())
+    }
+
+    // A value at nesting `depth`, the enclosing arrays and objects
+    fn value(&mut self, depth: usize) -> Result<JsonValue, JsonError> {
+        match self.next_non_whitespace()?.ok_or(JsonError::Syntax)? {
+            b'n' => self.expect(b"ull").map(|()| JsonValue::Null),
+            b't' => self.expect(b"rue").map(|()| JsonValue::Bool(true)),
+            b'f' => self.expect(b"alse").map(|()| JsonValue::Bool(false)),
+            b'"' => self.string().map(JsonValue::String),
+            b'[' if depth >= self.limits.max_depth => Err(JsonError::TooDeep),
+            b'[' => self.array(depth + 1),
+            b'{' if depth >= self.limits.max_depth => Err(JsonError::TooDeep),
+            b'{' => self.object(depth + 1),
+            first @ (b'-' | b'0'..=b'9') => self.number(first),
+            _ => Err(JsonError::Syntax),
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<JsonValue, JsonError> {
+        let mut items = Vec::new();
+        if self.peek_non_whitespace()? == Some(b']') {
+            self.next()?;
+            return Ok(JsonValue::Array(items));
+        }
+        loop {
+            items.push(self.value(depth)?);
+            match self.next_non_whitespace()? {
+                Some(b',') => {},
+                Some(b']') => return Ok(JsonValue::Array(items)),
+                _ => return Err(JsonError::Syntax),
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<JsonValue, JsonError> {
+        let mut members = Vec::new();
+        if self.peek_non_whitespace()? == Some(b'}') {
+            self.next()?;
+            return Ok(JsonValue::Object(members));
+        }
+        loop {
+            if self.next_non_whitespace()? != Some(b'"') {
+                return Err(JsonError::Syntax);
+            }
+            let key = self.string()?;
+            if self.next_non_whitespace()? != Some(b':') {
+                return Err(JsonError::Syntax);
+            }
+            members.push((key, self.value(depth)?));
+            match self.next_non_whitespace()? {
+                Some(b',') => {},
+                Some(b'}') => return Ok(JsonValue::Object(members)),
+                _ => return Err(JsonError::Syntax),
+            }
+        }
+    }
+
+    // Skip whitespace and look at the byte after it without consuming it
+    fn peek_non_whitespace(&mut self) -> Result<Option<u8>, JsonError> {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek()? {
+            self.next()?;
+        }
+        self.peek()
+    }
+
+    // The rest of a string after its opening quote
+    fn string(&mut self) -> Result<String, JsonError> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.next()?.ok_or(JsonError::Syntax)? {
+                b'"' => return String::from_utf8(bytes).map_err(|_| JsonError::Syntax),
+                b'\\' => {
+                    let unescaped = match self.next()?.ok_or(JsonError::Syntax)? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return Err(JsonError::Syntax),
+                    };
+                    bytes.extend_from_slice(unescaped.encode_utf8(&mut [0; 4]).as_bytes());
+                },
+                byte if byte < 0x20 => return Err(JsonError::Syntax),
+                byte => bytes.push(byte),
+            }
+        }
+    }
+
+    // The character of a `\uXXXX` escape, joining a surrogate pair
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or(JsonError::Syntax);
+        }
+        self.expect(b"\\u")?;
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err(JsonError::Syntax);
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)).ok_or(JsonError::Syntax)
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = self.next()?.and_then(|byte| (byte as char).to_digit(16)).ok_or(JsonError::Syntax)?;
+            value = value * 16 + digit;
+        }
+        Ok(value)
+    }
+
+    // A number starting with `first`: `-? (0 | [1-9][0-9]*) (. [0-9]+)?
+    // ([eE] [+-]? [0-9]+)?`
+    fn number(&mut self, first: u8) -> Result<JsonValue, JsonError> {
+        let mut text = vec![first];
+        let leading = if first == b'-' {
+            let digit = self.next()?.filter(u8::is_ascii_digit).ok_or(JsonError::Syntax)?;
+            text.push(digit);
+            digit
+        } else {
+            first
+        };
+        if leading != b'0' {
+            self.digits(&mut text)?;
+        }
+        if self.peek()? == Some(b'.') {
+            text.push(b'.');
+            self.next()?;
+            if self.digits(&mut text)? == 0 {
+                return Err(JsonError::Syntax);
+            }
+        }
+        if let Some(exponent @ (b'e' | b'E')) = self.peek()? {
+            text.push(exponent);
+            self.next()?;
+            if let Some(sign @ (b'+' | b'-')) = self.peek()? {
+                text.push(sign);
+                self.next()?;
+            }
+            if self.digits(&mut text)? == 0 {
+                return Err(JsonError::Syntax);
+            }
+        }
+
+        let text = String::from_utf8(text).map_err(|_| JsonError::Syntax)?;
+        text.parse().map(JsonValue::Number).map_err(|_| JsonError::Syntax)
+    }
+
+    // Consume a run of digits onto `text`, returning how many there were
+    fn digits(&mut self, text: &mut Vec<u8>) -> Result<usize, JsonError> {
+        let mut count = 0;
+        while let Some(digit) = self.peek()?.filter(u8::is_ascii_digit) {
+            text.push(digit);
+            self.next()?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<JsonValue, JsonError> {
+        parse_json(text.as_bytes(), &JsonLimits::default())
+    }
+
+    #[test]
+    fn parses_every_kind_of_value() {
+        let value = parse(r#" {"null": null, "bools": [true, false], "n": -1.5e2, "s": "a\"\u00e9\ud83d\ude00"} "#).unwrap();
+        assert_eq!(value, JsonValue::Object(vec![
+            ("null".to_string(), JsonValue::Null),
+            ("bools".to_string(), JsonValue::Array(vec![JsonValue::Bool(true), JsonValue::Bool(false)])),
+            ("n".to_string(), JsonValue::Number(-150.0)),
+            ("s".to_string(), JsonValue::String("a\"\u{e9}\u{1f600}".to_string())),
+        ]));
+        assert_eq!(parse("[]").unwrap(), JsonValue::Array(Vec::new()));
+        assert_eq!(parse("{ }").unwrap(), JsonValue::Object(Vec::new()));
+    }
+
+    #[test]
+    fn refuses_invalid_documents() {
+        let invalid = ["", "nul", "01", "1.", "-", "1e", "[1,]", "{\"a\" 1}", "{1:2}", "\"\u{1}\"", "\"\\x\"", "\"\\ud800\"", "1 2"];
+        for text in invalid {
+            assert!(matches!(parse(text), Err(JsonError::Syntax)), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn enforces_depth_and_size() {
+        let limits = JsonLimits { max_depth: 3, max_size: 16 };
+        assert!(parse_json(&b"[[{\"a\":[]}]]"[..], &limits).is_err_and(|e| matches!(e, JsonError::TooDeep)));
+        assert!(parse_json(&b"[[[1]]]"[..], &limits).is_ok());
+        assert!(parse_json(&b"[1, 2, 3, 4, 5, 6]"[..], &limits).is_err_and(|e| matches!(e, JsonError::TooLarge)));
+
+        // Refused as soon as the limit is crossed, without reading the rest
+        let endless = io::repeat(b'[');
+        assert!(matches!(parse_json(endless, &JsonLimits::default()), Err(JsonError::TooDeep)));
+        let endless = b"[".chain(io::repeat(b' '));
+        assert!(matches!(parse_json(endless, &limits), Err(JsonError::TooLarge)));
+    }
+
+    #[test]
+    fn writes_documents_back_compactly() {
+        let text = "{\"a\":[1,-2.5,true,null],\"b\\\"\":\"line\\nbreak\\u0001\\\\\",\"c\":{}}";
+        let value = parse(&format!(" {} ", text.replace(',', " , "))).unwrap();
+        assert_eq!(value.to_string(), text);
+        assert_eq!(parse(&value.to_string()).unwrap(), value);
+    }
+}
@@ -0,0 +1,6368 @@
+use std::any::{Any, TypeId};
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::marker::PhantomData;
+use std::io::{self, BufRead, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+
+mod chunked;
+mod headers;
+mod json;
+mod multipart;
+mod pool;
+mod session;
+mod static_files;
+mod tls;
+
+use chunked::{read_chunked_body, ChunkedError};
+use json::{parse_json, JsonError, JsonLimits, JsonValue};
+use multipart::{Multipart, MultipartError};
+use pool::ThreadPool;
+pub use session::Session;
+use session::SessionStore;
+use static_files::{apply_sendfile, EmbeddedAsset, StaticConfig, StaticFiles};
+use tls::SniCertificates;
+
+// HTTP Response status lines
+const HTTP_CONTINUE: &str = "HTTP/1.1 100 Continue\r\n\r\n";
+const HTTP_EARLY_HINTS: &str = "HTTP/1.1 103 Early Hints\r\n";
+const HTTP_OK: &str = "HTTP/1.1 200 OK\r\n";
+const HTTP_CREATED: &str = "HTTP/1.1 201 Created\r\n";
+const HTTP_NO_CONTENT: &str = "HTTP/1.1 204 No Content\r\n";
+const HTTP_BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request\r\n";
+const HTTP_UNAUTHORIZED: &str = "HTTP/1.1 401 Unauthorized\r\n";
+const HTTP_FORBIDDEN: &str = "HTTP/1.1 403 Forbidden\r\n";
+const HTTP_NOT_FOUND: &str = "HTTP/1.1 404 Not Found\r\n";
+const HTTP_METHOD_NOT_ALLOWED: &str = "HTTP/1.1 405 Method Not Allowed\r\n";
+const HTTP_NOT_ACCEPTABLE: &str = "HTTP/1.1 406 Not Acceptable\r\n";
+const HTTP_CONFLICT: &str = "HTTP/1.1 409 Conflict\r\n";
+const HTTP_REQUEST_TIMEOUT: &str = "HTTP/1.1 408 Request Timeout\r\n";
+const HTTP_PAYLOAD_TOO_LARGE: &str = "HTTP/1.1 413 Payload Too Large\r\n";
+const HTTP_URI_TOO_LONG: &str = "HTTP/1.1 414 URI Too Long\r\n";
+const HTTP_UNSUPPORTED_MEDIA_TYPE: &str = "HTTP/1.1 415 Unsupported Media Type\r\n";
+const HTTP_EXPECTATION_FAILED: &str = "HTTP/1.1 417 Expectation Failed\r\n";
+const HTTP_TOO_MANY_REQUESTS: &str = "HTTP/1.1 429 Too Many Requests\r\n";
+const HTTP_UPGRADE_REQUIRED: &str = "HTTP/1.1 426 Upgrade Required\r\n";
+const HTTP_HEADER_FIELDS_TOO_LARGE: &str = "HTTP/1.1 431 Request Header Fields Too Large\r\n";
+const HTTP_INTERNAL_SERVER_ERROR: &str = "HTTP/1.1 500 Internal Server Error\r\n";
+const HTTP_NOT_IMPLEMENTED: &str = "HTTP/1.1 501 Not Implemented\r\n";
+const HTTP_SERVICE_UNAVAILABLE: &str = "HTTP/1.1 503 Service Unavailable\r\n";
+const HTTP_GATEWAY_TIMEOUT: &str = "HTTP/1.1 504 Gateway Timeout\r\n";
+const HTTP_VERSION_NOT_SUPPORTED: &str = "HTTP/1.1 505 HTTP Version Not Supported\r\n";
+
+// Bearer token accepted unless the configuration sets its own
+const DEFAULT_AUTH_TOKEN: &str = "secret-token";
+
+// Debug logging, enabled by setting KP_HTTP_DEBUG in the environment
+static DEBUG_LOGGING: AtomicBool = AtomicBool::new(false);
+
+// Set by SIGTERM or SIGINT. New connections then get a 503 while open ones
+// drain, and the process exits once they have or `shutdown_grace` is up.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Backtrace of the last panic on this thread, captured by the panic hook
+    static PANIC_BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        if DEBUG_LOGGING.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+// Server configuration shared by all connections
+pub struct Config {
+    // Address the listener binds to
+    bind_addr: String,
+    // Set `SO_REUSEADDR` on the listener, so a restarted server can bind
+    // while connections of the old one linger in `TIME_WAIT`. On by default
+    // on Unix only, since on Windows it lets another socket take the port.
+    reuse_address: bool,
+    // Set `SO_REUSEPORT`, letting several processes listen on the same port
+    // with the kernel spreading connections among them, for restarting
+    // without downtime. Supported on Linux, the BSDs and macOS, ignored with
+    // a warning elsewhere.
+    reuse_port: bool,
+    // Bearer token that marks a request as authenticated
+    pub auth_token: String,
+    // Maximum number of `/`-separated segments allowed in a request path
+    max_path_segments: usize,
+    // Size limits applied while reading and parsing requests
+    limits: ParserLimits,
+    // Enable SO_KEEPALIVE on accepted sockets so dead peers are detected
+    tcp_keepalive: bool,
+    // Idle time before the first keepalive probe is sent
+    tcp_keepalive_idle: Duration,
+    // Time between unanswered keepalive probes
+    tcp_keepalive_interval: Duration,
+    // Serve server start time and uptime at `GET /status`
+    status_endpoint: bool,
+    // Serve connection and request counters at `GET /metrics` in the
+    // Prometheus text format
+    metrics_endpoint: bool,
+    // Robots policy served from memory at `GET /robots.txt`, `None` to
+    // leave the path to the other routes
+    robots_txt: Option<String>,
+    // `Link` values, like `</style.css>; rel=preload; as=style`, sent as
+    // 103 Early Hints ahead of the response to a `GET` of their path, so
+    // clients start fetching assets while the page is prepared
+    early_hints: Vec<(String, Vec<String>)>,
+    // Save the files of `multipart/form-data` uploads to `POST /upload` in
+    // this directory, streamed to disk as they arrive. `None` leaves the
+    // route out.
+    upload_dir: Option<PathBuf>,
+    // Serve the effective configuration, secrets redacted, to authenticated
+    // clients at `GET /admin/config`. Refused while `auth_token` is still
+    // the default.
+    pub config_endpoint: bool,
+    // Path prefix, header name and value: requests below the prefix are
+    // refused with 403 unless they carry the header with exactly that
+    // value, such as `X-Internal: true` for `/admin`
+    required_headers: Vec<(String, String, String)>,
+    // Path prefixes only served over TLS. The server doesn't terminate TLS
+    // itself, so a request there is answered 426 naming `TLS/1.2` in
+    // `Upgrade` unless a TLS-terminating proxy in front marked it with
+    // `X-Forwarded-Proto: https`.
+    tls_only_prefixes: Vec<String>,
+    // Answer `OPTIONS` and `HEAD` for routes that don't handle them, see
+    // `Router::auto_options` and `Router::auto_head`
+    auto_options: bool,
+    auto_head: bool,
+    // Echo `TRACE` requests back for debugging. Off by default because of
+    // cross-site tracing, in which case every `TRACE` gets 405 with the
+    // path's methods in `Allow`, whatever routes or mounts cover it.
+    trace_enabled: bool,
+    // Body format of framework-generated errors
+    error_format: ErrorFormat,
+    // Serve files from a directory, `None` to disable
+    static_files: Option<StaticConfig>,
+    // Certificates to pick from by the SNI hostname once TLS is served
+    sni_certificates: Option<SniCertificates>,
+    // Track sessions through a cookie, expiring them after this much
+    // inactivity. `None` disables sessions.
+    session_ttl: Option<Duration>,
+    // Most sessions kept at once, the least recently used making way for
+    // new ones beyond it
+    max_sessions: usize,
+    // Print an access log line per request in the combined log format
+    access_log: bool,
+    // Reject with 400 a request whose absolute-form target names a
+    // different authority than its `Host` header. The target's authority
+    // wins either way.
+    reject_host_mismatch: bool,
+    // Maximum number of connections handled at once. Connections beyond
+    // this get a 503.
+    max_connections: usize,
+    // Maximum number of connections from one IP handled at once, so one
+    // client can't take all of `max_connections`. Connections beyond this
+    // get a 503. `None` sets no per-IP cap.
+    max_connections_per_ip: Option<usize>,
+    // `Retry-After` sent with 503 responses when overloaded or shutting down
+    retry_after: Duration,
+    // Include the panic message and a short backtrace in the 500 sent when a
+    // handler panics. Defaults to on in debug builds only.
+    expose_panic_details: bool,
+    // Send responses up to `coalesce_threshold` bytes with a single write.
+    // Larger ones are written as head and body so the body isn't copied.
+    coalesce_small_responses: bool,
+    coalesce_threshold: usize,
+    // Time budget shared by the middleware and the handler of a request.
+    // Past it the remaining stages are skipped and the client gets a 504.
+    request_timeout: Option<Duration>,
+    // Number of threads serving connections. A keep-alive connection holds
+    // its worker until it closes. `None` sizes the pool from the number of
+    // CPUs, and so does 0, which would leave no one to serve requests.
+    worker_threads: Option<usize>,
+    // Worker threads are named `<prefix>-<n>` for logs and stack traces
+    worker_thread_name: String,
+    // How long a read may block once a request has started arriving
+    read_timeout: Duration,
+    // How long a kept-alive connection may wait for the first byte of its
+    // next request, usually shorter than `read_timeout` so idle connections
+    // are freed quickly while slow requests still get time
+    idle_timeout: Duration,
+    // How often the idle sweeper looks for connections idle past
+    // `idle_timeout`, `None` leaves it to the socket read timeout
+    idle_sweep_interval: Option<Duration>,
+    // How long open connections get to finish after a shutdown signal
+    shutdown_grace: Duration,
+    // Client IP ranges in CIDR notation, like `10.0.0.0/8` or `::1/128`.
+    // With an allowlist only clients in it are served, and clients in the
+    // denylist never are. Others get a 403.
+    ip_allowlist: Vec<String>,
+    ip_denylist: Vec<String>,
+    // Allow each client IP this many requests per `rate_limit_window`,
+    // answering 429 beyond it. `None` disables rate limiting.
+    rate_limit: Option<u32>,
+    rate_limit_window: Duration,
+    // Expect a PROXY protocol v1 line from a load balancer ahead of the
+    // first request, and take the client address from it. Connections
+    // without a valid one are closed.
+    proxy_protocol: bool,
+    // Total request bytes a connection may send across its requests. The
+    // request crossing it is still answered, with `Connection: close`, to
+    // cap what a single persistent client can use.
+    max_connection_bytes: Option<usize>,
+    // Requests a client may have pipelined on one connection before reads
+    // pause. Responses to requests already received are held and written
+    // together, once this many are waiting or nothing more has arrived, and
+    // the socket isn't read again until they're out. 1 writes each response
+    // before reading on, and so does 0.
+    max_pipelined_requests: usize,
+    // Largest response body sent. A bigger one aborts the connection
+    // instead, so a runaway handler can't exhaust bandwidth. A streamed body
+    // is cut off once it grows past it, without the end of the chunked
+    // body, so the client can tell.
+    max_response_bytes: Option<usize>,
+    // Gzip responses of at least `compression_min_size` bytes for clients
+    // that accept it
+    compress_responses: bool,
+    compression_min_size: usize,
+    // Media types worth compressing, `type/*` matching a whole type.
+    // Anything else, like images and archives that are compressed already,
+    // is sent as is.
+    compressible_types: Vec<String>,
+    // Rewrite response bodies by content type after the handler, before
+    // compression, such as injecting a dev live-reload script into pages
+    pub response_interceptors: Vec<ResponseInterceptor>,
+    // Headers added to every response, error responses included, such as
+    // `X-Frame-Options: DENY`. A response setting the same header itself
+    // keeps its own value.
+    default_headers: Vec<(String, String)>,
+    // Report how long handling took in a `Server-Timing: app;dur=...`
+    // header, for browser dev tools
+    server_timing: bool,
+    // Directory a handler's `X-Sendfile: /path` header is resolved in, the
+    // file replacing the response body. `None` ignores the header.
+    sendfile_root: Option<PathBuf>,
+    // Write every request and the exact response bytes sent for it to
+    // numbered `.request` and `.response` files here, for golden-file tests
+    record_dir: Option<PathBuf>,
+    // Parse `application/json` request bodies as they're read, within these
+    // limits, refusing invalid documents with 400. Handlers find the result
+    // as a `JsonBody` extension. `None` leaves bodies unparsed.
+    json_limits: Option<JsonLimits>,
+    // Copy matching request bodies to an audit sink as they're read, in
+    // addition to handing them to the handler
+    pub audit_bodies: Option<BodyAudit>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_addr: "127.0.0.1:8080".to_string(),
+            reuse_address: cfg!(unix),
+            reuse_port: false,
+            auth_token: DEFAULT_AUTH_TOKEN.to_string(),
+            max_path_segments: 50,
+            limits: ParserLimits::default(),
+            tcp_keepalive: true,
+            tcp_keepalive_idle: Duration::from_secs(60),
+            tcp_keepalive_interval: Duration::from_secs(10),
+            status_endpoint: true,
+            metrics_endpoint: true,
+            robots_txt: Some(ROBOTS_TXT.to_string()),
+            early_hints: Vec::new(),
+            upload_dir: None,
+            config_endpoint: false,
+            required_headers: Vec::new(),
+            tls_only_prefixes: Vec::new(),
+            auto_options: true,
+            auto_head: true,
+            trace_enabled: false,
+            error_format: ErrorFormat::Text,
+            static_files: Some(StaticConfig::default()),
+            sni_certificates: None,
+            session_ttl: None,
+            max_sessions: 10_000,
+            access_log: true,
+            reject_host_mismatch: false,
+            max_connections: 1024,
+            max_connections_per_ip: None,
+            retry_after: Duration::from_secs(5),
+            expose_panic_details: cfg!(debug_assertions),
+            coalesce_small_responses: true,
+            coalesce_threshold: 16 * 1024,
+            request_timeout: Some(Duration::from_secs(30)),
+            worker_threads: None,
+            worker_thread_name: "kp-http-worker".to_string(),
+            read_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(5),
+            idle_sweep_interval: Some(Duration::from_secs(1)),
+            shutdown_grace: Duration::from_secs(10),
+            ip_allowlist: Vec::new(),
+            ip_denylist: Vec::new(),
+            rate_limit: None,
+            rate_limit_window: Duration::from_secs(60),
+            proxy_protocol: false,
+            max_connection_bytes: None,
+            max_pipelined_requests: 1,
+            max_response_bytes: None,
+            compress_responses: true,
+            compression_min_size: 1024,
+            compressible_types: ["text/*", "application/json", "application/javascript", "image/svg+xml"]
+                .map(String::from)
+                .to_vec(),
+            response_interceptors: Vec::new(),
+            default_headers: Vec::new(),
+            server_timing: false,
+            sendfile_root: None,
+            record_dir: None,
+            json_limits: Some(JsonLimits::default()),
+            audit_bodies: None,
+        }
+    }
+}
+
+impl Config {
+    // Worker threads to start, from `worker_threads` or else four per CPU,
+    // as workers mostly wait on their connections
+    fn worker_count(&self) -> usize {
+        match self.worker_threads {
+            Some(count) if count > 0 => count,
+            _ => thread::available_parallelism().map_or(1, |cpus| cpus.get()) * 4,
+        }
+    }
+
+    // Defaults with the settings given by `KP_HTTP_*` environment variables
+    pub fn from_env() -> Self {
+        Config::default().with_env(|name| std::env::var(name).ok())
+    }
+
+    // Apply the settings `var` finds by variable name:
+    //
+    // - `KP_HTTP_ERROR_FORMAT`: `text`, `json` or `problem`, see
+    //   `ErrorFormat`
+    fn with_env(mut self, var: impl Fn(&str) -> Option<String>) -> Self {
+        match var("KP_HTTP_ERROR_FORMAT").as_deref() {
+            None => {},
+            Some("text") => self.error_format = ErrorFormat::Text,
+            Some("json") => self.error_format = ErrorFormat::Json,
+            Some("problem") => self.error_format = ErrorFormat::Problem,
+            Some(other) => eprintln!("Ignoring unknown KP_HTTP_ERROR_FORMAT {:?}", other),
+        }
+        self
+    }
+}
+
+// Process-wide server state shared with the built-in handlers
+pub struct ServerState {
+    // Monotonic start time, used for uptime
+    started: Instant,
+    // Wall-clock start time, reported to clients
+    started_at: SystemTime,
+    // Connections currently being handled
+    active_connections: AtomicUsize,
+    // Connections handed to a worker since startup
+    connections_accepted: AtomicU64,
+    // Requests dispatched since startup, across all connections
+    requests_served: AtomicU64,
+    // Time requests spent in each phase, indexed like `PHASES`
+    phase_seconds: [Histogram; 4],
+    // Requests each closed connection carried, to tune keep-alive
+    connection_requests: Histogram,
+    // Open connections by id, for the idle sweeper
+    connections: Mutex<HashMap<u64, ConnectionEntry>>,
+    next_connection_id: AtomicU64,
+    // Connections currently being handled per client IP, for
+    // `max_connections_per_ip`. IPs without any are removed.
+    connections_per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+// An open connection as seen by the idle sweeper
+struct ConnectionEntry {
+    // Handle to the connection's socket, for shutting it down
+    stream: TcpStream,
+    // When it started waiting for a request, `None` while one is in progress
+    idle_since: Option<Instant>,
+}
+
+// Request phases timed per request: reading the head, reading the body,
+// running the handler and writing the response
+const PHASES: [&str; 4] = ["headers", "body", "handler", "write"];
+
+// Upper bounds in seconds of the phase histogram buckets
+const PHASE_BUCKETS: &[f64] = &[0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+// Upper bounds of the requests-per-connection histogram buckets
+const CONNECTION_REQUEST_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 1000.0];
+
+// Distribution of observed values over fixed buckets, for `/metrics`
+struct Histogram {
+    // Ascending bucket upper bounds, with an implicit `+Inf` after them
+    bounds: &'static [f64],
+    counts: Locked<HistogramCounts>,
+}
+
+struct HistogramCounts {
+    // Observations per bucket, not cumulative, the last one for `+Inf`
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        let counts = HistogramCounts { buckets: vec![0; bounds.len() + 1], sum: 0.0, count: 0 };
+        Histogram { bounds, counts: Locked::new(counts) }
+    }
+
+    fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|&bound| value <= bound).unwrap_or(self.bounds.len());
+        self.counts.write(|counts| {
+            counts.buckets[bucket] += 1;
+            counts.sum += value;
+            counts.count += 1;
+        });
+    }
+
+    // Append the `_bucket`, `_sum` and `_count` series in the Prometheus
+    // text format, each with `labels` like `phase="body"` if not empty
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        self.counts.read(|counts| {
+            let mut cumulative = 0;
+            for (i, count) in counts.buckets.iter().enumerate() {
+                cumulative += count;
+                let bound = self.bounds.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+                out.push_str(&format!("{}_bucket{{{}{}le=\"{}\"}} {}\n", name, labels, separator, bound, cumulative));
+            }
+            let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+            out.push_str(&format!("{}_sum{} {}\n", name, labels, counts.sum));
+            out.push_str(&format!("{}_count{} {}\n", name, labels, counts.count));
+        });
+    }
+}
+
+impl ServerState {
+    fn new() -> Self {
+        ServerState {
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            active_connections: AtomicUsize::new(0),
+            connections_accepted: AtomicU64::new(0),
+            requests_served: AtomicU64::new(0),
+            phase_seconds: std::array::from_fn(|_| Histogram::new(PHASE_BUCKETS)),
+            connection_requests: Histogram::new(CONNECTION_REQUEST_BUCKETS),
+            connections: Mutex::new(HashMap::new()),
+            next_connection_id: AtomicU64::new(0),
+            connections_per_ip: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn connections(&self) -> std::sync::MutexGuard<'_, HashMap<u64, ConnectionEntry>> {
+        self.connections.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Track a connection, returning its id. Without a socket handle to shut
+    // it down it can't be swept, and isn't tracked.
+    fn register_connection(&self, stream: &TcpStream) -> Option<u64> {
+        let stream = stream.try_clone().ok()?;
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        self.connections().insert(id, ConnectionEntry { stream, idle_since: None });
+        Some(id)
+    }
+
+    fn set_idle(&self, id: u64, idle: bool) {
+        if let Some(entry) = self.connections().get_mut(&id) {
+            entry.idle_since = idle.then(Instant::now);
+        }
+    }
+
+    fn unregister_connection(&self, id: u64) {
+        self.connections().remove(&id);
+    }
+}
+
+// Every `interval`, shut down connections that have waited longer than
+// `timeout` for a request. Read timeouts only fire for a blocked read; this
+// also covers sockets whose reads never time out.
+fn spawn_idle_sweeper(state: Arc<ServerState>, interval: Duration, timeout: Duration) -> io::Result<()> {
+    thread::Builder::new().name("kp-http-sweeper".to_string()).spawn(move || loop {
+        thread::sleep(interval);
+        sweep_idle(&state, timeout);
+    })?;
+    Ok(())
+}
+
+// Shut down and stop tracking the connections idle for `timeout` or longer
+fn sweep_idle(state: &ServerState, timeout: Duration) {
+    state.connections().retain(|id, entry| {
+        let expired = entry.idle_since.is_some_and(|since| since.elapsed() >= timeout);
+        if expired {
+            debug_log!("Sweeping connection {} idle for over {:?}", id, timeout);
+            let _ = entry.stream.shutdown(std::net::Shutdown::Both);
+        }
+        !expired
+    });
+}
+
+// Counts a connection as active until dropped
+struct ConnectionGuard(Arc<ServerState>);
+
+impl ConnectionGuard {
+    fn new(state: &Arc<ServerState>) -> Self {
+        state.active_connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(Arc::clone(state))
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Counts a connection against its client IP until dropped
+struct IpConnectionGuard {
+    state: Arc<ServerState>,
+    ip: IpAddr,
+}
+
+impl IpConnectionGuard {
+    // Count a connection from `ip`, unless it already has `max`
+    fn acquire(state: &Arc<ServerState>, ip: IpAddr, max: usize) -> Option<Self> {
+        let mut counts = state.connections_per_ip.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if counts.get(&ip).copied().unwrap_or(0) >= max {
+            return None;
+        }
+        *counts.entry(ip).or_insert(0) += 1;
+        Some(IpConnectionGuard { state: Arc::clone(state), ip })
+    }
+}
+
+impl Drop for IpConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.state.connections_per_ip.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn install_shutdown_handler() -> io::Result<()> {
+    extern "C" fn on_signal(_signal: libc::c_int) {
+        SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    }
+
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // SAFETY: the handler only stores to an atomic, which is
+        // async-signal-safe
+        let previous = unsafe { libc::signal(signal, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t) };
+        if previous == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Without signals there is no shutdown trigger, the process is just killed
+#[cfg(not(unix))]
+fn install_shutdown_handler() -> io::Result<()> {
+    Ok(())
+}
+
+// Wait for a shutdown signal, give open connections up to the grace period
+// to finish, then exit
+fn spawn_shutdown_watcher(state: Arc<ServerState>, grace: Duration) -> io::Result<()> {
+    thread::Builder::new().name("kp-http-shutdown".to_string()).spawn(move || {
+        while !SHUTTING_DOWN.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        println!("Shutting down, draining {} connections", state.active_connections.load(Ordering::SeqCst));
+
+        let deadline = Instant::now() + grace;
+        while state.active_connections.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        let remaining = state.active_connections.load(Ordering::SeqCst);
+        if remaining > 0 {
+            eprintln!("Grace period over, dropping {} connections", remaining);
+        }
+        process::exit(0);
+    })?;
+    Ok(())
+}
+
+// Size limits applied while reading and parsing requests, bundled so
+// deployments can tune their strictness in one place
+struct ParserLimits {
+    // Maximum length of the request line, excluding the CRLF
+    max_request_line: usize,
+    // Maximum length of the method token, so a pathological method isn't
+    // stored and compared against every route
+    max_method_len: usize,
+    // Maximum length in bytes of the raw request target, checked before any
+    // decoding or routing of the path
+    max_uri_length: usize,
+    // Maximum length of a single header line, excluding the CRLF
+    max_header_line: usize,
+    // Maximum number of header lines
+    max_headers: usize,
+    // Maximum size of the whole head, request line and headers included
+    max_header_bytes: usize,
+    // Maximum accepted request body size in bytes, for bodies read into
+    // memory before the handler runs
+    max_body: usize,
+    // Maximum body size for routes added with `add_streaming`, which read
+    // the body from the connection as it arrives
+    max_upload: usize,
+    // Empty lines tolerated ahead of the request line
+    max_leading_empty_lines: usize,
+    // Accept bare `\n` line endings, and a head cut short by the client
+    // closing its side before the blank line, for minimal clients
+    lenient_parsing: bool,
+    // Require the request line to be separated by single spaces with no
+    // control characters, instead of splitting on any whitespace. A lax
+    // split can read a line differently from a proxy in front of us.
+    strict_request_line: bool,
+}
+
+impl Default for ParserLimits {
+    fn default() -> Self {
+        ParserLimits {
+            max_request_line: 8 * 1024,
+            max_method_len: 32,
+            max_uri_length: 8 * 1024,
+            max_header_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 32 * 1024,
+            max_body: 1024 * 1024,
+            max_upload: 1024 * 1024 * 1024,
+            max_leading_empty_lines: 4,
+            lenient_parsing: false,
+            strict_request_line: false,
+        }
+    }
+}
+
+impl ParserLimits {
+    // Find the blank line ending a request head, returning where the head
+    // stops and where the body starts
+    fn find_head_end(&self, buffer: &[u8]) -> Option<(usize, usize)> {
+        if !self.lenient_parsing {
+            // A bare `\n\n` doesn't end a head strictly, but waiting for a
+            // `\r\n\r\n` that isn't coming would hang until the read timeout.
+            // The head ends there with the bare LF inside it, for `parse` to
+            // refuse.
+            let crlf = find_subsequence(buffer, b"\r\n\r\n");
+            return match (crlf, find_subsequence(buffer, b"\n\n")) {
+                (Some(crlf), Some(lf)) if lf < crlf => Some((lf + 2, lf + 2)),
+                (None, Some(lf)) => Some((lf + 2, lf + 2)),
+                (crlf, _) => crlf.map(|pos| (pos, pos + 4)),
+            };
+        }
+
+        // Any line ending followed by an empty line, `\n\n` or `\n\r\n`
+        (0..buffer.len()).find_map(|pos| match &buffer[pos..] {
+            [b'\n', b'\n', ..] => Some((pos, pos + 2)),
+            [b'\n', b'\r', b'\n', ..] => Some((pos, pos + 3)),
+            _ => None,
+        })
+    }
+
+    // Check a complete request head against the line limits
+    fn check_head(&self, head: &[u8]) -> Result<(), ParseError> {
+        let mut lines = head.split(|&byte| byte == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .filter(|line| !line.is_empty());
+
+        if lines.next().is_some_and(|line| line.len() > self.max_request_line) {
+            return Err(ParseError::UriTooLong);
+        }
+
+        let mut header_count = 0;
+        for line in lines {
+            header_count += 1;
+            if header_count > self.max_headers || line.len() > self.max_header_line {
+                return Err(ParseError::HeadersTooLarge);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Why a request head was rejected
+#[derive(Debug, PartialEq)]
+enum ParseError {
+    // Nothing before the blank line ending the head
+    Empty,
+    // The request line isn't `METHOD target HTTP/x.y`
+    BadRequestLine,
+    // The method is longer than `max_method_len` or isn't a token
+    BadMethod,
+    // The path holds control characters such as NUL, raw or
+    // percent-encoded, which could cut a file name short
+    BadPath,
+    // The version isn't of the form `HTTP/x.y`
+    BadVersion,
+    // A well-formed version other than HTTP/1.x
+    UnsupportedVersion,
+    // A header line without a colon, or whose name is empty or isn't a
+    // token, like `Content-Length : 5`
+    BadHeader,
+    // A `Content-Length` that isn't one non-negative integer, or several
+    // that disagree
+    BadContentLength,
+    // A header line continuing the previous one with leading whitespace,
+    // obsolete line folding that intermediaries may join differently
+    FoldedHeader,
+    // Too many header lines, a header line too long, or the head too large
+    HeadersTooLarge,
+    // The request line or its target is longer than allowed
+    UriTooLong,
+}
+
+impl ParseError {
+    // Status line to reject the request with
+    fn status_line(&self) -> &'static str {
+        match self {
+            ParseError::Empty
+            | ParseError::BadRequestLine
+            | ParseError::BadMethod
+            | ParseError::BadPath
+            | ParseError::BadVersion
+            | ParseError::BadHeader
+            | ParseError::BadContentLength
+            | ParseError::FoldedHeader => HTTP_BAD_REQUEST,
+            ParseError::UnsupportedVersion => HTTP_VERSION_NOT_SUPPORTED,
+            ParseError::HeadersTooLarge => HTTP_HEADER_FIELDS_TOO_LARGE,
+            ParseError::UriTooLong => HTTP_URI_TOO_LONG,
+        }
+    }
+}
+
+// Type-keyed store for values shared between middleware and handlers,
+// holding at most one value per type
+#[derive(Default)]
+struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    // Store a value, returning the previous value of the same type
+    fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map.insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    fn get<T: 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.map.len()).finish()
+    }
+}
+
+// Writer for interim responses sent ahead of the final one
+struct InterimWriter(Mutex<Box<dyn Write + Send>>);
+
+impl fmt::Debug for InterimWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("InterimWriter")
+    }
+}
+
+// HTTP Request struct to parse incoming requests
+#[derive(Debug)]
+pub struct HttpRequest {
+    method: String,
+    path: String,
+    // Authority from an absolute-form target like `http://host/path`
+    authority: Option<String>,
+    version: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // Values attached by middleware for later middleware and the handler
+    extensions: Extensions,
+    // Connection handle for writing interim (1xx) responses from a handler
+    interim: Option<InterimWriter>,
+    // When the response has to be ready, set by the `Timeout` middleware
+    deadline: Option<Instant>,
+    // Address of the client the request came from
+    remote_addr: Option<SocketAddr>,
+    // Fields sent after a chunked body, kept apart from `headers` since the
+    // client could not have known them up front
+    trailers: Vec<(String, String)>,
+    // Request headers the response was negotiated on, for `Vary`
+    negotiated_on: Mutex<Vec<&'static str>>,
+    // Values captured by the `:name` segments of the matched route,
+    // percent-decoded
+    params: Vec<(String, String)>,
+    // Set by `close_connection` to close the connection after the response
+    close_requested: AtomicBool,
+    // Body left on the connection for a streaming route's handler to read,
+    // see `Router::add_streaming`. `body` is empty then.
+    body_stream: Option<Mutex<BodyStream>>,
+}
+
+impl HttpRequest {
+    fn parse(raw_request: &[u8], limits: &ParserLimits) -> Result<HttpRequest, ParseError> {
+        // Split the head from the body at the first blank line, keeping the
+        // body as raw bytes. Without a blank line everything is head.
+        let (head, body) = match limits.find_head_end(raw_request) {
+            Some((head_end, body_start)) => (&raw_request[..head_end], &raw_request[body_start..]),
+            None => (raw_request, &raw_request[raw_request.len()..]),
+        };
+        limits.check_head(head)?;
+
+        // Strictly a bare `\n` isn't a line ending. Left in place it would
+        // end up inside a header value, so refuse the request instead.
+        let bare_lf = head.iter().enumerate()
+            .any(|(pos, &byte)| byte == b'\n' && (pos == 0 || head[pos - 1] != b'\r'));
+        if bare_lf && !limits.lenient_parsing {
+            return Err(ParseError::BadHeader);
+        }
+
+        let head = String::from_utf8_lossy(head);
+        let mut lines: Box<dyn Iterator<Item = &str>> = if limits.lenient_parsing {
+            Box::new(head.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line)))
+        } else {
+            Box::new(head.split("\r\n"))
+        };
+
+        // Parse request line
+        let request_line = lines.next().unwrap_or("");
+        if request_line.trim().is_empty() {
+            return Err(ParseError::Empty);
+        }
+        if limits.strict_request_line
+            && (request_line.split(' ').any(str::is_empty) || request_line.chars().any(char::is_control))
+        {
+            return Err(ParseError::BadRequestLine);
+        }
+        let mut request_line = request_line.split_whitespace();
+        let (Some(method), Some(target), Some(version), None) = (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) else {
+            return Err(ParseError::BadRequestLine);
+        };
+        if method.len() > limits.max_method_len || !method.bytes().all(is_token_char) {
+            return Err(ParseError::BadMethod);
+        }
+        if target.len() > limits.max_uri_length {
+            return Err(ParseError::UriTooLong);
+        }
+        let method = method.to_string();
+        let version = parse_version(version)?.to_string();
+
+        // Absolute-form targets are split into authority and origin-form path
+        let (authority, path) = match split_absolute_target(target) {
+            Some((authority, rest)) if rest.starts_with('/') => (Some(authority.to_string()), rest.to_string()),
+            Some((authority, rest)) => (Some(authority.to_string()), format!("/{}", rest)),
+            None => (None, target.to_string()),
+        };
+        let raw_path = path.split('?').next().unwrap_or("");
+        if percent_decode(raw_path).chars().any(char::is_control) {
+            return Err(ParseError::BadPath);
+        }
+
+        // Parse headers, trimming optional whitespace around values
+        let mut headers = Vec::new();
+        for line in lines.take_while(|line| !line.is_empty()) {
+            if line.starts_with([' ', '\t']) {
+                return Err(ParseError::FoldedHeader);
+            }
+            let (key, value) = line.split_once(':').ok_or(ParseError::BadHeader)?;
+            // Whitespace before the colon would leave `Content-Length ` to be
+            // ignored here and honoured by a proxy that trims it
+            if key.is_empty() || !key.bytes().all(is_token_char) {
+                return Err(ParseError::BadHeader);
+            }
+            headers.push((key.to_string(), value.trim_matches([' ', '\t']).to_string()));
+        }
+
+        let request = HttpRequest {
+            method,
+            path,
+            authority,
+            version,
+            headers,
+            body: body.to_vec(),
+            extensions: Extensions::default(),
+            interim: None,
+            deadline: None,
+            remote_addr: None,
+            trailers: Vec::new(),
+            negotiated_on: Mutex::new(Vec::new()),
+            params: Vec::new(),
+            close_requested: AtomicBool::new(false),
+            body_stream: None,
+        };
+
+        // A length that can't be read leaves no way to find the body's end
+        if request.header("Content-Length").is_some() && headers::content_length(&request).is_none() {
+            return Err(ParseError::BadContentLength);
+        }
+        Ok(request)
+    }
+
+    // Value of the first header with the given name, compared case-insensitively
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Value captured by the route's `:name` segment
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Value of the first trailer field with the given name
+    pub fn trailer(&self, name: &str) -> Option<&str> {
+        self.trailers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Cookies sent in `Cookie` headers, in the order they appear. Pairs may
+    // be separated by `;` with or without spaces, whitespace around names
+    // and values is dropped, and a quoted value like `"a b"` loses its
+    // quotes. Pairs without a name are skipped.
+    fn cookies(&self) -> Vec<(&str, &str)> {
+        self.headers.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                let name = name.trim_matches([' ', '\t']);
+                let value = value.trim_matches([' ', '\t']);
+                let value = match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+                    Some(unquoted) => unquoted,
+                    None => value,
+                };
+                (!name.is_empty()).then_some((name, value))
+            })
+            .collect()
+    }
+
+    fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies().into_iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    fn user_agent(&self) -> Option<&str> {
+        self.header("User-Agent")
+    }
+
+    fn referer(&self) -> Option<&str> {
+        self.header("Referer")
+    }
+
+    // Send a `103 Early Hints` response with one `Link` header per entry,
+    // e.g. `</style.css>; rel=preload; as=style`, so the client can start
+    // fetching assets while the final response is prepared. Skipped for
+    // HTTP/1.0 clients, which don't understand interim responses.
+    fn early_hints(&self, links: &[&str]) -> io::Result<()> {
+        let Some(InterimWriter(writer)) = &self.interim else {
+            return Ok(());
+        };
+        if self.version != "HTTP/1.1" {
+            return Ok(());
+        }
+
+        let mut hints = HTTP_EARLY_HINTS.to_string();
+        for link in links {
+            hints.push_str(&format!("Link: {}\r\n", link));
+        }
+        hints.push_str("\r\n");
+
+        let mut writer = writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        writer.write_all(hints.as_bytes())?;
+        writer.flush()
+    }
+
+    // Whether the body is JSON as sent, `application/json` or a `+json`
+    // type and not content-encoded
+    fn is_json(&self) -> bool {
+        let media_type = self.header("Content-Type").and_then(|value| value.split(';').next()).unwrap_or("").trim();
+        let encoded = self.header("Content-Encoding").is_some_and(|coding| !coding.eq_ignore_ascii_case("identity"));
+        let json = media_type.eq_ignore_ascii_case("application/json")
+            || media_type.to_ascii_lowercase().ends_with("+json");
+        json && !encoded
+    }
+
+    // Reader over the body: straight from the connection on a streaming
+    // route, from `body` on any other
+    fn body_reader(&self) -> RequestBody<'_> {
+        match &self.body_stream {
+            Some(stream) => RequestBody::Streamed(stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner())),
+            None => RequestBody::Buffered(&self.body),
+        }
+    }
+
+    // Streaming reader over a multipart body's parts, if the body is one
+    fn multipart<'a>(&'a self, limits: &'a ParserLimits) -> Option<Multipart<'a, RequestBody<'a>>> {
+        let boundary = multipart::boundary(self.header("Content-Type")?)?;
+        Some(Multipart::new(self.body_reader(), boundary, limits))
+    }
+
+    // Whether the body runs until the client closes the connection: an
+    // HTTP/1.0 request of a method that carries a body, sent with neither
+    // `Content-Length` nor `Transfer-Encoding`. In HTTP/1.1 a request
+    // without either has no body.
+    fn body_until_close(&self) -> bool {
+        self.version == "HTTP/1.0"
+            && matches!(self.method.as_str(), "POST" | "PUT" | "PATCH")
+            && self.header("Content-Length").is_none()
+            && self.header("Transfer-Encoding").is_none()
+    }
+
+    // Whether the body is delimited by `Content-Length` alone and sent as is,
+    // so a streaming route can read it straight from the connection
+    fn body_streamable(&self) -> bool {
+        self.header("Transfer-Encoding").is_none()
+            && self.header("Content-Encoding").is_none()
+            && !self.body_until_close()
+    }
+
+    // Whether a TLS-terminating proxy in front marked the request as
+    // received over HTTPS with `X-Forwarded-Proto`
+    fn over_https(&self) -> bool {
+        self.header("X-Forwarded-Proto").is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+    }
+
+    // Close the connection once the response is sent, whatever keep-alive
+    // was asked for, such as after spotting an abusive client
+    fn close_connection(&self) {
+        self.close_requested.store(true, Ordering::Relaxed);
+    }
+
+    // Declared body length, 0 without a `Content-Length`. Invalid ones are
+    // refused by `parse`.
+    fn content_length(&self) -> usize {
+        headers::content_length(self).map_or(0, |len| usize::try_from(len).unwrap_or(usize::MAX))
+    }
+
+    // Make the `Host` header agree with an absolute-form target, since the
+    // target's authority takes precedence. Returns false if they disagreed
+    // and `reject_mismatch` is set.
+    fn reconcile_host(&mut self, reject_mismatch: bool) -> bool {
+        let Some(authority) = self.authority.clone() else {
+            return true;
+        };
+
+        let mut found = false;
+        for (key, value) in self.headers.iter_mut() {
+            if key.eq_ignore_ascii_case("Host") {
+                if !value.eq_ignore_ascii_case(&authority) && reject_mismatch {
+                    return false;
+                }
+                *value = authority.clone();
+                found = true;
+            }
+        }
+        if !found {
+            self.headers.push(("Host".to_string(), authority));
+        }
+        true
+    }
+
+    // Undo a `Content-Encoding` on the body so handlers see plain bytes. The
+    // decoded size is capped at `max_body` so small bombs can't expand into
+    // huge bodies. Returns the status line to reject the request with.
+    fn decode_body(&mut self, max_body: usize) -> Result<(), &'static str> {
+        let Some(encoding) = self.header("Content-Encoding") else {
+            return Ok(());
+        };
+
+        if encoding.eq_ignore_ascii_case("gzip") || encoding.eq_ignore_ascii_case("x-gzip") {
+            let mut decoded = Vec::new();
+            GzDecoder::new(self.body.as_slice())
+                .take(max_body as u64 + 1)
+                .read_to_end(&mut decoded)
+                .map_err(|_| HTTP_BAD_REQUEST)?;
+            if decoded.len() > max_body {
+                return Err(HTTP_PAYLOAD_TOO_LARGE);
+            }
+            self.body = decoded;
+        } else if !encoding.eq_ignore_ascii_case("identity") {
+            return Err(HTTP_UNSUPPORTED_MEDIA_TYPE);
+        }
+
+        // Describe the body as handlers now see it
+        let len = self.body.len().to_string();
+        self.headers.retain(|(key, _)| !key.eq_ignore_ascii_case("Content-Encoding"));
+        for (key, value) in self.headers.iter_mut() {
+            if key.eq_ignore_ascii_case("Content-Length") {
+                *value = len.clone();
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the connection should stay open after this request. HTTP/1.1
+    // defaults to keep-alive, HTTP/1.0 has to ask for it.
+    fn keep_alive(&self) -> bool {
+        if self.connection_has("close") {
+            false
+        } else if self.connection_has("keep-alive") {
+            true
+        } else {
+            self.version == "HTTP/1.1"
+        }
+    }
+
+    // Whether the comma-separated `Connection` header lists an option, as in
+    // `Connection: keep-alive, Upgrade`
+    fn connection_has(&self, option: &str) -> bool {
+        self.header("Connection")
+            .is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case(option)))
+    }
+
+    // Number of non-empty segments in the path, ignoring any query string
+    fn path_segment_count(&self) -> usize {
+        let path = self.path.split('?').next().unwrap_or("");
+        path.split('/').filter(|segment| !segment.is_empty()).count()
+    }
+
+    // The offered content type the client prefers going by `Accept`, or
+    // `None` if it accepts none of them and should get a 406. A missing
+    // `Accept` accepts anything, and ties go to the earlier offer, so the
+    // first offer is the default.
+    fn negotiate<'a>(&self, offered: &[&'a str]) -> Option<&'a str> {
+        self.negotiated_on("Accept");
+        let Some(accept) = self.header("Accept").filter(|accept| !accept.trim().is_empty()) else {
+            return offered.first().copied();
+        };
+
+        // Media ranges with their quality, skipping ones we can't parse
+        let ranges: Vec<(&str, f32)> = accept.split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let media = params.next()?.trim();
+                let quality = match params.find_map(|param| param.trim().strip_prefix("q=")) {
+                    Some(quality) => quality.trim().parse().ok()?,
+                    None => 1.0,
+                };
+                Some((media, quality))
+            })
+            .collect();
+
+        // An offer takes the quality of the most specific range matching it
+        let quality_of = |offer: &str| {
+            let (offer_type, _) = offer.split_once('/').unwrap_or((offer, ""));
+            ranges.iter()
+                .filter_map(|&(media, quality)| {
+                    let specificity = if media == "*/*" {
+                        0
+                    } else if media.strip_suffix("/*").is_some_and(|media_type| media_type.eq_ignore_ascii_case(offer_type)) {
+                        1
+                    } else if media.eq_ignore_ascii_case(offer) {
+                        2
+                    } else {
+                        return None;
+                    };
+                    Some((specificity, quality))
+                })
+                .max_by_key(|&(specificity, _)| specificity)
+                .map_or(0.0, |(_, quality)| quality)
+        };
+
+        let mut best = None;
+        let mut best_quality = 0.0;
+        for &offer in offered {
+            let quality = quality_of(offer);
+            if quality > best_quality {
+                best = Some(offer);
+                best_quality = quality;
+            }
+        }
+        best
+    }
+
+    // Whether `Accept-Encoding` allows a content coding like `gzip`
+    fn accepts_encoding(&self, coding: &str) -> bool {
+        self.negotiated_on("Accept-Encoding");
+
+        // An explicit entry for the coding wins over `*`
+        let mut wildcard = None;
+        for (name, quality) in headers::accept_encoding(self) {
+            if name.eq_ignore_ascii_case(coding) {
+                return quality > 0.0;
+            } else if name == "*" {
+                wildcard = Some(quality > 0.0);
+            }
+        }
+        wildcard.unwrap_or(false)
+    }
+
+    // Note a request header the response depends on
+    fn negotiated_on(&self, header: &'static str) {
+        let mut headers = self.negotiated_on.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !headers.contains(&header) {
+            headers.push(header);
+        }
+    }
+
+    // Time remaining until the deadline, zero once it has passed and
+    // `Duration::MAX` without one
+    fn time_left(&self) -> Duration {
+        match self.deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => Duration::MAX,
+        }
+    }
+}
+
+// Check an `HTTP/x.y` version token, accepting only HTTP/1.x
+fn parse_version(version: &str) -> Result<&str, ParseError> {
+    let numbers = version.strip_prefix("HTTP/").ok_or(ParseError::BadVersion)?;
+    let (major, minor) = numbers.split_once('.').ok_or(ParseError::BadVersion)?;
+    let is_digit = |part: &str| part.len() == 1 && part.as_bytes()[0].is_ascii_digit();
+    if !is_digit(major) || !is_digit(minor) {
+        return Err(ParseError::BadVersion);
+    }
+    if major != "1" {
+        return Err(ParseError::UnsupportedVersion);
+    }
+    Ok(version)
+}
+
+// Split an absolute-form target such as `http://example.com/a?b` into its
+// authority and the rest of the target (`example.com`, `/a?b`)
+fn split_absolute_target(target: &str) -> Option<(&str, &str)> {
+    let scheme_end = target.find("://")?;
+    let scheme = &target[..scheme_end];
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+
+    let rest = &target[scheme_end + 3..];
+    let path_start = rest.find(['/', '?']).unwrap_or(rest.len());
+    Some(rest.split_at(path_start))
+}
+
+// Whether `byte` may appear in a token such as a method, per RFC 9110's
+// `tchar`
+fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+// Position of the first occurrence of `needle` in `haystack`
+fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// Status code of a response and its reason phrase, kept apart so the
+// phrase can be changed without touching the code
+#[derive(Debug, Clone)]
+struct StatusCode {
+    code: u16,
+    reason: String,
+}
+
+impl StatusCode {
+    // Split a status line like `HTTP/1.1 404 Not Found\r\n`
+    fn from_status_line(status_line: &str) -> Self {
+        let mut parts = status_line.trim_end().splitn(3, ' ').skip(1);
+        let code = parts.next().and_then(|code| code.parse().ok()).unwrap_or(0);
+        let reason = parts.next().unwrap_or("").to_string();
+        StatusCode { code, reason }
+    }
+}
+
+// HTTP Response builder
+pub struct HttpResponse {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // A `HEAD` response, sent without a body
+    head_only: bool,
+    // Length of the body a `HEAD` response stands in for, sent as
+    // `Content-Length`. `None` if it isn't known without producing the
+    // body, such as one gzipped on the way out.
+    head_length: Option<u64>,
+    // Send no `Content-Length` and close the connection after the body to
+    // delimit it, for HTTP/1.0 clients that can't take chunked bodies
+    close_delimited: bool,
+    // Body written from its source as it's sent, instead of `body`
+    stream: Option<StreamedBody>,
+}
+
+// Source of a response body that isn't held in memory
+enum StreamedBody {
+    // Output of unknown length, sent chunked, or delimited by the close to
+    // HTTP/1.0 clients
+    Reader(Box<dyn Read + Send>),
+    // The bytes `range` of a memory-mapped file, written from the map
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap, std::ops::Range<usize>),
+}
+
+impl StreamedBody {
+    // Length of the body, if known before it's sent
+    fn len(&self) -> Option<u64> {
+        match self {
+            StreamedBody::Reader(_) => None,
+            #[cfg(feature = "mmap")]
+            StreamedBody::Mapped(_, range) => Some(range.len() as u64),
+        }
+    }
+
+    // Write the body, in chunks if `chunked`. Going past `max` bytes is an
+    // error, which leaves a chunked body unterminated.
+    fn send(self, out: &mut impl Write, chunked: bool, max: Option<usize>) -> io::Result<()> {
+        match self {
+            StreamedBody::Reader(mut reader) => {
+                let mut chunk = vec![0; 8192];
+                let mut sent = 0;
+                loop {
+                    let size = match reader.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(size) => size,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    sent += size;
+                    if max.is_some_and(|max| sent > max) {
+                        return Err(io::Error::other(format!("streamed body exceeds {} bytes", sent - size)));
+                    }
+                    // Each chunk goes out as the source produces it
+                    if chunked {
+                        let mut framed = format!("{:x}\r\n", size).into_bytes();
+                        framed.extend_from_slice(&chunk[..size]);
+                        framed.extend_from_slice(b"\r\n");
+                        out.write_all(&framed)?;
+                    } else {
+                        out.write_all(&chunk[..size])?;
+                    }
+                    out.flush()?;
+                }
+                if chunked {
+                    out.write_all(b"0\r\n\r\n")?;
+                }
+                out.flush()
+            },
+            #[cfg(feature = "mmap")]
+            StreamedBody::Mapped(map, range) => out.write_all(&map[range]),
+        }
+    }
+}
+
+impl HttpResponse {
+    pub fn new(status_line: &str) -> Self {
+        HttpResponse {
+            status: StatusCode::from_status_line(status_line),
+            headers: Vec::new(),
+            body: Vec::new(),
+            head_only: false,
+            head_length: None,
+            close_delimited: false,
+            stream: None,
+        }
+    }
+
+    // Replace the standard reason phrase, for clients that key off it
+    fn with_reason(mut self, reason: &str) -> Self {
+        self.status.reason = reason.to_string();
+        self
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    // Bulk-add headers, keeping the iteration order of the collection
+    fn with_headers(mut self, headers: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    // Add an option to the `Connection` header, joining one the handler set,
+    // such as `upgrade`, rather than sending two
+    fn with_connection_option(mut self, option: &str) -> Self {
+        match self.headers.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case("Connection")) {
+            Some((_, value)) => {
+                value.push_str(", ");
+                value.push_str(option);
+                self
+            },
+            None => self.with_header("Connection", option),
+        }
+    }
+
+    // Set `Cache-Control` from directives like `public` and `max-age=3600`
+    fn cache_control(self, directives: &[&str]) -> Self {
+        self.with_header("Cache-Control", &directives.join(", "))
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self.stream = None;
+        self
+    }
+
+    // Send the body from `reader` as it produces it, chunked, or delimited
+    // by the close to HTTP/1.0 clients. It's sent as is, never compressed
+    // or rewritten, and a response holding it is written right away rather
+    // than held with pipelined ones.
+    fn with_reader(mut self, reader: impl Read + Send + 'static) -> Self {
+        self.body = Vec::new();
+        self.stream = Some(StreamedBody::Reader(Box::new(reader)));
+        self
+    }
+
+    // Send the bytes `range` of a mapped file, written to the socket from
+    // the map without being copied first
+    #[cfg(feature = "mmap")]
+    fn with_mapped(mut self, map: memmap2::Mmap, range: std::ops::Range<usize>) -> Self {
+        self.body = Vec::new();
+        self.stream = Some(StreamedBody::Mapped(map, range));
+        self
+    }
+
+    // Copy a mapped body into `body`, for the steps that rewrite it
+    fn buffer_mapped(&mut self) {
+        #[cfg(feature = "mmap")]
+        if let Some(StreamedBody::Mapped(map, range)) = self.stream.take() {
+            self.body = map[range].to_vec();
+        }
+    }
+
+    // Delimit the body by closing the connection instead of by length
+    fn close_delimited(mut self) -> Self {
+        self.close_delimited = true;
+        self
+    }
+
+    // Answer `HEAD` for a body of `len` bytes without having it at hand
+    // (`None` if its length isn't known)
+    fn headers_only(mut self, len: impl Into<Option<u64>>) -> Self {
+        self.body = Vec::new();
+        self.stream = None;
+        self.head_only = true;
+        self.head_length = len.into();
+        self
+    }
+
+    // Length of the body as sent, `None` when it isn't known up front
+    fn body_len(&self) -> Option<u64> {
+        match &self.stream {
+            _ if self.head_only => self.head_length,
+            Some(stream) => stream.len(),
+            None => Some(self.body.len() as u64),
+        }
+    }
+
+    // Whether the body is sent chunked, being streamed with no known length
+    fn is_chunked(&self) -> bool {
+        !self.head_only && !self.close_delimited && self.body_len().is_none()
+    }
+
+    fn status_code(&self) -> u16 {
+        self.status.code
+    }
+
+    fn reason(&self) -> &str {
+        &self.status.reason
+    }
+
+    fn build(self) -> Vec<u8> {
+        let (mut response, body) = self.into_parts();
+        response.extend_from_slice(&body);
+        response
+    }
+
+    // Add `; charset=utf-8` to a `text/*` or `application/json` content
+    // type without a charset, if the body is UTF-8. An encoded body can't be
+    // checked, so this runs before compression too, and neither can the
+    // missing body of a `HEAD` response or a streamed one.
+    fn set_default_charset(&mut self) {
+        let encoded = self.headers.iter().any(|(key, _)| key.eq_ignore_ascii_case("Content-Encoding"))
+            || self.head_only
+            || self.stream.is_some();
+        let Some((_, content_type)) = self.headers.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case("Content-Type"))
+        else {
+            return;
+        };
+        if lacks_charset(content_type) && !encoded && std::str::from_utf8(&self.body).is_ok() {
+            content_type.push_str("; charset=utf-8");
+        }
+    }
+
+    // Serialized status line and headers, and the body, for sending apart
+    fn into_parts(mut self) -> (Vec<u8>, Vec<u8>) {
+        let head = self.head();
+        (head, self.body)
+    }
+
+    // Serialized status line and headers
+    fn head(&mut self) -> Vec<u8> {
+        self.set_default_charset();
+        let mut response = Vec::new();
+        let status_code = self.status_code();
+        let body_len = self.body_len();
+        
+        // Add status line
+        response.extend_from_slice(format!("HTTP/1.1 {} {}\r\n", status_code, self.status.reason).as_bytes());
+        
+        // Add headers
+        for (key, value) in &self.headers {
+            // Content-Length is always computed from the actual body below
+            if key.eq_ignore_ascii_case("Content-Length") {
+                match body_len {
+                    Some(len) if value.trim() != len.to_string() && !self.close_delimited => {
+                        eprintln!("Ignoring Content-Length {} set by handler, body is {} bytes", value, len);
+                    },
+                    _ => {},
+                }
+                continue;
+            }
+            response.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+        }
+        
+        // Add Content-Length header, except for statuses that never have a
+        // body, bodies the connection's close delimits and `HEAD` responses
+        // that can't know it
+        let has_length = status_code >= 200 && status_code != 204 && status_code != 304 && !self.close_delimited;
+        if let (true, Some(len)) = (has_length, body_len) {
+            response.extend_from_slice(format!("Content-Length: {}\r\n", len).as_bytes());
+        }
+        if self.is_chunked() {
+            response.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+        }
+        
+        // Add empty line to separate headers from body
+        response.extend_from_slice(b"\r\n");
+        
+        response
+    }
+}
+
+// Whether a content type is `text/*` or `application/json` with no charset,
+// which `set_default_charset` then labels UTF-8
+fn lacks_charset(content_type: &str) -> bool {
+    let mut params = content_type.split(';');
+    let media_type = params.next().unwrap_or("").trim().to_ascii_lowercase();
+    let has_charset = params.any(|param| param.trim().to_ascii_lowercase().starts_with("charset="));
+    (media_type.starts_with("text/") || media_type == "application/json") && !has_charset
+}
+
+// Gzip a full 200 response of a compressible type for a client accepting
+// it. Partial and already encoded responses are left alone, as are small
+// ones that wouldn't shrink, and streamed ones of unknown length. A `HEAD`
+// response gets the headers the `GET` would, but no `Content-Length`, since
+// the gzipped length isn't known without compressing.
+fn compress_response(config: &Config, request: &HttpRequest, mut response: HttpResponse) -> HttpResponse {
+    let encoded = response.headers.iter().any(|(key, _)| key.eq_ignore_ascii_case("Content-Encoding"));
+    let len = response.body_len();
+    if !config.compress_responses
+        || response.status_code() != 200
+        || encoded
+        || len.is_none_or(|len| len < config.compression_min_size as u64)
+        || !is_compressible(&config.compressible_types, &response)
+        || !request.accepts_encoding("gzip")
+    {
+        return response;
+    }
+
+    let compressed = if response.head_only {
+        response.head_length = None;
+        None
+    } else {
+        response.buffer_mapped();
+        response.set_default_charset();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        match encoder.write_all(&response.body).and_then(|()| encoder.finish()) {
+            Ok(compressed) => Some(compressed),
+            Err(e) => {
+                eprintln!("Failed to compress response: {}", e);
+                return response;
+            }
+        }
+    };
+
+    // The gzipped body is a different representation from the identity one,
+    // so it needs its own ETag or a cache could answer a revalidation for
+    // one with the other
+    for (key, value) in response.headers.iter_mut() {
+        if key.eq_ignore_ascii_case("ETag") && value.ends_with('"') {
+            value.insert_str(value.len() - 1, "-gzip");
+        }
+    }
+    response = response.with_header("Content-Encoding", "gzip");
+    match compressed {
+        Some(compressed) => response.with_body(compressed),
+        None => response,
+    }
+}
+
+// Hook rewriting the bodies of responses of one media type
+pub struct ResponseInterceptor {
+    // Media type of the responses handed to `rewrite`, like `text/html`
+    pub content_type: String,
+    // Called with the full `Content-Type` value and the body, returning the
+    // new body
+    pub rewrite: RewriteBody,
+}
+
+pub type RewriteBody = Box<dyn Fn(&str, Vec<u8>) -> Vec<u8> + Send + Sync>;
+
+impl ResponseInterceptor {
+    // Insert `html` before the closing `</body>` of HTML pages, or at the
+    // end of ones without it
+    pub fn inject_html(html: &str) -> Self {
+        let html = html.as_bytes().to_vec();
+        ResponseInterceptor {
+            content_type: "text/html".to_string(),
+            rewrite: Box::new(move |_content_type, mut body| {
+                let close = body.windows(7).rposition(|window| window.eq_ignore_ascii_case(b"</body>"));
+                let at = close.unwrap_or(body.len());
+                body.splice(at..at, html.iter().copied());
+                body
+            }),
+        }
+    }
+}
+
+// Pass the body through the interceptors for its media type. Encoded and
+// partial bodies aren't the whole document, and are left alone, as are
+// streamed ones of unknown length.
+fn intercept_response(config: &Config, mut response: HttpResponse) -> HttpResponse {
+    let encoded = response.headers.iter().any(|(key, _)| key.eq_ignore_ascii_case("Content-Encoding"));
+    if config.response_interceptors.is_empty() || encoded || response.status_code() == 206 {
+        return response;
+    }
+    response.buffer_mapped();
+    if response.stream.is_some() {
+        return response;
+    }
+    let Some(content_type) = response.headers.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("Content-Type"))
+        .map(|(_, value)| value.clone())
+    else {
+        return response;
+    };
+
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    for interceptor in &config.response_interceptors {
+        if interceptor.content_type.eq_ignore_ascii_case(media_type) {
+            let body = std::mem::take(&mut response.body);
+            response.body = (interceptor.rewrite)(&content_type, body);
+        }
+    }
+    response
+}
+
+// Whether the response's media type is in `types`. Responses without a
+// `Content-Type` could be anything, and aren't compressed.
+fn is_compressible(types: &[String], response: &HttpResponse) -> bool {
+    let Some((_, content_type)) = response.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case("Content-Type"))
+    else {
+        return false;
+    };
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    types.iter().any(|allowed| match allowed.strip_suffix("/*") {
+        Some(prefix) => media_type
+            .split_once('/')
+            .is_some_and(|(main_type, _)| main_type.eq_ignore_ascii_case(prefix)),
+        None => allowed.eq_ignore_ascii_case(media_type),
+    })
+}
+
+// Add the request headers a response was negotiated on to its `Vary`
+// header, keeping any the handler set itself
+fn add_vary(mut response: HttpResponse, negotiated_on: &[&str]) -> HttpResponse {
+    let mut vary: Vec<String> = response.headers.iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("Vary"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let before = vary.len();
+    for &header in negotiated_on {
+        if !vary.iter().any(|name| name.eq_ignore_ascii_case(header) || name == "*") {
+            vary.push(header.to_string());
+        }
+    }
+    if vary.len() == before {
+        return response;
+    }
+
+    response.headers.retain(|(key, _)| !key.eq_ignore_ascii_case("Vary"));
+    response.with_header("Vary", &vary.join(", "))
+}
+
+// How framework-generated errors are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ErrorFormat {
+    // Plain text such as `404 - Not Found`
+    Text,
+    // JSON such as `{"error":"Not Found","status":404}`
+    Json,
+    // RFC 7807 `application/problem+json` with type, title, status and
+    // detail members, for API clients
+    Problem,
+}
+
+// Response for a framework-generated error in the configured format
+fn error_response(config: &Config, status_line: &str) -> HttpResponse {
+    let response = HttpResponse::new(status_line);
+    let (code, reason) = (response.status_code(), response.reason());
+
+    let (content_type, body) = match config.error_format {
+        ErrorFormat::Text => ("text/plain", format!("{} - {}", code, reason)),
+        ErrorFormat::Json => ("application/json", format!("{{\"error\":\"{}\",\"status\":{}}}", reason, code)),
+        ErrorFormat::Problem => {
+            let detail = error_detail(code).map(|detail| format!(",\"detail\":\"{}\"", detail)).unwrap_or_default();
+            let body = format!("{{\"type\":\"about:blank\",\"title\":\"{}\",\"status\":{}{}}}", reason, code, detail);
+            ("application/problem+json", body)
+        },
+    };
+    response
+        .with_header("Content-Type", content_type)
+        .with_body(body.into_bytes())
+}
+
+// Explanation of the errors the server itself produces, for problem details
+fn error_detail(code: u16) -> Option<&'static str> {
+    Some(match code {
+        400 => "The request could not be parsed.",
+        401 => "The request lacks valid credentials.",
+        403 => "The request is not allowed for this resource.",
+        404 => "No resource exists at the requested path.",
+        405 => "The resource does not support the request method, see Allow.",
+        408 => "The request was not received in time.",
+        413 => "The request body exceeds the size limit.",
+        414 => "The request target exceeds the length limit.",
+        426 => "The resource requires switching protocols, see Upgrade.",
+        429 => "Too many requests were sent, retry later.",
+        431 => "The request headers exceed the size limit.",
+        500 => "The server failed while handling the request.",
+        503 => "The server is not accepting requests right now, see Retry-After.",
+        504 => "The request was not handled within its time budget.",
+        _ => return None,
+    })
+}
+
+// 405 listing the methods the resource does support in `Allow`
+fn method_not_allowed(config: &Config, allowed: &[&str]) -> HttpResponse {
+    error_response(config, HTTP_METHOD_NOT_ALLOWED).with_header("Allow", &allowed.join(", "))
+}
+
+// 426 for a route only served over another protocol, such as `TLS/1.2` for
+// a TLS-only route or `websocket`, named in the `Upgrade` header
+fn upgrade_required(config: &Config, protocol: &str) -> HttpResponse {
+    error_response(config, HTTP_UPGRADE_REQUIRED)
+        .with_header("Upgrade", protocol)
+        .with_header("Connection", "upgrade")
+}
+
+// 503 telling the client to back off for `retry_after`, used when the server
+// is overloaded or shutting down
+fn service_unavailable(config: &Config) -> HttpResponse {
+    error_response(config, HTTP_SERVICE_UNAVAILABLE)
+        .with_header("Retry-After", &config.retry_after.as_secs().to_string())
+        .with_header("Connection", "close")
+}
+
+// Answer a new connection with a 503 if it can't be served, returning
+// whether it was turned away. Connections that arrive while draining are
+// told to retry elsewhere instead of finding the socket closed, as are those
+// past the connection limit. The small 503 fits in the socket buffer, so
+// this doesn't stall accepting.
+fn turn_away(stream: &mut impl Write, config: &Config, state: &ServerState, draining: bool) -> bool {
+    if !draining && state.active_connections.load(Ordering::SeqCst) < config.max_connections {
+        return false;
+    }
+    send_response(stream, service_unavailable(config), config);
+    true
+}
+
+// Number of backtrace frames included in a debug 500 response
+const PANIC_BACKTRACE_FRAMES: usize = 10;
+
+// Frames of a backtrace between the panic machinery and the thread start,
+// the part std shows in its short backtraces
+fn short_backtrace(backtrace: &str) -> Vec<String> {
+    let mut frames: Vec<String> = Vec::new();
+    for line in backtrace.lines() {
+        let is_frame_start = line.trim_start().split(':').next()
+            .is_some_and(|index| !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()));
+        match frames.last_mut() {
+            Some(frame) if !is_frame_start => {
+                frame.push('\n');
+                frame.push_str(line);
+            },
+            _ => frames.push(line.to_string()),
+        }
+    }
+
+    let start = frames.iter()
+        .position(|frame| frame.contains("__rust_end_short_backtrace"))
+        .map_or(0, |pos| pos + 1);
+    frames.into_iter()
+        .skip(start)
+        .filter(|frame| !frame.contains("rust_begin_unwind") && !frame.contains("core::panicking::"))
+        .take_while(|frame| !frame.contains("__rust_begin_short_backtrace"))
+        .take(PANIC_BACKTRACE_FRAMES)
+        .collect()
+}
+
+// 500 for a handler that panicked, with the panic message and the start of
+// its backtrace when `expose_panic_details` is set
+fn panic_response(config: &Config, payload: Box<dyn Any + Send>) -> HttpResponse {
+    let backtrace = PANIC_BACKTRACE.with(|backtrace| backtrace.borrow_mut().take());
+    if !config.expose_panic_details {
+        return error_response(config, HTTP_INTERNAL_SERVER_ERROR);
+    }
+
+    let message = payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    let mut body = format!("500 - Internal Server Error\n\nHandler panicked: {}\n", message);
+    if let Some(backtrace) = backtrace {
+        body.push('\n');
+        for frame in short_backtrace(&backtrace) {
+            body.push_str(&frame);
+            body.push('\n');
+        }
+    }
+
+    HttpResponse::new(HTTP_INTERNAL_SERVER_ERROR)
+        .with_header("Content-Type", "text/plain")
+        .with_body(body.into_bytes())
+}
+
+// Request handler, implemented for any function or closure taking a request
+// and the state shared by every handler of the router
+pub trait Handler<S>: Send + Sync {
+    fn handle(&self, request: &HttpRequest, state: &S) -> HttpResponse;
+}
+
+impl<S, F> Handler<S> for F
+where
+    F: Fn(&HttpRequest, &S) -> HttpResponse + Send + Sync,
+{
+    fn handle(&self, request: &HttpRequest, state: &S) -> HttpResponse {
+        self(request, state)
+    }
+}
+
+impl<S> Handler<S> for Box<dyn Handler<S>> {
+    fn handle(&self, request: &HttpRequest, state: &S) -> HttpResponse {
+        (**self).handle(request, state)
+    }
+}
+
+// Why a typed handler's input couldn't be built from the request
+#[derive(Debug)]
+pub enum HandlerError {
+    // The body isn't of a type the target can be parsed from
+    UnsupportedMediaType,
+    // The body is of the right type but doesn't parse, or is missing
+    // something the target needs
+    Malformed,
+}
+
+impl HandlerError {
+    fn status_line(&self) -> &'static str {
+        match self {
+            HandlerError::UnsupportedMediaType => HTTP_UNSUPPORTED_MEDIA_TYPE,
+            HandlerError::Malformed => HTTP_BAD_REQUEST,
+        }
+    }
+}
+
+// A value a typed handler receives, parsed from the request body before the
+// handler runs
+pub trait FromBody: Sized {
+    fn from_body(request: &HttpRequest) -> Result<Self, HandlerError>;
+}
+
+// Fields of an `application/x-www-form-urlencoded` body in the order sent.
+// Typed form structs build on it, pulling their fields out with `get`.
+pub struct Form(Vec<(String, String)>);
+
+impl Form {
+    // First value of the field `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+impl FromBody for Form {
+    fn from_body(request: &HttpRequest) -> Result<Self, HandlerError> {
+        let media_type = request.header("Content-Type").and_then(|value| value.split(';').next()).unwrap_or("");
+        if !media_type.trim().eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return Err(HandlerError::UnsupportedMediaType);
+        }
+        let body = std::str::from_utf8(&request.body).map_err(|_| HandlerError::Malformed)?;
+
+        // `+` stands for a space, so it's replaced before `%2B` is decoded
+        let decode = |text: &str| percent_decode(&text.replace('+', " "));
+        let fields = body
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode(key), decode(value))
+            })
+            .collect();
+        Ok(Form(fields))
+    }
+}
+
+// A JSON body, taken from the `JsonBody` the connection already parsed or
+// parsed here when `json_limits` is off
+impl FromBody for JsonValue {
+    fn from_body(request: &HttpRequest) -> Result<Self, HandlerError> {
+        if !request.is_json() {
+            return Err(HandlerError::UnsupportedMediaType);
+        }
+        match request.extensions.get::<JsonBody>() {
+            Some(JsonBody(value)) => Ok(value.clone()),
+            None => parse_json(&request.body[..], &JsonLimits::default()).map_err(|_| HandlerError::Malformed),
+        }
+    }
+}
+
+// Handler adapter that parses the body into `T` and passes it to `handler`,
+// answering 415 or 400 in the configured error format without calling the
+// handler when that fails
+pub struct Typed<T, F> {
+    config: Arc<Config>,
+    handler: F,
+    body: PhantomData<fn() -> T>,
+}
+
+impl<T, F> Typed<T, F> {
+    pub fn new(config: Arc<Config>, handler: F) -> Self {
+        Typed { config, handler, body: PhantomData }
+    }
+}
+
+impl<S, T, F> Handler<S> for Typed<T, F>
+where
+    T: FromBody,
+    F: Fn(&HttpRequest, T, &S) -> HttpResponse + Send + Sync,
+{
+    fn handle(&self, request: &HttpRequest, state: &S) -> HttpResponse {
+        match T::from_body(request) {
+            Ok(body) => (self.handler)(request, body, state),
+            Err(e) => error_response(&self.config, e.status_line()),
+        }
+    }
+}
+
+// Handler saving the files of a `multipart/form-data` upload in a directory
+// as they arrive, under the names they were sent with. Register it with
+// `add_streaming` so the body is never held in memory. Answers 201 listing
+// the names saved. Fields that aren't files are skipped, and a name that's
+// already taken is refused with 409.
+struct Uploads {
+    config: Arc<Config>,
+    dir: PathBuf,
+}
+
+impl Uploads {
+    fn new(config: Arc<Config>, dir: &Path) -> Self {
+        Uploads { config, dir: dir.to_path_buf() }
+    }
+
+    fn save(&self, request: &HttpRequest) -> Result<Vec<String>, &'static str> {
+        let mut parts = request.multipart(&self.config.limits).ok_or(HTTP_UNSUPPORTED_MEDIA_TYPE)?;
+        let mut saved = Vec::new();
+        while let Some(headers) = parts.next_part().map_err(|e| self.failed(request, e))? {
+            let Some(name) = upload_file_name(&headers) else {
+                continue;
+            };
+            let file = fs::OpenOptions::new().write(true).create_new(true).open(self.dir.join(&name));
+            let mut file = match file {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(HTTP_CONFLICT),
+                Err(e) => return Err(self.failed(request, MultipartError::Io(e))),
+            };
+            parts.copy_body(&mut file).map_err(|e| self.failed(request, e))?;
+            saved.push(name);
+        }
+        Ok(saved)
+    }
+
+    fn failed(&self, request: &HttpRequest, error: MultipartError) -> &'static str {
+        match error {
+            MultipartError::Malformed => HTTP_BAD_REQUEST,
+            MultipartError::Io(e) if is_incomplete(&e) || is_disconnect(&e) => {
+                debug_log!("Upload to {} cut short: {}", request.path, e);
+                HTTP_BAD_REQUEST
+            },
+            MultipartError::Io(e) => {
+                eprintln!("Failed to save upload in {}: {}", self.dir.display(), e);
+                HTTP_INTERNAL_SERVER_ERROR
+            },
+        }
+    }
+}
+
+impl<S> Handler<S> for Uploads {
+    fn handle(&self, request: &HttpRequest, _state: &S) -> HttpResponse {
+        match self.save(request) {
+            Ok(saved) => {
+                let body = saved.iter().map(|name| format!("{}\n", name)).collect::<String>();
+                HttpResponse::new(HTTP_CREATED)
+                    .with_header("Content-Type", "text/plain")
+                    .with_body(body.into_bytes())
+            },
+            Err(status_line) => error_response(&self.config, status_line),
+        }
+    }
+}
+
+// File name of an uploaded part, from the `filename` of its
+// `Content-Disposition`. Only the last path component is kept, so a name
+// like `../../etc/passwd` can't leave the upload directory.
+fn upload_file_name(headers: &[(String, String)]) -> Option<String> {
+    let disposition = headers.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("Content-Disposition"))
+        .map(|(_, value)| value.as_str())?;
+    let name = disposition.split(';')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("filename"))
+        .map(|(_, value)| value.trim().trim_matches('"'))?;
+    let name = name.rsplit(['/', '\\']).next().unwrap_or("");
+    (!name.is_empty() && name != "." && name != "..").then(|| name.to_string())
+}
+
+// Handler running a command for each request, CGI-style: the request body
+// is its stdin, and its stdout is streamed back as the response body while
+// the command writes it. Lines of its stderr go to the server log. A command
+// that exits before writing anything is answered by how it exited, 200 on
+// success and 500 otherwise. Once output has gone out a failure can only
+// cut the body off, which leaves the chunked body unterminated so the client
+// can tell.
+pub struct Subprocess {
+    config: Arc<Config>,
+    program: String,
+    args: Vec<String>,
+    content_type: String,
+}
+
+impl Subprocess {
+    pub fn new(config: Arc<Config>, program: &str) -> Self {
+        Subprocess { config, program: program.to_string(), args: Vec::new(), content_type: "text/plain".to_string() }
+    }
+
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    // Content type of the output, `text/plain` unless set
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = content_type.to_string();
+        self
+    }
+
+    fn spawn(&self, input: Vec<u8>) -> io::Result<process::Child> {
+        let mut child = process::Command::new(&self.program)
+            .args(&self.args)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()?;
+
+        // Feed stdin from its own thread, or a command writing a lot before
+        // reading its input would never see the rest of it. A command that
+        // exits without reading its input closes the pipe, which isn't an
+        // error.
+        if let Some(mut stdin) = child.stdin.take() {
+            thread::spawn(move || {
+                let _ = stdin.write_all(&input);
+            });
+        }
+        if let Some(stderr) = child.stderr.take() {
+            let program = self.program.clone();
+            thread::spawn(move || {
+                for line in io::BufReader::new(stderr).lines().map_while(Result::ok) {
+                    eprintln!("{}: {}", program, line);
+                }
+            });
+        }
+        Ok(child)
+    }
+
+    // The response once the command has exited without writing anything
+    fn exited(&self, request: &HttpRequest, status: io::Result<process::ExitStatus>) -> HttpResponse {
+        match status {
+            Ok(status) if status.success() => HttpResponse::new(HTTP_OK).with_header("Content-Type", &self.content_type),
+            Ok(status) => {
+                eprintln!("{} for {} {} exited with {}", self.program, request.method, request.path, status);
+                error_response(&self.config, HTTP_INTERNAL_SERVER_ERROR)
+            },
+            Err(e) => {
+                eprintln!("Failed to wait for {}: {}", self.program, e);
+                error_response(&self.config, HTTP_INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+impl<S> Handler<S> for Subprocess {
+    fn handle(&self, request: &HttpRequest, _state: &S) -> HttpResponse {
+        let mut child = match self.spawn(request.body.clone()) {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("Failed to run {}: {}", self.program, e);
+                return error_response(&self.config, HTTP_INTERNAL_SERVER_ERROR);
+            }
+        };
+        let Some(mut stdout) = child.stdout.take() else {
+            return error_response(&self.config, HTTP_INTERNAL_SERVER_ERROR);
+        };
+
+        // The status is only settled by the first output, or the exit
+        let mut first = vec![0; 8192];
+        let size = loop {
+            match stdout.read(&mut first) {
+                Ok(size) => break size,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => {
+                    eprintln!("Failed to read the output of {}: {}", self.program, e);
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return error_response(&self.config, HTTP_INTERNAL_SERVER_ERROR);
+                }
+            }
+        };
+        if size == 0 {
+            return self.exited(request, child.wait());
+        }
+
+        first.truncate(size);
+        let output = CommandOutput {
+            program: self.program.clone(),
+            output: io::Cursor::new(first).chain(stdout),
+            child,
+        };
+        HttpResponse::new(HTTP_OK)
+            .with_header("Content-Type", &self.content_type)
+            .with_reader(output)
+    }
+}
+
+// Stdout of a running command. Its end waits for the command, and is an
+// error instead if the command failed. Dropped early, as when the client
+// goes away, it kills the command.
+struct CommandOutput {
+    program: String,
+    output: io::Chain<io::Cursor<Vec<u8>>, process::ChildStdout>,
+    child: process::Child,
+}
+
+impl Read for CommandOutput {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let size = self.output.read(out)?;
+        if size == 0 && !out.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("{} exited with {}", self.program, status)));
+            }
+        }
+        Ok(size)
+    }
+}
+
+impl Drop for CommandOutput {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Middleware wrapping request handling. Call `next.run(request)` to continue
+// down the chain, or return a response without calling it to stop here:
+// nothing after this middleware runs, the handler included, and the response
+// goes to the client as is.
+trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut HttpRequest, next: Next) -> HttpResponse;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut HttpRequest, Next) -> HttpResponse + Send + Sync,
+{
+    fn handle(&self, request: &mut HttpRequest, next: Next) -> HttpResponse {
+        self(request, next)
+    }
+}
+
+// Middleware giving the rest of the chain a shared deadline. Stages can't be
+// interrupted, so one that overruns finishes, but later stages are skipped
+// and its late response is replaced with a 504.
+struct Timeout {
+    budget: Duration,
+}
+
+impl Middleware for Timeout {
+    fn handle(&self, request: &mut HttpRequest, next: Next) -> HttpResponse {
+        let deadline = Instant::now() + self.budget;
+        request.deadline = Some(request.deadline.map_or(deadline, |existing| existing.min(deadline)));
+
+        let config = next.config;
+        let response = next.run(request);
+        if request.time_left().is_zero() {
+            debug_log!("{} {} exceeded its {:?} budget", request.method, request.path, self.budget);
+            return error_response(config, HTTP_GATEWAY_TIMEOUT);
+        }
+        response
+    }
+}
+
+// Middleware sending the `early_hints` configured for a `GET`'s path before
+// the rest of the chain prepares the response
+fn send_early_hints(request: &mut HttpRequest, next: Next) -> HttpResponse {
+    let path = request.path.split('?').next().unwrap_or("");
+    let hints = next.config.early_hints.iter().find(|(hinted, _)| hinted == path);
+    if let (true, Some((_, links))) = (request.method == "GET", hints) {
+        let links: Vec<&str> = links.iter().map(String::as_str).collect();
+        if let Err(e) = request.early_hints(&links) {
+            debug_log!("Failed to send early hints for {}: {}", request.path, e);
+        }
+    }
+    next.run(request)
+}
+
+// Middleware answering requests below the `tls_only_prefixes` with 426
+// unless they reached the proxy in front over HTTPS
+fn require_tls(request: &mut HttpRequest, next: Next) -> HttpResponse {
+    let tls_only = next.config.tls_only_prefixes.iter()
+        .any(|prefix| path_under(&request.path, prefix.trim_end_matches('/')));
+    if tls_only && !request.over_https() {
+        return upgrade_required(next.config, "TLS/1.2, HTTP/1.1");
+    }
+    next.run(request)
+}
+
+// A CIDR range of IP addresses
+struct IpRange {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl IpRange {
+    // Parse `addr/prefix`, or a bare address for just that address
+    fn parse(cidr: &str) -> Option<IpRange> {
+        let (network, prefix_len) = match cidr.split_once('/') {
+            Some((network, prefix_len)) => (network.parse().ok()?, Some(prefix_len.parse().ok()?)),
+            None => (cidr.parse().ok()?, None),
+        };
+        let max_len = if matches!(network, IpAddr::V4(_)) { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        (prefix_len <= max_len).then_some(IpRange { network, prefix_len })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+// Filter answering 403 to clients outside the allowlist or inside the
+// denylist. The denylist wins when a client is in both.
+struct IpFilter {
+    allow: Vec<IpRange>,
+    deny: Vec<IpRange>,
+}
+
+impl IpFilter {
+    fn new(allow: &[String], deny: &[String]) -> io::Result<Self> {
+        let parse = |ranges: &[String]| {
+            ranges.iter()
+                .map(|cidr| IpRange::parse(cidr).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid IP range {:?}", cidr))
+                }))
+                .collect::<io::Result<Vec<_>>>()
+        };
+        Ok(IpFilter { allow: parse(allow)?, deny: parse(deny)? })
+    }
+
+    fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+}
+
+// Middleware limiting each client IP to `limit` requests per fixed window,
+// turning away the rest with 429 before they reach the handler
+struct RateLimit {
+    limit: u32,
+    window: Duration,
+    // Start of the current window and requests seen in it, per client
+    clients: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl RateLimit {
+    // Clients are forgotten once their window is over, but only swept when
+    // the table gets this big
+    const SWEEP_THRESHOLD: usize = 1024;
+
+    fn new(limit: u32, window: Duration) -> Self {
+        RateLimit { limit, window, clients: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, request: &mut HttpRequest, next: Next) -> HttpResponse {
+        let Some(ip) = request.remote_addr.map(|addr| addr.ip()) else {
+            return next.run(request);
+        };
+
+        let now = Instant::now();
+        let retry_after = {
+            let mut clients = self.clients.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if clients.len() >= Self::SWEEP_THRESHOLD {
+                clients.retain(|_, (started, _)| now.duration_since(*started) < self.window);
+            }
+
+            let (started, count) = clients.entry(ip).or_insert((now, 0));
+            if now.duration_since(*started) >= self.window {
+                *started = now;
+                *count = 0;
+            }
+            *count += 1;
+            (*count > self.limit).then(|| self.window.saturating_sub(now.duration_since(*started)))
+        };
+
+        match retry_after {
+            Some(retry_after) => {
+                debug_log!("Rate limiting {}", ip);
+                // A client over its limit gets no more of the connection
+                request.close_connection();
+                // Round up, so the client doesn't come back a moment too soon
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                error_response(next.config, HTTP_TOO_MANY_REQUESTS)
+                    .with_header("Retry-After", &secs.to_string())
+            },
+            None => next.run(request),
+        }
+    }
+}
+
+// The rest of the middleware chain, ending in route dispatch
+struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    router: &'a dyn Dispatch,
+    config: &'a Config,
+}
+
+// Route dispatch at the end of the middleware chain, behind a trait so that
+// middleware doesn't depend on the router's state type
+trait Dispatch {
+    fn dispatch(&self, request: &mut HttpRequest, config: &Config) -> HttpResponse;
+}
+
+impl Next<'_> {
+    fn run(self, request: &mut HttpRequest) -> HttpResponse {
+        // Don't start another stage once the shared budget is spent
+        if request.time_left().is_zero() {
+            return error_response(self.config, HTTP_GATEWAY_TIMEOUT);
+        }
+
+        match self.middleware.split_first() {
+            Some((first, rest)) => {
+                let next = Next { middleware: rest, ..self };
+                first.handle(request, next)
+            },
+            None => self.router.dispatch(request, self.config),
+        }
+    }
+}
+
+// A single method + path route
+struct Route<S> {
+    method: String,
+    // Path pattern, where a `:name` segment matches any one segment
+    path: String,
+    handler: Box<dyn Handler<S>>,
+    // Whether the handler looks at the request body
+    reads_body: bool,
+    // Whether the handler reads the body from the connection itself
+    streams_body: bool,
+}
+
+// Handler for every method and path below a prefix
+struct Mount<S> {
+    prefix: String,
+    handler: Box<dyn Handler<S>>,
+}
+
+// Header a request below a path prefix must carry with a given value
+struct RequiredHeader {
+    prefix: String,
+    name: String,
+    value: String,
+}
+
+// Match a request path against a route pattern, returning the `:name`
+// captures. Segments are split on the raw path and only then decoded, so
+// `/files/a%2Fb` matches `/files/:name` with `name` being `a/b`.
+fn match_route(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+    let path = path.split('?').next().unwrap_or("");
+    let (mut patterns, mut segments) = (pattern.split('/'), path.split('/'));
+    let mut params = Vec::new();
+    loop {
+        match (patterns.next(), segments.next()) {
+            (None, None) => return Some(params),
+            (Some(expected), Some(segment)) => match expected.strip_prefix(':') {
+                Some(name) if !segment.is_empty() => params.push((name.to_string(), percent_decode(segment))),
+                Some(_) => return None,
+                None if expected == segment => {},
+                None => return None,
+            },
+            _ => return None,
+        }
+    }
+}
+
+// Decode `%XX` escapes. Malformed ones are kept as they are, and bytes that
+// aren't UTF-8 once decoded become U+FFFD.
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| segment.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Whether a path is `prefix` itself or below it, by whole segments
+fn path_under(path: &str, prefix: &str) -> bool {
+    let path = path.split('?').next().unwrap_or("");
+    path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+// Mutable application state shared by handlers on different connections.
+// Handlers only get `&S`, so state they change has to do its own locking;
+// wrapping it in `Locked` sends every access through a lock held just for
+// the closure:
+//
+//     state.hits.write(|hits| *hits += 1);
+//     let hits = state.hits.read(|hits| *hits);
+//
+// Do a whole read-modify-write inside one `write`. Reading in one call and
+// writing back in another lets a concurrent request update in between, and
+// its update is lost. Keep the closures short, and don't lock another
+// `Locked` inside one, or two requests locking in opposite orders deadlock.
+struct Locked<T> {
+    value: RwLock<T>,
+}
+
+impl<T> Locked<T> {
+    fn new(value: T) -> Self {
+        Locked { value: RwLock::new(value) }
+    }
+
+    // Run `f` with shared access, alongside other readers
+    fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.value.read().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    // Run `f` with exclusive access, waiting for readers and writers
+    fn write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.value.write().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+}
+
+// Router mapping method + path pairs to their handlers, which all get a
+// reference to the same application state
+pub struct Router<S> {
+    routes: Vec<Route<S>>,
+    mounts: Vec<Mount<S>>,
+    required_headers: Vec<RequiredHeader>,
+    middleware: Vec<Box<dyn Middleware>>,
+    state: Arc<S>,
+    // Answer `OPTIONS` for a path with routes but no `OPTIONS` route, with
+    // its methods in `Allow`
+    auto_options: bool,
+    // Answer `HEAD` for a path with a `GET` route but no `HEAD` route with
+    // the `GET` handler, whose body the connection then leaves out
+    auto_head: bool,
+    // Clients to turn away, checked by the connection before it reads
+    // anything from them
+    ip_filter: Option<IpFilter>,
+}
+
+impl<S: Send + Sync + 'static> Router<S> {
+    fn with_state(state: Arc<S>) -> Self {
+        Router {
+            routes: Vec::new(),
+            mounts: Vec::new(),
+            required_headers: Vec::new(),
+            middleware: Vec::new(),
+            state,
+            auto_options: true,
+            auto_head: true,
+            ip_filter: None,
+        }
+    }
+
+    // Turn the automatic `OPTIONS` answer off, for routers that register
+    // their own `OPTIONS` routes or want it refused with 405
+    fn auto_options(&mut self, enabled: bool) -> &mut Self {
+        self.auto_options = enabled;
+        self
+    }
+
+    // Turn the automatic `HEAD` answer off, refusing `HEAD` with 405 where
+    // no `HEAD` route is registered
+    fn auto_head(&mut self, enabled: bool) -> &mut Self {
+        self.auto_head = enabled;
+        self
+    }
+
+    // Refuse clients by address with 403. Unlike a middleware this runs
+    // before the request is read, so a refused client's body is never read
+    // or decoded and no route, `TRACE` included, is reached.
+    fn filter_ips(&mut self, filter: IpFilter) -> &mut Self {
+        self.ip_filter = Some(filter);
+        self
+    }
+
+    // Add a middleware, run in registration order before the handler
+    fn wrap(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    // Run a request through the middleware chain and its route handler
+    fn handle(&self, request: &mut HttpRequest, config: &Config) -> HttpResponse {
+        Next { middleware: &self.middleware, router: self, config }.run(request)
+    }
+
+    pub fn add(&mut self, method: &str, path: &str, handler: impl Handler<S> + 'static) -> &mut Self {
+        self.routes.push(Route {
+            method: method.to_string(),
+            path: path.to_string(),
+            handler: Box::new(handler),
+            reads_body: true,
+            streams_body: false,
+        });
+        self
+    }
+
+    // Register a route whose handler never looks at the body, so a client
+    // waiting on `Expect: 100-continue` is never asked to upload it
+    fn add_without_body(&mut self, method: &str, path: &str, handler: impl Handler<S> + 'static) -> &mut Self {
+        self.add(method, path, handler);
+        if let Some(route) = self.routes.last_mut() {
+            route.reads_body = false;
+        }
+        self
+    }
+
+    // Register a route whose handler reads the body from the connection as
+    // it arrives, through `HttpRequest::body_reader` or `multipart`, instead
+    // of finding it in `body`. Only bodies of a known length that aren't
+    // chunked or compressed are left to it, others are read as usual. For
+    // uploads too large to hold in memory.
+    fn add_streaming(&mut self, method: &str, path: &str, handler: impl Handler<S> + 'static) -> &mut Self {
+        self.add(method, path, handler);
+        if let Some(route) = self.routes.last_mut() {
+            route.streams_body = true;
+        }
+        self
+    }
+
+    // Whether the request goes to a route added with `add_streaming`
+    fn streams_body(&self, request: &HttpRequest) -> bool {
+        self.routes.iter()
+            .find(|route| route.method == request.method && match_route(&route.path, &request.path).is_some())
+            .is_some_and(|route| route.streams_body)
+    }
+
+    // Whether the handler for this request will read its body. Mounted
+    // handlers are assumed to, requests that match nothing don't.
+    fn reads_body(&self, request: &HttpRequest) -> bool {
+        let route = self.routes.iter()
+            .find(|route| route.method == request.method && match_route(&route.path, &request.path).is_some());
+        match route {
+            Some(route) => route.reads_body,
+            None => self.find_mount(request).is_some(),
+        }
+    }
+
+    // Methods with a route matching this path, in registration order, for
+    // `Allow` on 405 and `OPTIONS` responses. Empty if no route has the path.
+    fn allowed_methods(&self, path: &str) -> Vec<&str> {
+        let mut methods = Vec::new();
+        for route in self.routes.iter().filter(|route| match_route(&route.path, path).is_some()) {
+            if !methods.contains(&route.method.as_str()) {
+                methods.push(route.method.as_str());
+            }
+        }
+        if self.auto_head && methods.contains(&"GET") && !methods.contains(&"HEAD") {
+            methods.push("HEAD");
+        }
+        methods
+    }
+
+    // 405 for a method that's turned off, with `Allow` listing what the path
+    // accepts just like a 405 from routing
+    fn refuse_method(&self, path: &str, config: &Config) -> HttpResponse {
+        let mut allowed = self.allowed_methods(path);
+        if self.auto_options && !allowed.is_empty() {
+            allowed.push("OPTIONS");
+        }
+        method_not_allowed(config, &allowed)
+    }
+
+    // Mount with the longest prefix covering the request path
+    fn find_mount(&self, request: &HttpRequest) -> Option<&Mount<S>> {
+        self.mounts.iter()
+            .filter(|mount| path_under(&request.path, &mount.prefix))
+            .max_by_key(|mount| mount.prefix.len())
+    }
+
+    // Refuse requests below `prefix` with 403 unless they carry the header
+    // `name` set to exactly `value`, before any route or mount sees them.
+    // A lighter way to gate a section than a middleware.
+    fn require_header(&mut self, prefix: &str, name: &str, value: &str) -> &mut Self {
+        self.required_headers.push(RequiredHeader {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+        self
+    }
+
+    // Register a whole table of routes at once. Handlers are boxed so that
+    // different closures and functions can share one table.
+    fn add_all<'a>(
+        &mut self,
+        routes: impl IntoIterator<Item = (&'a str, &'a str, Box<dyn Handler<S>>)>,
+    ) -> &mut Self {
+        for (method, path, handler) in routes {
+            self.add(method, path, handler);
+        }
+        self
+    }
+
+    // Hand every request below `prefix` to the handler, whatever its method.
+    // Exact routes take precedence, then the longest matching prefix.
+    fn mount(&mut self, prefix: &str, handler: impl Handler<S> + 'static) -> &mut Self {
+        self.mounts.push(Mount {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+}
+
+impl<S: Send + Sync + 'static> Dispatch for Router<S> {
+    fn dispatch(&self, request: &mut HttpRequest, config: &Config) -> HttpResponse {
+        let refused = self.required_headers.iter().any(|required| {
+            path_under(&request.path, &required.prefix) && request.header(&required.name) != Some(required.value.as_str())
+        });
+        if refused {
+            return error_response(config, HTTP_FORBIDDEN);
+        }
+
+        let route = self.routes.iter()
+            .filter(|route| route.method == request.method)
+            .find_map(|route| Some((route, match_route(&route.path, &request.path)?)));
+        if let Some((route, params)) = route {
+            request.params = params;
+            return route.handler.handle(request, &self.state);
+        }
+
+        if let Some(mount) = self.find_mount(request) {
+            return mount.handler.handle(request, &self.state);
+        }
+
+        if self.auto_head && request.method == "HEAD" {
+            let get = self.routes.iter()
+                .filter(|route| route.method == "GET")
+                .find_map(|route| Some((route, match_route(&route.path, &request.path)?)));
+            if let Some((route, params)) = get {
+                request.params = params;
+                return route.handler.handle(request, &self.state);
+            }
+        }
+
+        // The path exists for other methods: list them, and answer `OPTIONS`
+        // for it ourselves unless a route does or that's turned off
+        let mut allowed = self.allowed_methods(&request.path);
+        if allowed.is_empty() {
+            return error_response(config, HTTP_NOT_FOUND);
+        }
+        if !self.auto_options {
+            return method_not_allowed(config, &allowed);
+        }
+        allowed.push("OPTIONS");
+        if request.method == "OPTIONS" {
+            HttpResponse::new(HTTP_NO_CONTENT).with_header("Allow", &allowed.join(", "))
+        } else {
+            method_not_allowed(config, &allowed)
+        }
+    }
+}
+
+// Built-in route handlers
+// Default robots policy, letting crawlers fetch everything
+const ROBOTS_TXT: &str = "User-agent: *\nDisallow:\n";
+
+fn index(_request: &HttpRequest, _state: &ServerState) -> HttpResponse {
+    HttpResponse::new(HTTP_OK)
+        .with_header("Content-Type", "text/html")
+        .with_body(b"<h1>Welcome to Rust HTTP Server!</h1>".to_vec())
+}
+
+// Marker attached by the `authenticate` middleware to requests carrying a
+// valid bearer token
+struct Authenticated;
+
+fn authenticate(request: &mut HttpRequest, next: Next) -> HttpResponse {
+    // Check for authentication header
+    let is_authenticated = request.headers.iter()
+        .any(|(key, value)| key == "Authorization" && value.strip_prefix("Bearer ") == Some(&next.config.auth_token));
+
+    if is_authenticated {
+        request.extensions.insert(Authenticated);
+    }
+    next.run(request)
+}
+
+fn echo(request: &HttpRequest, _state: &ServerState) -> HttpResponse {
+    if request.extensions.get::<Authenticated>().is_none() {
+        HttpResponse::new(HTTP_UNAUTHORIZED)
+            .with_header("Content-Type", "text/plain")
+            .with_body(b"Unauthorized".to_vec())
+    } else {
+        // Echo back the request body
+        HttpResponse::new(HTTP_OK)
+            .with_header("Content-Type", "application/json")
+            .with_body(request.body.clone())
+    }
+}
+
+fn health(request: &HttpRequest, _state: &ServerState) -> HttpResponse {
+    health_response(request, SHUTTING_DOWN.load(Ordering::SeqCst))
+}
+
+// Health check answer. While draining it's `503 Draining`, so a load
+// balancer polling over a kept-alive connection takes the server out of
+// rotation before it stops.
+fn health_response(request: &HttpRequest, draining: bool) -> HttpResponse {
+    if draining {
+        return HttpResponse::new(HTTP_SERVICE_UNAVAILABLE)
+            .with_reason("Draining")
+            .with_header("Content-Type", "text/plain")
+            .with_body(b"draining".to_vec());
+    }
+    match request.negotiate(&["application/json", "text/plain"]) {
+        Some("text/plain") => HttpResponse::new(HTTP_OK)
+            .with_header("Content-Type", "text/plain")
+            .with_body(b"healthy".to_vec()),
+        Some(_) => HttpResponse::new(HTTP_OK)
+            .with_header("Content-Type", "application/json")
+            .with_body(b"{\"status\": \"healthy\"}".to_vec()),
+        None => HttpResponse::new(HTTP_NOT_ACCEPTABLE)
+            .with_header("Content-Type", "text/plain")
+            .with_body(b"Not Acceptable".to_vec()),
+    }
+}
+
+fn status(_request: &HttpRequest, state: &ServerState) -> HttpResponse {
+    let started_at = state.started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let uptime = state.started.elapsed().as_secs_f64();
+    let body = format!("{{\"started_at\": {}, \"uptime_seconds\": {:.3}}}", started_at, uptime);
+
+    HttpResponse::new(HTTP_OK)
+        .with_header("Content-Type", "application/json")
+        .with_body(body.into_bytes())
+}
+
+// Keep-alive effectiveness: how many requests each connection carried
+fn metrics(_request: &HttpRequest, state: &ServerState) -> HttpResponse {
+    let connections = state.connections_accepted.load(Ordering::Relaxed);
+    let requests = state.requests_served.load(Ordering::Relaxed);
+    let per_connection = if connections == 0 { 0.0 } else { requests as f64 / connections as f64 };
+
+    let mut body = format!(
+        "# HELP kp_http_connections_accepted_total Connections accepted and handed to a worker.\n\
+         # TYPE kp_http_connections_accepted_total counter\n\
+         kp_http_connections_accepted_total {}\n\
+         # HELP kp_http_requests_served_total Requests dispatched.\n\
+         # TYPE kp_http_requests_served_total counter\n\
+         kp_http_requests_served_total {}\n\
+         # HELP kp_http_requests_per_connection Average number of requests per connection.\n\
+         # TYPE kp_http_requests_per_connection gauge\n\
+         kp_http_requests_per_connection {:.3}\n\
+         # HELP kp_http_connection_requests Requests served by each connection before it closed.\n\
+         # TYPE kp_http_connection_requests histogram\n",
+        connections, requests, per_connection,
+    );
+    state.connection_requests.render("kp_http_connection_requests", "", &mut body);
+    body.push_str(
+        "# HELP kp_http_request_phase_seconds Time spent reading the head and body, handling, and writing.\n\
+         # TYPE kp_http_request_phase_seconds histogram\n",
+    );
+    for (phase, histogram) in PHASES.iter().zip(&state.phase_seconds) {
+        histogram.render("kp_http_request_phase_seconds", &format!("phase=\"{}\"", phase), &mut body);
+    }
+
+    HttpResponse::new(HTTP_OK)
+        .with_header("Content-Type", "text/plain; version=0.0.4")
+        .with_body(body.into_bytes())
+}
+
+// The settings the server is running with as JSON, for authenticated
+// clients only. The auth token is never shown.
+fn admin_config(request: &HttpRequest, config: &Config) -> HttpResponse {
+    if request.extensions.get::<Authenticated>().is_none() {
+        return error_response(config, HTTP_UNAUTHORIZED);
+    }
+
+    let object = |members: Vec<(&str, JsonValue)>| {
+        JsonValue::Object(members.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    };
+    let number = |value: usize| JsonValue::Number(value as f64);
+    let optional = |value: Option<usize>| value.map_or(JsonValue::Null, number);
+    let secs = |duration: Duration| JsonValue::Number(duration.as_secs_f64());
+    let limits = &config.limits;
+    let body = object(vec![
+        ("bind_addr", JsonValue::String(config.bind_addr.clone())),
+        ("auth_token", JsonValue::String("[redacted]".to_string())),
+        ("worker_threads", number(config.worker_count())),
+        ("max_connections", number(config.max_connections)),
+        ("max_connections_per_ip", optional(config.max_connections_per_ip)),
+        ("timeouts", object(vec![
+            ("request", config.request_timeout.map_or(JsonValue::Null, secs)),
+            ("read", secs(config.read_timeout)),
+            ("idle", secs(config.idle_timeout)),
+            ("shutdown_grace", secs(config.shutdown_grace)),
+        ])),
+        ("limits", object(vec![
+            ("max_request_line", number(limits.max_request_line)),
+            ("max_method_len", number(limits.max_method_len)),
+            ("max_header_line", number(limits.max_header_line)),
+            ("max_headers", number(limits.max_headers)),
+            ("max_header_bytes", number(limits.max_header_bytes)),
+            ("max_body", number(limits.max_body)),
+            ("max_upload", number(limits.max_upload)),
+            ("max_uri_length", number(limits.max_uri_length)),
+            ("max_path_segments", number(config.max_path_segments)),
+            ("max_response_bytes", optional(config.max_response_bytes)),
+        ])),
+        ("rate_limit", config.rate_limit.map_or(JsonValue::Null, |limit| JsonValue::Number(limit.into()))),
+    ]);
+
+    HttpResponse::new(HTTP_OK)
+        .with_header("Content-Type", "application/json")
+        .with_body(body.to_string().into_bytes())
+}
+
+// Echo the received request head back as `message/http`, leaving out
+// credentials so they can't be read back by a script
+fn trace(request: &HttpRequest) -> HttpResponse {
+    let mut body = format!("{} {} {}\r\n", request.method, request.path, request.version);
+    for (key, value) in &request.headers {
+        if key.eq_ignore_ascii_case("Authorization") || key.eq_ignore_ascii_case("Cookie") {
+            continue;
+        }
+        body.push_str(&format!("{}: {}\r\n", key, value));
+    }
+
+    HttpResponse::new(HTTP_OK)
+        .with_header("Content-Type", "message/http")
+        .with_body(body.into_bytes())
+}
+
+// Access log line in the combined log format:
+// host - - [time] "request line" status bytes "referer" "user agent"
+fn access_log_line(peer: Option<SocketAddr>, request: &HttpRequest, response: &HttpResponse) -> String {
+    let host = peer.map(|peer| peer.ip().to_string()).unwrap_or_else(|| "-".to_string());
+    format!(
+        "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+        host,
+        clf_time(SystemTime::now()),
+        request.method,
+        request.path,
+        request.version,
+        response.status_code(),
+        response.body_len().map_or("-".to_string(), |len| if response.head_only { "0".to_string() } else { len.to_string() }),
+        request.referer().unwrap_or("-"),
+        request.user_agent().unwrap_or("-"),
+    )
+}
+
+// Timestamp as used in access logs, e.g. `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+    let date = CivilTime::from(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        date.day,
+        MONTHS[date.month - 1],
+        date.year,
+        date.secs_of_day / 3600,
+        date.secs_of_day / 60 % 60,
+        date.secs_of_day % 60,
+    )
+}
+
+// Timestamp as used in HTTP headers like `Last-Modified`, e.g.
+// `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+    let date = CivilTime::from(time);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        // The epoch was a Thursday
+        WEEKDAYS[((date.days + 4) % 7) as usize],
+        date.day,
+        MONTHS[date.month - 1],
+        date.year,
+        date.secs_of_day / 3600,
+        date.secs_of_day / 60 % 60,
+        date.secs_of_day % 60,
+    )
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// A UTC time split into calendar fields
+struct CivilTime {
+    year: i64,
+    // 1 to 12
+    month: usize,
+    day: i64,
+    secs_of_day: u64,
+    // Days since the epoch
+    days: u64,
+}
+
+impl From<SystemTime> for CivilTime {
+    fn from(time: SystemTime) -> Self {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+        // Civil date from days since the epoch (Howard Hinnant's algorithm)
+        let z = days as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let day_of_era = z.rem_euclid(146097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        CivilTime { year, month: month as usize, day, secs_of_day, days }
+    }
+}
+
+// Read from the stream until `buffer` holds a complete request head and
+// return the offset just past its terminating blank line. Bytes past the
+// head stay in `buffer`, so pipelined requests are not lost. Returns
+// `Ok(None)` if the client closed the connection before sending anything.
+// Up to `max_leading_empty_lines` empty lines before the request line are
+// skipped. A head larger than `max_header_bytes` fails with `InvalidData`.
+fn read_head(
+    stream: &mut impl Read,
+    buffer: &mut Vec<u8>,
+    limits: &ParserLimits,
+) -> io::Result<Option<usize>> {
+    let mut chunk = [0; 1024];
+    let mut skipped_lines = 0;
+
+    loop {
+        while skipped_lines < limits.max_leading_empty_lines {
+            if buffer.starts_with(b"\r\n") {
+                buffer.drain(..2);
+            } else if buffer.starts_with(b"\n") {
+                buffer.drain(..1);
+            } else {
+                break;
+            }
+            skipped_lines += 1;
+        }
+
+        if let Some((_, body_start)) = limits.find_head_end(buffer) {
+            return Ok(Some(body_start));
+        }
+        if buffer.len() > limits.max_header_bytes {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too large"));
+        }
+
+        let size = stream.read(&mut chunk)?;
+        if size == 0 {
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+            // The client closed its side right after the headers, take
+            // what it sent as the whole head
+            if limits.lenient_parsing {
+                return Ok(Some(buffer.len()));
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+    }
+}
+
+// Read from the stream until `buffer` holds at least `len` bytes
+fn read_body(stream: &mut impl Read, buffer: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    let mut chunk = [0; 1024];
+
+    while buffer.len() < len {
+        let size = stream.read(&mut chunk)?;
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+    }
+
+    Ok(())
+}
+
+// Read until the client closes its side, returning the end of the buffer.
+// A buffer growing past `max_end` is too large.
+fn read_body_until_close(stream: &mut impl Read, buffer: &mut Vec<u8>, max_end: usize) -> Result<usize, ChunkedError> {
+    let mut chunk = [0; 4096];
+
+    loop {
+        if buffer.len() > max_end {
+            return Err(ChunkedError::TooLarge);
+        }
+        let size = stream.read(&mut chunk)?;
+        if size == 0 {
+            return Ok(buffer.len());
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+    }
+}
+
+// Reader over a request body, taking bytes already buffered first and then
+// reading more from the stream into the buffer, so the body is read once yet
+// stays buffered for the handler. Ends at `end`, the end of the body.
+struct BodyReader<'a, R> {
+    stream: &'a mut R,
+    buffer: &'a mut Vec<u8>,
+    pos: usize,
+    end: usize,
+}
+
+impl<R: Read> Read for BodyReader<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.end {
+            return Ok(0);
+        }
+        if self.pos == self.buffer.len() {
+            let mut chunk = [0; 4096];
+            let size = self.stream.read(&mut chunk)?;
+            if size == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buffer.extend_from_slice(&chunk[..size]);
+        }
+        let available = &self.buffer[self.pos..self.end.min(self.buffer.len())];
+        let size = available.len().min(out.len());
+        out[..size].copy_from_slice(&available[..size]);
+        self.pos += size;
+        Ok(size)
+    }
+}
+
+// Body of a request to a streaming route, which the handler reads from the
+// connection itself: first what arrived with the head, then the socket, up
+// to the declared length and no further, so a pipelined request behind it
+// stays unread
+struct BodyStream {
+    buffered: Vec<u8>,
+    pos: usize,
+    stream: TcpStream,
+    // Bytes still to come from the socket
+    remaining: u64,
+}
+
+impl BodyStream {
+    // Read past whatever the handler left, returning whether the connection
+    // got to the end of the body and can read the next request
+    fn finish(&mut self) -> bool {
+        io::copy(self, &mut io::sink()).is_ok()
+    }
+}
+
+impl Read for BodyStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buffered.len() {
+            let size = (self.buffered.len() - self.pos).min(out.len());
+            out[..size].copy_from_slice(&self.buffered[self.pos..self.pos + size]);
+            self.pos += size;
+            return Ok(size);
+        }
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let len = out.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let size = self.stream.read(&mut out[..len])?;
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= size as u64;
+        Ok(size)
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyStream").field("remaining", &self.remaining).finish()
+    }
+}
+
+// Reader returned by `HttpRequest::body_reader`
+enum RequestBody<'a> {
+    Buffered(&'a [u8]),
+    Streamed(MutexGuard<'a, BodyStream>),
+}
+
+impl Read for RequestBody<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        match self {
+            RequestBody::Buffered(body) => body.read(out),
+            RequestBody::Streamed(stream) => stream.read(out),
+        }
+    }
+}
+
+// Parsed JSON request body, attached to requests as an extension
+struct JsonBody(JsonValue);
+
+// A document that doesn't parse is a bad request like a malformed chunk
+fn json_body_error(error: JsonError) -> ChunkedError {
+    match error {
+        JsonError::Io(e) => ChunkedError::Io(e),
+        JsonError::Syntax | JsonError::TooDeep | JsonError::TooLarge => ChunkedError::Malformed,
+    }
+}
+
+// Errors that just mean the client went away, not that the server failed
+fn is_disconnect(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+    )
+}
+
+// Errors meaning the client stopped sending partway through a request
+fn is_incomplete(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof
+    )
+}
+
+// Write a response, returning whether the connection is still usable
+fn send_response(stream: &mut impl Write, mut response: HttpResponse, config: &Config) -> bool {
+    let defaults: Vec<(String, String)> = config.default_headers.iter()
+        .filter(|(key, _)| !response.headers.iter().any(|(existing, _)| existing.eq_ignore_ascii_case(key)))
+        .cloned()
+        .collect();
+    response = response.with_headers(defaults);
+
+    let result = if response.stream.is_some() {
+        send_streamed(stream, response, config)
+    } else if config.coalesce_small_responses && response.body.len() <= config.coalesce_threshold {
+        stream.write_all(&response.build())
+    } else {
+        let (head, body) = response.into_parts();
+        stream.write_all(&head).and_then(|()| stream.write_all(&body))
+    };
+
+    match result {
+        Ok(()) => true,
+        Err(e) if is_disconnect(&e) => {
+            debug_log!("Client disconnected before response was sent: {}", e);
+            false
+        },
+        Err(e) => {
+            eprintln!("Failed to send response: {}", e);
+            false
+        }
+    }
+}
+
+// Write a response with a streamed body, cut off at `max_response_bytes`
+fn send_streamed(out: &mut impl Write, mut response: HttpResponse, config: &Config) -> io::Result<()> {
+    let chunked = response.is_chunked();
+    out.write_all(&response.head())?;
+    match response.stream.take() {
+        Some(body) => body.send(out, chunked, config.max_response_bytes),
+        None => Ok(()),
+    }
+}
+
+// Numbers recorded exchanges across all connections, so files never clash
+static RECORD_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+// Writer passing bytes through while keeping a copy of them
+struct Recorder<'a, W> {
+    inner: &'a mut W,
+    written: Vec<u8>,
+}
+
+impl<W: Write> Write for Recorder<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.written.extend_from_slice(&buf[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// `send_response`, saving the exchange when `record_dir` is set
+fn send_recorded(out: &mut impl Write, response: HttpResponse, config: &Config, raw_request: &[u8]) -> bool {
+    let Some(dir) = &config.record_dir else {
+        return send_response(out, response, config);
+    };
+    let mut recorder = Recorder { inner: out, written: Vec::new() };
+    let sent = send_response(&mut recorder, response, config);
+    if let Err(e) = record_exchange(dir, raw_request, &recorder.written) {
+        eprintln!("Failed to record exchange in {}: {}", dir.display(), e);
+    }
+    sent
+}
+
+// Save a request and the response bytes sent for it as `NNNNNN.request`
+// and `NNNNNN.response`
+fn record_exchange(dir: &Path, request: &[u8], response: &[u8]) -> io::Result<()> {
+    let sequence = RECORD_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(format!("{:06}.request", sequence)), request)?;
+    fs::write(dir.join(format!("{:06}.response", sequence)), response)
+}
+
+// Which request bodies get copied for auditing, and where to
+pub struct BodyAudit {
+    // Path prefixes audited, every path if empty
+    pub paths: Vec<String>,
+    // Media types audited, like `application/json`, any if empty
+    pub content_types: Vec<String>,
+    // Longest part of a body copied, the rest is left out
+    pub max_bytes: usize,
+    pub sink: AuditSink,
+}
+
+pub enum AuditSink {
+    // Append an entry per body to this file
+    File(PathBuf),
+    // Hand the request and its possibly truncated body to a function
+    Callback(AuditCallback),
+}
+
+pub type AuditCallback = Box<dyn Fn(&HttpRequest, &[u8]) + Send + Sync>;
+
+impl BodyAudit {
+    fn matches(&self, request: &HttpRequest) -> bool {
+        let media_type = request.header("Content-Type").and_then(|value| value.split(';').next()).unwrap_or("");
+        let path_matches =
+            self.paths.is_empty() || self.paths.iter().any(|prefix| request.path.starts_with(prefix.as_str()));
+        let type_matches = self.content_types.is_empty()
+            || self.content_types.iter().any(|allowed| allowed.eq_ignore_ascii_case(media_type.trim()));
+        path_matches && type_matches
+    }
+
+    // Copy the request's body to the sink if it matches. A failing sink is
+    // reported and doesn't affect the request.
+    fn record(&self, request: &HttpRequest) {
+        if !self.matches(request) {
+            return;
+        }
+        let body = &request.body[..request.body.len().min(self.max_bytes)];
+        match &self.sink {
+            AuditSink::File(path) => {
+                // One write per entry, so concurrent appends don't interleave
+                let mut entry = format!(
+                    "[{}] {} {} {}/{} bytes\n",
+                    clf_time(SystemTime::now()),
+                    request.method,
+                    request.path,
+                    body.len(),
+                    request.body.len(),
+                )
+                .into_bytes();
+                entry.extend_from_slice(body);
+                entry.push(b'\n');
+                let written = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(&entry));
+                if let Err(e) = written {
+                    eprintln!("Failed to write audit entry to {}: {}", path.display(), e);
+                }
+            },
+            AuditSink::Callback(callback) => callback(request, body),
+        }
+    }
+}
+
+// Bind the listening socket with the configured reuse options
+fn bind_listener(config: &Config) -> io::Result<TcpListener> {
+    let addr = config.bind_addr.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bind address resolved to nothing"))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(config.reuse_address)?;
+    if config.reuse_port {
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))))]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin")))))]
+        eprintln!("SO_REUSEPORT isn't supported on this platform, binding without it");
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+// Apply the configured TCP keepalive settings to an accepted socket
+fn set_tcp_keepalive(stream: &TcpStream, config: &Config) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+    if !config.tcp_keepalive {
+        return socket.set_keepalive(false);
+    }
+
+    let keepalive = TcpKeepalive::new().with_time(config.tcp_keepalive_idle);
+    // The probe interval can't be tuned on every platform
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    let keepalive = keepalive.with_interval(config.tcp_keepalive_interval);
+    socket.set_tcp_keepalive(&keepalive)
+}
+
+// Longest PROXY protocol v1 line allowed by the spec, CRLF included
+const PROXY_LINE_MAX: usize = 107;
+
+// Parse a PROXY protocol v1 line like `PROXY TCP4 192.0.2.1 198.51.100.1
+// 56324 443`, giving the client's address. `Some(None)` for `PROXY UNKNOWN`,
+// `None` if the line is malformed.
+fn parse_proxy_line(line: &str) -> Option<Option<SocketAddr>> {
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return None;
+    }
+
+    let family = fields.next()?;
+    if family == "UNKNOWN" {
+        return Some(None);
+    }
+    let (Some(source), Some(_destination), Some(source_port), Some(_destination_port), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return None;
+    };
+
+    let ip: IpAddr = source.parse().ok()?;
+    let port: u16 = source_port.parse().ok()?;
+    match (family, ip) {
+        ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Some(Some(SocketAddr::new(ip, port))),
+        _ => None,
+    }
+}
+
+// Phase of a client connection, advanced one at a time by `Connection::step`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConnState {
+    // Waiting for the PROXY protocol line, before anything else
+    ReadingProxyHeader,
+    // Waiting for a complete request head
+    ReadingHeaders,
+    // Head parsed, waiting for the body
+    ReadingBody,
+    // Request complete, running it through the router
+    Dispatching,
+    // Sending the response
+    Writing,
+    // Response sent, waiting for the next request on the same connection
+    Idle,
+    // Finished, the connection is to be dropped
+    Closing,
+}
+
+impl ConnState {
+    // Index of the request phase in `PHASES` this state times, if any
+    fn phase(self) -> Option<usize> {
+        match self {
+            ConnState::ReadingHeaders => Some(0),
+            ConnState::ReadingBody => Some(1),
+            ConnState::Dispatching => Some(2),
+            ConnState::Writing => Some(3),
+            _ => None,
+        }
+    }
+}
+
+// A client connection: its stream and read buffer, and the request and
+// response in progress. Requests are handled one at a time. Up to
+// `max_pipelined_requests` responses to pipelined requests are held in
+// `output` while more requests are read, then written blocking, so a client
+// pipelining faster than it reads responses is held back by its own
+// receive window.
+struct Connection<'a, S> {
+    stream: TcpStream,
+    config: &'a Config,
+    router: &'a Router<S>,
+    server: &'a ServerState,
+    // Client address, from the socket or the PROXY protocol line
+    peer: Option<SocketAddr>,
+    buffer: Vec<u8>,
+    state: ConnState,
+    // Offset of the body in `buffer`, once the head is parsed
+    head_end: usize,
+    request: Option<HttpRequest>,
+    response: Option<HttpResponse>,
+    // Whether to send `100 Continue` before reading the body
+    send_continue: bool,
+    // Raw bytes of the request in progress, kept only when recording
+    raw_request: Vec<u8>,
+    keep_alive: bool,
+    // Id in the server's connection registry, if tracked
+    id: Option<u64>,
+    // Time the request in progress has spent in each phase so far
+    phase_times: [Duration; 4],
+    // Requests dispatched on this connection
+    requests: u64,
+    // Bytes of the requests read on this connection, heads and bodies
+    bytes_received: usize,
+    // Responses to pipelined requests not yet written to the socket, and
+    // how many there are
+    output: Vec<u8>,
+    held: usize,
+}
+
+impl<S> Drop for Connection<'_, S> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.server.unregister_connection(id);
+        }
+    }
+}
+
+impl<'a, S: Send + Sync + 'static> Connection<'a, S> {
+    fn new(stream: TcpStream, config: &'a Config, router: &'a Router<S>, server: &'a ServerState) -> Self {
+        let state = if config.proxy_protocol {
+            ConnState::ReadingProxyHeader
+        } else {
+            ConnState::ReadingHeaders
+        };
+        let mut connection = Connection {
+            peer: stream.peer_addr().ok(),
+            id: server.register_connection(&stream),
+            phase_times: [Duration::ZERO; 4],
+            requests: 0,
+            bytes_received: 0,
+            stream,
+            config,
+            router,
+            server,
+            buffer: Vec::new(),
+            state,
+            head_end: 0,
+            request: None,
+            response: None,
+            send_continue: false,
+            raw_request: Vec::new(),
+            keep_alive: false,
+            output: Vec::new(),
+            held: 0,
+        };
+        // Behind a balancer the client is only known from the PROXY line
+        if !config.proxy_protocol {
+            connection.state = connection.screen_peer();
+        }
+        connection
+    }
+
+    // Turn the client away if the IP filter refuses it, before reading
+    // anything from it, or go on to read its first request
+    fn screen_peer(&mut self) -> ConnState {
+        match (&self.router.ip_filter, self.peer) {
+            (Some(filter), Some(peer)) if !filter.permits(peer.ip()) => {
+                debug_log!("Refusing {} by IP filter", peer.ip());
+                self.reject(error_response(self.config, HTTP_FORBIDDEN))
+            },
+            _ => ConnState::ReadingHeaders,
+        }
+    }
+
+    // Serve requests until the client or a request asks to close
+    fn run(mut self) {
+        loop {
+            match self.step() {
+                Ok(ConnState::Closing) => break,
+                Ok(_) => {},
+                Err(e) => {
+                    eprintln!("Connection failed while {:?}: {}", self.state, e);
+                    break;
+                }
+            }
+        }
+        self.server.connection_requests.observe(self.requests as f64);
+    }
+
+    // Run the current phase and move on to the next, returning it. Errors
+    // are I/O failures other than the client going away or stalling, which
+    // end the connection.
+    fn step(&mut self) -> io::Result<ConnState> {
+        let (phase, started) = (self.state, Instant::now());
+        self.state = match self.state {
+            ConnState::ReadingProxyHeader => self.read_proxy_header()?,
+            ConnState::ReadingHeaders => self.read_headers()?,
+            ConnState::ReadingBody => self.read_body()?,
+            ConnState::Dispatching => self.dispatch(),
+            ConnState::Writing => self.write(),
+            ConnState::Idle => self.wait_idle()?,
+            ConnState::Closing => ConnState::Closing,
+        };
+        if self.state == ConnState::Closing {
+            self.flush_output();
+        }
+
+        // Phases can take several steps, so times add up until the response
+        // is written. Waiting while idle isn't part of any request.
+        if let Some(index) = phase.phase() {
+            self.phase_times[index] += started.elapsed();
+        }
+        if phase == ConnState::Writing {
+            for (histogram, time) in self.server.phase_seconds.iter().zip(self.phase_times) {
+                histogram.observe(time.as_secs_f64());
+            }
+            self.phase_times = [Duration::ZERO; 4];
+        }
+        Ok(self.state)
+    }
+
+    // Wait on a kept-alive connection for the first bytes of the next request.
+    // Pipelined bytes already buffered start it straight away.
+    fn wait_idle(&mut self) -> io::Result<ConnState> {
+        self.request = None;
+        self.response = None;
+        self.raw_request.clear();
+        if !self.buffer.is_empty() && (self.held == 0 || self.config.limits.find_head_end(&self.buffer).is_some()) {
+            return Ok(ConnState::ReadingHeaders);
+        }
+
+        // With responses held, take whatever else the client has pipelined
+        // already, and write them out once a read would have to wait
+        let mut chunk = [0; 1024];
+        if self.held > 0 {
+            self.stream.set_nonblocking(true)?;
+            let read = self.stream.read(&mut chunk);
+            self.stream.set_nonblocking(false)?;
+            match read {
+                Ok(0) => return Ok(ConnState::Closing),
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) if is_disconnect(&e) => return Ok(ConnState::Closing),
+                Err(e) => return Err(e),
+            }
+            if self.config.limits.find_head_end(&self.buffer).is_some() {
+                return Ok(ConnState::ReadingHeaders);
+            }
+            if !self.flush_output() {
+                return Ok(ConnState::Closing);
+            }
+            if !self.buffer.is_empty() {
+                return Ok(ConnState::ReadingHeaders);
+            }
+        }
+
+        // Waiting for the first byte falls under the idle timeout, the rest
+        // of the request under the read timeout again. The idle sweeper may
+        // also shut the socket down meanwhile, which reads as the client
+        // closing.
+        if let Some(id) = self.id {
+            self.server.set_idle(id, true);
+        }
+        self.stream.set_read_timeout(Some(self.config.idle_timeout))?;
+        let read = self.stream.read(&mut chunk);
+        self.stream.set_read_timeout(Some(self.config.read_timeout))?;
+        if let Some(id) = self.id {
+            self.server.set_idle(id, false);
+        }
+        match read {
+            // The client closed the connection between requests
+            Ok(0) => Ok(ConnState::Closing),
+            Ok(size) => {
+                self.buffer.extend_from_slice(&chunk[..size]);
+                Ok(ConnState::ReadingHeaders)
+            },
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                debug_log!("Closing idle connection: {}", e);
+                Ok(ConnState::Closing)
+            },
+            Err(e) if is_disconnect(&e) => {
+                debug_log!("Client disconnected while idle: {}", e);
+                Ok(ConnState::Closing)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    // Answer with an error and close, for requests we can't go on reading
+    fn reject(&mut self, response: HttpResponse) -> ConnState {
+        self.response = Some(response.with_header("Connection", "close"));
+        self.keep_alive = false;
+        ConnState::Writing
+    }
+
+    fn read_proxy_header(&mut self) -> io::Result<ConnState> {
+        let mut chunk = [0; 128];
+        let line_end = loop {
+            if let Some(pos) = find_subsequence(&self.buffer, b"\r\n") {
+                break pos;
+            }
+            if self.buffer.len() >= PROXY_LINE_MAX {
+                debug_log!("Closing connection, PROXY line too long");
+                return Ok(ConnState::Closing);
+            }
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(ConnState::Closing),
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+                Err(e) if is_incomplete(&e) || is_disconnect(&e) => {
+                    debug_log!("No PROXY line received: {}", e);
+                    return Ok(ConnState::Closing);
+                },
+                Err(e) => return Err(e),
+            }
+        };
+
+        let line = String::from_utf8_lossy(&self.buffer[..line_end]).into_owned();
+        match parse_proxy_line(&line) {
+            Some(Some(peer)) => self.peer = Some(peer),
+            // The balancer doesn't know the client, keep the socket's peer
+            Some(None) => {},
+            None => {
+                debug_log!("Closing connection, malformed PROXY line {:?}", line);
+                return Ok(ConnState::Closing);
+            }
+        }
+        self.buffer.drain(..line_end + 2);
+        Ok(self.screen_peer())
+    }
+
+    fn read_headers(&mut self) -> io::Result<ConnState> {
+        let config = self.config;
+        let head_end = match read_head(&mut self.stream, &mut self.buffer, &config.limits) {
+            Ok(Some(head_end)) => head_end,
+            Ok(None) => return Ok(ConnState::Closing),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return Ok(self.reject(error_response(config, ParseError::HeadersTooLarge.status_line())));
+            },
+            Err(e) if self.buffer.is_empty() && matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                debug_log!("Closing idle connection: {}", e);
+                return Ok(ConnState::Closing);
+            },
+            // The head never got its terminating blank line
+            Err(e) if is_incomplete(&e) => {
+                debug_log!("Request head incomplete: {}", e);
+                return Ok(self.reject(error_response(config, HTTP_REQUEST_TIMEOUT)));
+            },
+            Err(e) if is_disconnect(&e) => {
+                debug_log!("Client disconnected: {}", e);
+                return Ok(ConnState::Closing);
+            },
+            Err(e) => return Err(e),
+        };
+        self.head_end = head_end;
+        if config.record_dir.is_some() {
+            self.raw_request.extend_from_slice(&self.buffer[..head_end]);
+        }
+
+        let mut request = match HttpRequest::parse(&self.buffer[..head_end], &config.limits) {
+            Ok(request) => request,
+            Err(e) => {
+                debug_log!("Rejecting malformed request: {:?}", e);
+                return Ok(self.reject(error_response(config, e.status_line())));
+            }
+        };
+        request.remote_addr = self.peer;
+        // An interim response would overtake the held responses, so early
+        // hints are skipped while there are any
+        request.interim = self.stream.try_clone().ok()
+            .filter(|_| self.held == 0)
+            .map(|stream| InterimWriter(Mutex::new(Box::new(stream))));
+        self.keep_alive = request.keep_alive();
+
+        // No upgrade protocols are implemented, so an upgrade request (to
+        // h2c, say) is served as the plain HTTP/1.1 request it also is
+        if request.connection_has("upgrade") {
+            if let Some(protocol) = request.header("Upgrade") {
+                debug_log!("Ignoring unsupported upgrade to {}", protocol);
+            }
+        }
+
+        // Framing by both `Transfer-Encoding` and `Content-Length` is how
+        // requests get smuggled, so the body can't be trusted either way
+        if let Some(transfer_encoding) = request.header("Transfer-Encoding") {
+            let final_coding = transfer_encoding.rsplit(',').next().unwrap_or("").trim();
+            let response = if request.header("Content-Length").is_some() || !final_coding.eq_ignore_ascii_case("chunked") {
+                Some(error_response(config, HTTP_BAD_REQUEST))
+            } else if !transfer_encoding.trim().eq_ignore_ascii_case("chunked") {
+                Some(error_response(config, HTTP_NOT_IMPLEMENTED))
+            } else {
+                None
+            };
+            if let Some(response) = response {
+                self.request = Some(request);
+                return Ok(self.reject(response));
+            }
+        }
+
+        // Check the body against the size policy before asking the client
+        // for it. The body is never read, so the connection has to close.
+        // Streamed bodies never sit in memory whole, and have their own limit.
+        let expect = request.header("Expect");
+        let max_body = if request.body_streamable() && self.router.streams_body(&request) {
+            config.limits.max_upload
+        } else {
+            config.limits.max_body
+        };
+        if request.content_length() > max_body {
+            let response = match expect {
+                Some(_) => error_response(config, HTTP_EXPECTATION_FAILED),
+                None => error_response(config, HTTP_PAYLOAD_TOO_LARGE),
+            };
+            self.request = Some(request);
+            return Ok(self.reject(response));
+        }
+
+        let expects_continue = match expect {
+            Some(value) if value.eq_ignore_ascii_case("100-continue") => true,
+            Some(_) => {
+                self.request = Some(request);
+                return Ok(self.reject(error_response(config, HTTP_EXPECTATION_FAILED)));
+            },
+            None => false,
+        };
+
+        // A client expecting 100-continue holds its body back until asked,
+        // so only ask when the route reads it. Otherwise the body may still
+        // turn up later, and the connection can't be reused.
+        let next = if expects_continue && !self.router.reads_body(&request) {
+            self.keep_alive = false;
+            self.buffer.clear();
+            ConnState::Dispatching
+        } else {
+            self.send_continue = expects_continue;
+            ConnState::ReadingBody
+        };
+        self.request = Some(request);
+        Ok(next)
+    }
+
+    fn read_body(&mut self) -> io::Result<ConnState> {
+        let config = self.config;
+
+        if self.send_continue {
+            self.send_continue = false;
+            if !self.flush_output() {
+                return Ok(ConnState::Closing);
+            }
+            if let Err(e) = self.stream.write_all(HTTP_CONTINUE.as_bytes()) {
+                if is_disconnect(&e) {
+                    debug_log!("Client disconnected before 100 Continue was sent: {}", e);
+                    return Ok(ConnState::Closing);
+                }
+                return Err(e);
+            }
+        }
+
+        let Some(request) = self.request.as_mut() else {
+            return Ok(ConnState::ReadingHeaders);
+        };
+
+        if request.body_streamable() && self.router.streams_body(request) {
+            let body_end = self.head_end + request.content_length();
+            let buffered_end = body_end.min(self.buffer.len());
+            request.body.clear();
+            request.body_stream = Some(Mutex::new(BodyStream {
+                buffered: self.buffer[self.head_end..buffered_end].to_vec(),
+                pos: 0,
+                stream: self.stream.try_clone()?,
+                remaining: (body_end - buffered_end) as u64,
+            }));
+            self.bytes_received += body_end;
+            self.buffer.drain(..buffered_end);
+            return Ok(ConnState::Dispatching);
+        }
+
+        let json_limits = config.json_limits.as_ref().filter(|_| request.is_json());
+        let read = if request.header("Transfer-Encoding").is_some() {
+            read_chunked_body(&mut self.stream, &mut self.buffer, self.head_end, &config.limits).and_then(|chunked| {
+                request.body = chunked.body;
+                request.trailers = chunked.trailers;
+                // Chunked bodies are decoded in full first, JSON is parsed after
+                if let Some(limits) = json_limits.filter(|_| !request.body.is_empty()) {
+                    let value = parse_json(request.body.as_slice(), limits).map_err(json_body_error)?;
+                    request.extensions.insert(JsonBody(value));
+                }
+                Ok(chunked.end)
+            })
+        } else if request.body_until_close() {
+            // Nothing can follow a body delimited by the close, so bytes
+            // already buffered and whatever arrives until EOF are all of it
+            read_body_until_close(&mut self.stream, &mut self.buffer, self.head_end + config.limits.max_body)
+                .and_then(|body_end| {
+                    request.body = self.buffer[self.head_end..body_end].to_vec();
+                    if let Some(limits) = json_limits.filter(|_| !request.body.is_empty()) {
+                        let value = parse_json(request.body.as_slice(), limits).map_err(json_body_error)?;
+                        request.extensions.insert(JsonBody(value));
+                    }
+                    Ok(body_end)
+                })
+        } else if let Some(limits) = json_limits.filter(|_| request.content_length() > 0) {
+            // Parse from the stream as the body arrives, so a document over
+            // the limits is refused without waiting for the rest of it
+            let body_end = self.head_end + request.content_length();
+            let reader = BodyReader { stream: &mut self.stream, buffer: &mut self.buffer, pos: self.head_end, end: body_end };
+            parse_json(reader, limits).map_err(json_body_error).map(|value| {
+                request.body = self.buffer[self.head_end..body_end].to_vec();
+                request.extensions.insert(JsonBody(value));
+                body_end
+            })
+        } else {
+            let body_end = self.head_end + request.content_length();
+            read_body(&mut self.stream, &mut self.buffer, body_end)
+                .map(|()| {
+                    request.body = self.buffer[self.head_end..body_end].to_vec();
+                    body_end
+                })
+                .map_err(ChunkedError::Io)
+        };
+
+        let body_end = match read {
+            Ok(body_end) => body_end,
+            Err(ChunkedError::Io(e)) if is_incomplete(&e) => {
+                debug_log!("Request body incomplete: {}", e);
+                return Ok(self.reject(error_response(config, HTTP_REQUEST_TIMEOUT)));
+            },
+            Err(ChunkedError::Io(e)) if is_disconnect(&e) => {
+                debug_log!("Client disconnected while sending body: {}", e);
+                return Ok(ConnState::Closing);
+            },
+            Err(ChunkedError::Io(e)) => return Err(e),
+            Err(ChunkedError::Malformed) => return Ok(self.reject(error_response(config, HTTP_BAD_REQUEST))),
+            Err(ChunkedError::TooLarge) => return Ok(self.reject(error_response(config, HTTP_PAYLOAD_TOO_LARGE))),
+        };
+        if request.body_until_close() {
+            self.keep_alive = false;
+        }
+        if config.record_dir.is_some() {
+            self.raw_request.extend_from_slice(&self.buffer[self.head_end..body_end]);
+        }
+        if let Some(audit) = &config.audit_bodies {
+            audit.record(request);
+        }
+        self.bytes_received += body_end;
+        self.buffer.drain(..body_end);
+        Ok(ConnState::Dispatching)
+    }
+
+    // Run the request through the checks and its handler, and finish the
+    // response for sending
+    fn dispatch(&mut self) -> ConnState {
+        let (config, router) = (self.config, self.router);
+        let Some(request) = self.request.as_mut() else {
+            return ConnState::Closing;
+        };
+
+        self.server.requests_served.fetch_add(1, Ordering::Relaxed);
+        self.requests += 1;
+
+        // Reject overly nested paths before doing any routing work
+        let handling_started = Instant::now();
+        let mut response = if !request.reconcile_host(config.reject_host_mismatch) {
+            error_response(config, HTTP_BAD_REQUEST)
+        } else if let Err(status_line) = request.decode_body(config.limits.max_body) {
+            error_response(config, status_line)
+        } else if request.path_segment_count() > config.max_path_segments {
+            error_response(config, HTTP_BAD_REQUEST)
+        } else if request.method == "TRACE" {
+            if config.trace_enabled {
+                trace(request)
+            } else {
+                router.refuse_method(&request.path, config)
+            }
+        } else {
+            // A panicking handler only takes down its own request
+            match panic::catch_unwind(AssertUnwindSafe(|| router.handle(request, config))) {
+                Ok(response) => response,
+                Err(payload) => {
+                    self.keep_alive = false;
+                    panic_response(config, payload)
+                }
+            }
+        };
+
+        // Whatever a streaming handler left of its body is read past, so the
+        // next request is read from where it starts
+        if let Some(body) = request.body_stream.take() {
+            let mut body = body.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if !body.finish() {
+                self.keep_alive = false;
+            }
+        }
+
+        response = apply_sendfile(config, response);
+        response = intercept_response(config, response);
+        response = compress_response(config, request, response);
+        let negotiated_on = request.negotiated_on.lock()
+            .map(|headers| headers.clone())
+            .unwrap_or_default();
+        response = add_vary(response, &negotiated_on);
+        // A `HEAD` response keeps the headers of the body it would have had,
+        // compression included, but never sends it
+        if request.method == "HEAD" && !response.head_only {
+            response.set_default_charset();
+            let len = response.body_len();
+            response = response.headers_only(len);
+        }
+        // HTTP/1.0 clients can't take a chunked body, so one of unknown
+        // length is ended by closing the connection
+        if request.version == "HTTP/1.0" && response.is_chunked() {
+            response = response.close_delimited();
+        }
+
+        let handling_time = handling_started.elapsed();
+        debug_log!("{} {} handled in {:?}", request.method, request.path, handling_time);
+        if config.server_timing {
+            let millis = handling_time.as_secs_f64() * 1000.0;
+            response = response.with_header("Server-Timing", &format!("app;dur={:.3}", millis));
+        }
+
+        // Finish the request in hand when shutting down, but no more
+        if SHUTTING_DOWN.load(Ordering::SeqCst) || request.close_requested.load(Ordering::Relaxed) || response.close_delimited {
+            self.keep_alive = false;
+        }
+        if let Some(max) = config.max_connection_bytes {
+            if self.bytes_received > max {
+                debug_log!("Closing connection after {} request bytes, over the {} byte budget", self.bytes_received, max);
+                self.keep_alive = false;
+            }
+        }
+
+        if !self.keep_alive {
+            response = response.with_connection_option("close");
+        } else if request.version == "HTTP/1.0" {
+            response = response.with_connection_option("keep-alive");
+        }
+
+        self.response = Some(response);
+        ConnState::Writing
+    }
+
+    fn write(&mut self) -> ConnState {
+        let Some(response) = self.response.take() else {
+            return ConnState::Closing;
+        };
+
+        // Rather than send a runaway body, or a cut-off one the client would
+        // take for complete, drop the connection
+        if let Some(max) = self.config.max_response_bytes {
+            let len = response.body_len().filter(|_| !response.head_only).unwrap_or(0);
+            if len > max as u64 {
+                let path = self.request.as_ref().map_or("-", |request| request.path.as_str());
+                eprintln!("Aborting response to {}: {} byte body exceeds the {} byte limit", path, len, max);
+                return ConnState::Closing;
+            }
+        }
+
+        if let (true, Some(request)) = (self.config.access_log, &self.request) {
+            println!("{}", access_log_line(self.peer, request, &response));
+        }
+
+        // Hold the response while the cap allows, otherwise write it after
+        // any held before it
+        let sent = if self.keep_alive && response.stream.is_none() && self.held + 1 < self.config.max_pipelined_requests {
+            self.held += 1;
+            send_recorded(&mut self.output, response, self.config, &self.raw_request)
+        } else {
+            self.flush_output() && send_recorded(&mut self.stream, response, self.config, &self.raw_request)
+        };
+
+        // A failed write leaves the socket unusable, so end the connection
+        if sent && self.keep_alive {
+            ConnState::Idle
+        } else {
+            ConnState::Closing
+        }
+    }
+
+    // Write the held responses, returning false if the client is gone
+    fn flush_output(&mut self) -> bool {
+        if self.output.is_empty() {
+            return true;
+        }
+        self.held = 0;
+        let result = self.stream.write_all(&self.output);
+        self.output.clear();
+        match result {
+            Ok(()) => true,
+            Err(e) if is_disconnect(&e) => {
+                debug_log!("Client disconnected before pipelined responses were sent: {}", e);
+                false
+            },
+            Err(e) => {
+                eprintln!("Failed to send pipelined responses: {}", e);
+                false
+            }
+        }
+    }
+}
+
+// The built-in routes and middleware, as configured
+fn server_router(config: &Arc<Config>, state: Arc<ServerState>) -> io::Result<Router<ServerState>> {
+    let mut router = Router::with_state(state);
+    router.auto_options(config.auto_options).auto_head(config.auto_head);
+    router.add_all([
+        ("GET", "/", Box::new(index) as Box<dyn Handler<ServerState>>),
+        ("POST", "/echo", Box::new(echo)),
+        ("GET", "/health", Box::new(health)),
+    ]);
+    if let Some(robots_txt) = &config.robots_txt {
+        let policy = robots_txt.clone().into_bytes();
+        router.add_without_body("GET", "/robots.txt", EmbeddedAsset::new(Arc::clone(config), policy, "text/plain"));
+    }
+    for (prefix, name, value) in &config.required_headers {
+        router.require_header(prefix, name, value);
+    }
+    if !config.ip_allowlist.is_empty() || !config.ip_denylist.is_empty() {
+        router.filter_ips(IpFilter::new(&config.ip_allowlist, &config.ip_denylist)?);
+    }
+    if let Some(limit) = config.rate_limit {
+        router.wrap(RateLimit::new(limit, config.rate_limit_window));
+    }
+    if let Some(budget) = config.request_timeout {
+        router.wrap(Timeout { budget });
+    }
+    if !config.tls_only_prefixes.is_empty() {
+        router.wrap(require_tls);
+    }
+    router.wrap(authenticate);
+    if !config.early_hints.is_empty() {
+        router.wrap(send_early_hints);
+    }
+    if let Some(ttl) = config.session_ttl {
+        router.wrap(SessionStore::new(ttl, config.max_sessions)?);
+    }
+    if let Some(dir) = &config.upload_dir {
+        router.add_streaming("POST", "/upload", Uploads::new(Arc::clone(config), dir));
+    }
+    if let Some(static_files) = &config.static_files {
+        router.mount(&static_files.mount_path, StaticFiles::new(Arc::clone(config)));
+    }
+    if config.status_endpoint {
+        router.add_without_body("GET", "/status", status);
+    }
+    if config.metrics_endpoint {
+        router.add_without_body("GET", "/metrics", metrics);
+    }
+    // Anyone can send the default token, so it never unlocks the config
+    if config.config_endpoint && config.auth_token == DEFAULT_AUTH_TOKEN {
+        eprintln!("Not serving /admin/config while auth_token is the default");
+    } else if config.config_endpoint {
+        let config = Arc::clone(config);
+        router.add_without_body("GET", "/admin/config", move |request: &HttpRequest, _state: &ServerState| {
+            admin_config(request, &config)
+        });
+    }
+    Ok(router)
+}
+
+// Serve with the configuration from the environment until shut down
+pub fn run() -> io::Result<()> {
+    DEBUG_LOGGING.store(std::env::var_os("KP_HTTP_DEBUG").is_some(), Ordering::Relaxed);
+
+    // `KP_HTTP_TLS_CERTS` lists certificates by hostname, as described at
+    // `SniCertificates::parse`
+    let sni_certificates = match std::env::var("KP_HTTP_TLS_CERTS") {
+        Ok(spec) => Some(SniCertificates::parse(&spec).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?),
+        Err(_) => None,
+    };
+    serve(Config { sni_certificates, ..Config::from_env() }, |_router, _config| {})
+}
+
+// Serve with `config` until shut down. `add_routes` adds the application's
+// routes next to the built-in ones, and is handed the configuration for
+// handlers like `Typed` that answer errors in its format.
+pub fn serve(config: Config, add_routes: impl FnOnce(&mut Router<ServerState>, &Arc<Config>)) -> io::Result<()> {
+    // Keep the default panic output, and remember the backtrace for
+    // `panic_response`
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let backtrace = Backtrace::force_capture().to_string();
+        PANIC_BACKTRACE.with(|slot| *slot.borrow_mut() = Some(backtrace));
+        default_hook(info);
+    }));
+
+    let config = Arc::new(config);
+    if let Some(certificates) = &config.sni_certificates {
+        certificates.check_readable()?;
+    }
+    let listener = bind_listener(&config)?;
+    println!("Server listening on http://{}", config.bind_addr);
+
+    let state = Arc::new(ServerState::new());
+    install_shutdown_handler()?;
+    spawn_shutdown_watcher(Arc::clone(&state), config.shutdown_grace)?;
+    if let Some(interval) = config.idle_sweep_interval {
+        spawn_idle_sweeper(Arc::clone(&state), interval, config.idle_timeout)?;
+    }
+
+    let mut router = server_router(&config, Arc::clone(&state))?;
+    add_routes(&mut router, &config);
+    let router = Arc::new(router);
+    if config.worker_threads == Some(0) {
+        eprintln!("Ignoring worker_threads = 0, sizing the pool from the CPU count");
+    }
+    let pool = ThreadPool::new(config.worker_count(), &config.worker_thread_name)?;
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                // Set timeouts for the connection
+                stream.set_read_timeout(Some(config.read_timeout))?;
+                stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+                if let Err(e) = set_tcp_keepalive(&stream, &config) {
+                    eprintln!("Failed to set TCP keepalive: {}", e);
+                }
+                
+                if turn_away(&mut stream, &config, &state, SHUTTING_DOWN.load(Ordering::SeqCst)) {
+                    continue;
+                }
+                let ip_guard = match (config.max_connections_per_ip, stream.peer_addr()) {
+                    (Some(max), Ok(peer)) => match IpConnectionGuard::acquire(&state, peer.ip(), max) {
+                        Some(guard) => Some(guard),
+                        None => {
+                            debug_log!("Turning away {}, it has {} connections open", peer.ip(), max);
+                            send_response(&mut stream, service_unavailable(&config), &config);
+                            continue;
+                        }
+                    },
+                    _ => None,
+                };
+
+                // Handle each connection on the next free worker
+                state.connections_accepted.fetch_add(1, Ordering::Relaxed);
+                let guard = ConnectionGuard::new(&state);
+                let config = Arc::clone(&config);
+                let router = Arc::clone(&router);
+                let state = Arc::clone(&state);
+                pool.execute(move || {
+                    Connection::new(stream, &config, &router, &state).run();
+                    drop(ip_guard);
+                    drop(guard);
+                });
+            }
+            Err(e) => {
+                eprintln!("Failed to establish connection: {}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::AtomicU64;
+    use std::thread;
+
+    fn parse(raw: &str) -> Result<HttpRequest, ParseError> {
+        HttpRequest::parse(raw.as_bytes(), &ParserLimits::default())
+    }
+
+    // Defaults without the access log, static files or anything else that
+    // reaches outside the test
+    pub fn test_config() -> Config {
+        Config { access_log: false, static_files: None, robots_txt: None, ..Config::default() }
+    }
+
+    fn test_router() -> Router<ServerState> {
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.add_all([
+            ("GET", "/", Box::new(index) as Box<dyn Handler<ServerState>>),
+            ("POST", "/echo", Box::new(echo_body)),
+        ]);
+        router
+    }
+
+    // `echo` without the authentication
+    fn echo_body(request: &HttpRequest, _state: &ServerState) -> HttpResponse {
+        HttpResponse::new(HTTP_OK).with_body(request.body.clone())
+    }
+
+    // An empty directory of the system's temporary directory for one test
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kp-http-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Connected client and server ends of a loopback TCP connection. Reads
+    // time out on the server end as they would on an accepted one.
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_read_timeout(Some(Config::default().read_timeout)).unwrap();
+        (client, server)
+    }
+
+    // Send `request` to a connection served with `config` and `router`,
+    // then close the client's sending side and return everything the
+    // server wrote before closing
+    pub fn exchange(config: &Config, router: &Router<ServerState>, request: &[u8]) -> String {
+        let (mut client, server) = socket_pair();
+        thread::scope(|scope| {
+            scope.spawn(|| Connection::new(server, config, router, &router.state).run());
+            client.write_all(request).unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        })
+    }
+
+    #[test]
+    fn header_names_must_be_tokens() {
+        for line in ["Content-Length : 5", " Host: x", "Bad Name: x", ": empty", "X-\u{1}: x"] {
+            let raw = format!("POST / HTTP/1.1\r\nHost: x\r\n{}\r\n\r\nhello", line);
+            assert!(parse(&raw).is_err(), "accepted {:?}", line);
+        }
+        assert_eq!(parse("GET / HTTP/1.1\r\nContent-Length : 5\r\n\r\n").unwrap_err(), ParseError::BadHeader);
+        assert!(parse("GET / HTTP/1.1\r\nX-Custom_Name.1: x\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn deeply_nested_paths_are_refused() {
+        let config = Config { max_path_segments: 3, ..test_config() };
+        let get = |path: &str| exchange(&config, &test_router(), format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes());
+        assert!(get("/a/b/c/d").starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        // Empty segments and the query don't count
+        assert!(get("/a//b/c/?x=/y/z").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert_eq!(parse("GET //a/b/ HTTP/1.1\r\n\r\n").unwrap().path_segment_count(), 2);
+    }
+
+    #[test]
+    fn route_tables_register_every_entry() {
+        let reply = |text: &'static str| move |_request: &HttpRequest, _state: &ServerState| {
+            HttpResponse::new(HTTP_OK).with_body(text.as_bytes().to_vec())
+        };
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.add_all([
+            ("GET", "/a", Box::new(reply("get a")) as Box<dyn Handler<ServerState>>),
+            ("POST", "/a", Box::new(reply("post a"))),
+            ("GET", "/b", Box::new(reply("get b"))),
+        ]);
+        for (request, body) in [("GET /a", "get a"), ("POST /a", "post a"), ("GET /b", "get b")] {
+            let response = exchange(&test_config(), &router, format!("{} HTTP/1.1\r\n\r\n", request).as_bytes());
+            assert!(response.ends_with(&format!("\r\n\r\n{}", body)), "{}", response);
+        }
+        let response = exchange(&test_config(), &router, b"POST /b HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn content_length_always_matches_the_body() {
+        let response = HttpResponse::new(HTTP_OK)
+            .with_header("content-length", "100")
+            .with_body(b"four".to_vec())
+            .build();
+        assert_eq!(String::from_utf8(response).unwrap(), "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nfour");
+        // Statuses without a body get none
+        let response = HttpResponse::new(HTTP_NO_CONTENT).with_header("Content-Length", "4").build();
+        assert_eq!(String::from_utf8(response).unwrap(), "HTTP/1.1 204 No Content\r\n\r\n");
+    }
+
+    #[test]
+    fn parsing_never_panics_on_arbitrary_input() {
+        // Fragments of requests, spliced and mutated at random, reach the
+        // parser's edge cases far more often than uniformly random bytes
+        const FRAGMENTS: &[&[u8]] = &[
+            b"GET ", b"POST ", b"/", b"/a/../b", b"?q=%", b"%zz", b"%00", b"http://h", b" HTTP/1.1", b"HTTP/1.0",
+            b"\r\n", b"\n", b"\r", b"\r\n\r\n", b"\n\n", b": ", b":", b"Host", b"Content-Length", b"-1", b"18446744073709551616",
+            b"Transfer-Encoding", b"chunked", b"Cookie", b"a=\"b", b"; ", b",", b" ", b"\t", b"\0", b"\xff\xfe", b"\xc3",
+        ];
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = move |below: usize| {
+            // xorshift64, for the same inputs on every run
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % below as u64) as usize
+        };
+        let limits = [
+            ParserLimits::default(),
+            ParserLimits { lenient_parsing: true, strict_request_line: true, ..ParserLimits::default() },
+            ParserLimits { max_request_line: 8, max_header_line: 4, max_headers: 1, max_header_bytes: 16,
+                max_uri_length: 2, max_method_len: 1, max_leading_empty_lines: 0, ..ParserLimits::default() },
+        ];
+
+        let mut accepted = 0;
+        for _ in 0..20_000 {
+            // Half start out as a valid request, so they get past the
+            // request line to the headers and body
+            let mut input = match random(2) {
+                0 => b"GET /a?b=c HTTP/1.1\r\nHost: h\r\nContent-Length: 2\r\n\r\nhi".to_vec(),
+                _ => Vec::new(),
+            };
+            for _ in 0..random(12) {
+                let at = random(input.len() + 1);
+                input.splice(at..at, FRAGMENTS[random(FRAGMENTS.len())].iter().copied());
+            }
+            for _ in 0..random(4) {
+                if !input.is_empty() {
+                    let at = random(input.len());
+                    input[at] = random(256) as u8;
+                }
+            }
+            for limits in &limits {
+                if let Ok(request) = HttpRequest::parse(&input, limits) {
+                    assert!(request.method.len() <= limits.max_method_len, "{:?}", String::from_utf8_lossy(&input));
+                    accepted += 1;
+                }
+            }
+        }
+        // Some inputs have to get through, or only the first checks ran
+        assert!(accepted > 500, "only {} inputs parsed", accepted);
+    }
+
+    #[test]
+    fn a_failed_write_ends_the_connection() {
+        let mut router = test_router();
+        router.add("GET", "/large", |_request: &HttpRequest, _state: &ServerState| {
+            HttpResponse::new(HTTP_OK).with_body(vec![b'x'; 16 * 1024 * 1024])
+        });
+        let (config, (mut client, server)) = (test_config(), socket_pair());
+        let mut connection = Connection::new(server, &config, &router, &router.state);
+        client.write_all(&b"GET /large HTTP/1.1\r\n\r\n".repeat(2)).unwrap();
+        step_until(&mut connection, ConnState::Writing);
+        drop(client);
+
+        // The pipelined second request is never served
+        assert_eq!(transitions(&mut connection).last(), Some(&ConnState::Closing));
+        assert_eq!(connection.requests, 1);
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused_before_continuing() {
+        let config = Config { limits: ParserLimits { max_body: 4, ..ParserLimits::default() }, ..test_config() };
+        let post = |headers: &str| {
+            exchange(&config, &test_router(), format!("POST /echo HTTP/1.1\r\n{}\r\n", headers).as_bytes())
+        };
+        let response = post("Expect: 100-continue\r\nContent-Length: 5\r\n");
+        assert!(response.starts_with("HTTP/1.1 417 Expectation Failed\r\n"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n") && !response.contains("100 Continue"), "{}", response);
+        let response = post("Content-Length: 5\r\n");
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", response);
+        // Expectations other than 100-continue can't be met
+        let response = post("Expect: something-else\r\nContent-Length: 2\r\n");
+        assert!(response.starts_with("HTTP/1.1 417 Expectation Failed\r\n"), "{}", response);
+
+        let response = exchange(&config, &test_router(), b"POST /echo HTTP/1.1\r\nExpect: 100-Continue\r\nContent-Length: 4\r\n\r\nfour");
+        assert!(response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn accepted_sockets_get_the_configured_keepalive() {
+        let (_client, server) = socket_pair();
+        let config = Config { tcp_keepalive_idle: Duration::from_secs(90), tcp_keepalive_interval: Duration::from_secs(7), ..test_config() };
+        set_tcp_keepalive(&server, &config).unwrap();
+        let socket = SockRef::from(&server);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!((socket.tcp_keepalive_time().unwrap(), socket.tcp_keepalive_interval().unwrap()),
+            (Duration::from_secs(90), Duration::from_secs(7)));
+
+        set_tcp_keepalive(&server, &Config { tcp_keepalive: false, ..test_config() }).unwrap();
+        assert!(!socket.keepalive().unwrap());
+    }
+
+    #[test]
+    fn parser_limits_bound_the_head() {
+        let limits = ParserLimits { max_request_line: 32, max_header_line: 16, max_headers: 2, ..ParserLimits::default() };
+        let parse_with = |raw: &str| HttpRequest::parse(raw.as_bytes(), &limits).map(|_| ());
+        assert_eq!(parse_with("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n"), Ok(()));
+        assert_eq!(parse_with(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(32))), Err(ParseError::UriTooLong));
+        assert_eq!(parse_with("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"), Err(ParseError::HeadersTooLarge));
+        assert_eq!(parse_with(&format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "a".repeat(14))), Err(ParseError::HeadersTooLarge));
+
+        // So is a head larger than `max_header_bytes`, with 431
+        let config = Config { limits: ParserLimits { max_header_bytes: 256, ..ParserLimits::default() }, ..test_config() };
+        let request = format!("GET / HTTP/1.1\r\nA: {}\r\n", "a".repeat(300));
+        let response = exchange(&config, &test_router(), request.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn status_reports_start_time_and_uptime() {
+        let mut router = test_router();
+        router.add_without_body("GET", "/status", status);
+        let response = exchange(&test_config(), &router, b"GET /status HTTP/1.1\r\n\r\n");
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        let Ok(JsonValue::Object(members)) = parse_json(body.as_bytes(), &JsonLimits::default()) else {
+            panic!("not a JSON object: {}", body);
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as f64;
+        match members.as_slice() {
+            [(started, JsonValue::Number(started_at)), (uptime, JsonValue::Number(seconds))] => {
+                assert_eq!((started.as_str(), uptime.as_str()), ("started_at", "uptime_seconds"));
+                assert!((now - started_at).abs() < 5.0 && (0.0..5.0).contains(seconds), "{}", body);
+            },
+            _ => panic!("unexpected members: {}", body),
+        }
+    }
+
+    #[test]
+    fn middleware_runs_in_order_and_shares_extensions() {
+        struct Trace(Vec<&'static str>);
+        let mut router = test_router();
+        router.add("GET", "/trace", |request: &HttpRequest, _state: &ServerState| {
+            let trace = request.extensions.get::<Trace>().map_or(Vec::new(), |trace| trace.0.clone());
+            HttpResponse::new(HTTP_OK).with_body(trace.join(",").into_bytes())
+        });
+        router.wrap(|request: &mut HttpRequest, next: Next| {
+            assert!(request.extensions.insert(Trace(vec!["first"])).is_none());
+            next.run(request).with_header("X-First", "1")
+        });
+        router.wrap(|request: &mut HttpRequest, next: Next| {
+            // Stop here for `/stop`, without calling the handler
+            if request.path == "/stop" {
+                return HttpResponse::new(HTTP_FORBIDDEN);
+            }
+            let mut trace = request.extensions.get::<Trace>().unwrap().0.clone();
+            trace.push("second");
+            assert!(request.extensions.insert(Trace(trace)).is_some());
+            next.run(request)
+        });
+
+        let response = exchange(&test_config(), &router, b"GET /trace HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nX-First: 1\r\n") && response.ends_with("\r\n\r\nfirst,second"), "{}", response);
+        let response = exchange(&test_config(), &router, b"GET /stop HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n") && response.contains("\r\nX-First: 1\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn header_values_are_trimmed() {
+        let request = parse("GET / HTTP/1.1\r\nA:1\r\nB: \t 2 \t\r\nC:\r\nD: x y \r\n\r\n").unwrap();
+        let values: Vec<&str> = request.headers.iter().map(|(_, value)| value.as_str()).collect();
+        assert_eq!(values, ["1", "2", "", "x y"]);
+        assert_eq!(request.header("b"), Some("2"));
+    }
+
+    #[test]
+    fn framework_errors_are_json_in_json_mode() {
+        let config = Config { error_format: ErrorFormat::Json, expose_panic_details: false, ..test_config() };
+        let mut router = test_router();
+        router.add("GET", "/panic", |_request: &HttpRequest, _state: &ServerState| -> HttpResponse { panic!("handler failed") });
+        let requests: [(&[u8], &str); 4] = [
+            (b"GET / HTTP/1.1\r\nBad Header: x\r\n\r\n", "{\"error\":\"Bad Request\",\"status\":400}"),
+            (b"GET /missing HTTP/1.1\r\n\r\n", "{\"error\":\"Not Found\",\"status\":404}"),
+            (b"GET / HTTP/2.0\r\n\r\n", "{\"error\":\"HTTP Version Not Supported\",\"status\":505}"),
+            (b"GET /panic HTTP/1.1\r\n\r\n", "{\"error\":\"Internal Server Error\",\"status\":500}"),
+        ];
+        for (request, body) in requests {
+            let response = exchange(&config, &router, request);
+            assert!(response.contains("\r\nContent-Type: application/json"), "{}", response);
+            assert!(response.ends_with(&format!("\r\n\r\n{}", body)), "{}", response);
+        }
+    }
+
+    #[test]
+    fn early_hints_go_out_before_the_response() {
+        let hints = ["</style.css>; rel=preload; as=style", "</app.js>; rel=preload; as=script"].map(String::from).to_vec();
+        let config = Config { early_hints: vec![("/".to_string(), hints)], ..test_config() };
+        let mut router = test_router();
+        router.wrap(send_early_hints);
+
+        let response = exchange(&config, &router, b"GET /?page=1 HTTP/1.1\r\n\r\n");
+        let expected = "HTTP/1.1 103 Early Hints\r\n\
+            Link: </style.css>; rel=preload; as=style\r\n\
+            Link: </app.js>; rel=preload; as=script\r\n\r\n\
+            HTTP/1.1 200 OK\r\n";
+        assert!(response.starts_with(expected), "{}", response);
+
+        // Not for HTTP/1.0, other methods or other paths
+        for request in ["GET / HTTP/1.0\r\n\r\n", "POST /echo HTTP/1.1\r\n\r\n", "GET /hello/x HTTP/1.1\r\n\r\n"] {
+            let response = exchange(&config, &router, request.as_bytes());
+            assert!(!response.contains("103 Early Hints"), "{}", response);
+        }
+    }
+
+    #[test]
+    fn access_log_lines_use_the_combined_format() {
+        let request = parse("GET /a?b HTTP/1.1\r\nReferer: http://x/\r\nUser-Agent: curl/8.0\r\n\r\n").unwrap();
+        let response = HttpResponse::new(HTTP_OK).with_body(b"hello".to_vec());
+        let peer = "192.0.2.1:4000".parse().ok();
+        let line = access_log_line(peer, &request, &response);
+        let (host, rest) = line.split_once(" - - [").unwrap();
+        let (_time, rest) = rest.split_once("] ").unwrap();
+        assert_eq!((host, rest), ("192.0.2.1", "\"GET /a?b HTTP/1.1\" 200 5 \"http://x/\" \"curl/8.0\""));
+
+        let request = parse("HEAD / HTTP/1.0\r\n\r\n").unwrap();
+        let line = access_log_line(None, &request, &response.headers_only(5));
+        assert!(line.starts_with("- - - [") && line.ends_with("] \"HEAD / HTTP/1.0\" 200 0 \"-\" \"-\""), "{}", line);
+        assert_eq!(clf_time(UNIX_EPOCH + Duration::from_secs(971_186_136)), "10/Oct/2000:13:55:36 +0000");
+    }
+
+    #[test]
+    fn absolute_targets_override_the_host_header() {
+        let mut request = parse("GET http://Example.com:8080/a?b HTTP/1.1\r\nHost: other\r\n\r\n").unwrap();
+        assert_eq!((request.path.as_str(), request.authority.as_deref()), ("/a?b", Some("Example.com:8080")));
+        assert!(request.reconcile_host(false));
+        assert_eq!(request.header("Host"), Some("Example.com:8080"));
+
+        // Added when missing, and a mismatch refused when asked to
+        let mut request = parse("GET http://example.com/ HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.reconcile_host(true));
+        assert_eq!(request.header("Host"), Some("example.com"));
+        let mut request = parse("GET http://example.com/ HTTP/1.1\r\nHost: EXAMPLE.com\r\n\r\n").unwrap();
+        assert!(request.reconcile_host(true));
+        let config = Config { reject_host_mismatch: true, ..test_config() };
+        let response = exchange(&config, &test_router(), b"GET http://example.com/ HTTP/1.1\r\nHost: other\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn overload_responses_ask_clients_to_retry() {
+        let config = Config { retry_after: Duration::from_secs(7), ..test_config() };
+        let response = String::from_utf8(service_unavailable(&config).build()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+        assert!(response.contains("\r\nRetry-After: 7\r\nConnection: close\r\n"), "{}", response);
+
+        let state = Arc::new(ServerState::new());
+        let guards: Vec<ConnectionGuard> = (0..3).map(|_| ConnectionGuard::new(&state)).collect();
+        assert_eq!(state.active_connections.load(Ordering::SeqCst), 3);
+        drop(guards);
+        assert_eq!(state.active_connections.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn panicking_handlers_get_a_500() {
+        let mut router = test_router();
+        router.add("GET", "/panic", |_request: &HttpRequest, _state: &ServerState| -> HttpResponse { panic!("handler failed") });
+        let request = b"GET /panic HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+
+        // The connection closes after the 500, the second request unread
+        let config = Config { expose_panic_details: true, ..test_config() };
+        let response = exchange(&config, &router, request);
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n500 - Internal Server Error\n\nHandler panicked: handler failed\n"), "{}", response);
+
+        let config = Config { expose_panic_details: false, ..test_config() };
+        let response = exchange(&config, &router, request);
+        assert!(response.ends_with("\r\n\r\n500 - Internal Server Error"), "{}", response);
+    }
+
+    #[test]
+    fn a_few_empty_lines_may_precede_the_request_line() {
+        let limits = ParserLimits::default();
+        let mut buffer = b"\r\n\n\r\nGET / HTTP/1.1\r\n\r\nrest".to_vec();
+        let head_end = read_head(&mut io::empty(), &mut buffer, &limits).unwrap();
+        assert_eq!(head_end, Some(buffer.len() - 4));
+        assert!(buffer.starts_with(b"GET / "));
+
+        // Past the limit the empty lines are part of the head, which is
+        // then malformed
+        let response = exchange(&test_config(), &test_router(), b"\r\n\r\n\r\n\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        let response = exchange(&test_config(), &test_router(), b"\r\n\r\n\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn only_routes_reading_the_body_are_sent_100_continue() {
+        let mut router = test_router();
+        router.add_without_body("POST", "/ignore", |_request: &HttpRequest, _state: &ServerState| HttpResponse::new(HTTP_NO_CONTENT));
+        let (config, (mut client, server)) = (test_config(), socket_pair());
+        let mut connection = Connection::new(server, &config, &router, &router.state);
+        client.write_all(b"POST /ignore HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\n").unwrap();
+
+        // Answered without asking for the body, and closed since the body
+        // may still come
+        assert_eq!(transitions(&mut connection), [ConnState::ReadingHeaders, ConnState::Dispatching, ConnState::Writing,
+            ConnState::Closing]);
+        assert_eq!(received(&mut client), "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+
+        // Nor are requests that match no route
+        let response = exchange(&config, &router, b"POST /missing HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn parse_errors_say_what_was_wrong() {
+        let cases = [
+            ("\r\n", ParseError::Empty, HTTP_BAD_REQUEST),
+            ("GET\r\n\r\n", ParseError::BadRequestLine, HTTP_BAD_REQUEST),
+            ("GET / HTTP/x\r\n\r\n", ParseError::BadVersion, HTTP_BAD_REQUEST),
+            ("GET / HTTP/2.0\r\n\r\n", ParseError::UnsupportedVersion, HTTP_VERSION_NOT_SUPPORTED),
+            ("GET /a%00 HTTP/1.1\r\n\r\n", ParseError::BadPath, HTTP_BAD_REQUEST),
+            ("GET / HTTP/1.1\r\nNo colon\r\n\r\n", ParseError::BadHeader, HTTP_BAD_REQUEST),
+        ];
+        for (raw, error, status_line) in cases {
+            let parsed = parse(raw).map(|_| ());
+            assert_eq!(parsed, Err(error), "{:?}", raw);
+            assert_eq!(parsed.unwrap_err().status_line(), status_line, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn small_responses_are_sent_in_one_write() {
+        // Writer keeping each write apart
+        struct Writes(Vec<Vec<u8>>);
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.to_vec());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let writes = |config: &Config, body_len: usize| {
+            let mut out = Writes(Vec::new());
+            assert!(send_response(&mut out, HttpResponse::new(HTTP_OK).with_body(vec![b'x'; body_len]), config));
+            // The body's size of each write after the first, which holds the
+            // head
+            out.0.iter().skip(1).map(Vec::len).collect::<Vec<_>>()
+        };
+
+        let config = Config { coalesce_threshold: 16, ..test_config() };
+        assert_eq!(writes(&config, 16), []);
+        assert_eq!(writes(&config, 17), [17]);
+        let config = Config { coalesce_small_responses: false, ..test_config() };
+        assert_eq!(writes(&config, 1), [1]);
+    }
+
+    #[test]
+    fn incomplete_heads_time_out_and_malformed_ones_are_bad() {
+        let config = Config { read_timeout: Duration::from_millis(100), ..test_config() };
+        let router = test_router();
+
+        // The client stops partway through the head and waits
+        let (mut client, server) = socket_pair();
+        server.set_read_timeout(Some(config.read_timeout)).unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| Connection::new(server, &config, &router, &router.state).run());
+            client.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", response);
+        });
+
+        // Or closes its side before finishing it
+        let response = exchange(&config, &router, b"GET / HTTP/1.1\r\nHost: x\r\n");
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", response);
+
+        // A whole head that doesn't parse is the client's mistake
+        let response = exchange(&config, &router, b"GET / HTTP/1.1\r\nHost x\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn disconnects_are_told_apart_from_write_failures() {
+        // Writer failing every write with one kind of error
+        struct Failing(io::ErrorKind);
+        impl Write for Failing {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(self.0.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Only logged with debug logging on, but the connection ends either way
+        for kind in [io::ErrorKind::BrokenPipe, io::ErrorKind::ConnectionReset, io::ErrorKind::ConnectionAborted] {
+            assert!(is_disconnect(&kind.into()), "{:?}", kind);
+            assert!(!send_response(&mut Failing(kind), HttpResponse::new(HTTP_OK), &test_config()));
+        }
+        for kind in [io::ErrorKind::PermissionDenied, io::ErrorKind::Other, io::ErrorKind::WriteZero] {
+            assert!(!is_disconnect(&kind.into()), "{:?}", kind);
+            assert!(!send_response(&mut Failing(kind), HttpResponse::new(HTTP_OK), &test_config()));
+        }
+    }
+
+    #[test]
+    fn gzipped_request_bodies_reach_handlers_decoded() {
+        let gzip = |body: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        };
+        let post = |config: &Config, encoding: &str, body: &[u8]| {
+            let mut raw = format!("POST /echo HTTP/1.1\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n", encoding, body.len())
+                .into_bytes();
+            raw.extend_from_slice(body);
+            exchange(config, &test_router(), &raw)
+        };
+
+        let config = test_config();
+        let response = post(&config, "gzip", &gzip(b"hello, compressed world"));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello, compressed world"), "{}", response);
+        assert!(post(&config, "gzip", b"not gzip").starts_with("HTTP/1.1 400 "));
+        assert!(post(&config, "br", b"hello").starts_with("HTTP/1.1 415 "));
+
+        // The limit applies to the decoded size, however well it compresses
+        let config = Config { limits: ParserLimits { max_body: 1000, ..ParserLimits::default() }, ..test_config() };
+        let bomb = gzip(&[0; 100_000]);
+        assert!(bomb.len() < 1000);
+        assert!(post(&config, "gzip", &bomb).starts_with("HTTP/1.1 413 "));
+    }
+
+    #[test]
+    fn middleware_overrunning_the_budget_gets_a_504() {
+        let mut router = test_router();
+        router.wrap(Timeout { budget: Duration::from_millis(50) });
+        router.wrap(|request: &mut HttpRequest, next: Next| -> HttpResponse {
+            assert!(request.time_left() <= Duration::from_millis(50));
+            if request.path == "/slow" {
+                thread::sleep(Duration::from_millis(80));
+            }
+            next.run(request)
+        });
+        // The handler is never reached once the budget is spent
+        router.add("GET", "/slow", |_request: &HttpRequest, _state: &ServerState| -> HttpResponse {
+            panic!("ran past the deadline")
+        });
+
+        let config = test_config();
+        let response = exchange(&config, &router, b"GET /slow HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"), "{}", response);
+        let response = exchange(&config, &router, b"GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn unsupported_upgrades_are_served_as_plain_requests() {
+        let raw = b"POST /echo HTTP/1.1\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n\
+            HTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\nContent-Length: 5\r\n\r\nhello\
+            GET / HTTP/1.1\r\n\r\n";
+        let response = exchange(&test_config(), &test_router(), raw);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(!response.contains("101 Switching Protocols"), "{}", response);
+        assert!(!response.contains("Upgrade"), "{}", response);
+        // The connection stays HTTP/1.1 for the next request
+        let (first, second) = response.split_once("hello").unwrap();
+        assert!(first.ends_with("\r\n\r\n"), "{}", response);
+        assert!(second.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn lenient_heads_may_end_with_the_stream() {
+        let lenient = Config { limits: ParserLimits { lenient_parsing: true, ..ParserLimits::default() }, ..test_config() };
+        let router = test_router();
+        for raw in [&b"GET / HTTP/1.1\nHost: x\n\n"[..], b"GET / HTTP/1.1\r\nHost: x\r\n", b"GET / HTTP/1.1\nHost: x"] {
+            let response = exchange(&lenient, &router, raw);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", String::from_utf8_lossy(raw), response);
+        }
+
+        // Without the flag a bare LF is refused and a cut-off head times out
+        let response = exchange(&test_config(), &router, b"GET / HTTP/1.1\nHost: x\n\n");
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        let response = exchange(&test_config(), &router, b"GET / HTTP/1.1\r\nHost: x\r\n");
+        assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let request = b"POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\none\
+            POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\ntwo\
+            GET /missing HTTP/1.1\r\n\r\n";
+        let response = exchange(&test_config(), &test_router(), request);
+        let statuses: Vec<&str> = response.match_indices("HTTP/1.1 ").map(|(at, _)| &response[at..at + 12]).collect();
+        assert_eq!(statuses, ["HTTP/1.1 200", "HTTP/1.1 200", "HTTP/1.1 404"], "{}", response);
+        let one = response.find("\r\n\r\none").unwrap();
+        assert!(one < response.find("\r\n\r\ntwo").unwrap(), "{}", response);
+    }
+
+    #[test]
+    fn connections_arriving_while_draining_are_told_to_retry() {
+        let config = Config { retry_after: Duration::from_secs(30), ..test_config() };
+        let state = ServerState::new();
+        let mut out = Vec::new();
+        assert!(!turn_away(&mut out, &config, &state, false));
+        assert!(out.is_empty());
+
+        assert!(turn_away(&mut out, &config, &state, true));
+        let response = String::from_utf8(out).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+        assert!(response.contains("\r\nRetry-After: 30\r\n"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+
+        // As are connections past the limit when not draining
+        let config = Config { max_connections: 0, ..test_config() };
+        assert!(turn_away(&mut Vec::new(), &config, &state, false));
+    }
+
+    #[test]
+    fn cache_control_directives_are_joined_with_commas() {
+        let response = HttpResponse::new(HTTP_OK).cache_control(&["public", "max-age=3600", "must-revalidate"]);
+        assert_eq!(response.headers, [("Cache-Control".to_string(), "public, max-age=3600, must-revalidate".to_string())]);
+        let response = HttpResponse::new(HTTP_OK).cache_control(&["no-store"]);
+        assert_eq!(response.headers, [("Cache-Control".to_string(), "no-store".to_string())]);
+    }
+
+    #[test]
+    fn handlers_share_the_router_state() {
+        struct App {
+            greeting: String,
+            visits: AtomicU64,
+        }
+        let mut router = Router::with_state(Arc::new(App { greeting: "Hi".to_string(), visits: AtomicU64::new(0) }));
+        router.add("POST", "/visit", |_request: &HttpRequest, app: &App| {
+            let visits = app.visits.fetch_add(1, Ordering::SeqCst) + 1;
+            HttpResponse::new(HTTP_OK).with_body(visits.to_string().into_bytes())
+        });
+        router.add("GET", "/greet", |_request: &HttpRequest, app: &App| {
+            let body = format!("{} after {} visits", app.greeting, app.visits.load(Ordering::SeqCst));
+            HttpResponse::new(HTTP_OK).with_body(body.into_bytes())
+        });
+        let (config, server) = (test_config(), ServerState::new());
+        let request = |raw: &[u8]| {
+            let (mut client, stream) = socket_pair();
+            thread::scope(|scope| {
+                scope.spawn(|| Connection::new(stream, &config, &router, &server).run());
+                client.write_all(raw).unwrap();
+                client.shutdown(std::net::Shutdown::Write).unwrap();
+                let mut response = String::new();
+                client.read_to_string(&mut response).unwrap();
+                response
+            })
+        };
+
+        // Each connection sees the count the others left
+        for expected in 1..=3 {
+            let response = request(b"POST /visit HTTP/1.1\r\n\r\n");
+            assert!(response.ends_with(&format!("\r\n\r\n{}", expected)), "{}", response);
+        }
+        assert!(request(b"GET /greet HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nHi after 3 visits"));
+        assert_eq!(router.state.visits.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn server_timing_reports_the_handling_time() {
+        let raw = b"GET / HTTP/1.1\r\n\r\n";
+        let response = exchange(&test_config(), &test_router(), raw);
+        assert!(!response.contains("Server-Timing"), "{}", response);
+
+        let config = Config { server_timing: true, ..test_config() };
+        let response = exchange(&config, &test_router(), raw);
+        let value = response.lines().find_map(|line| line.strip_prefix("Server-Timing: app;dur=")).unwrap();
+        assert!(value.parse::<f64>().is_ok_and(|millis| millis >= 0.0), "{}", response);
+    }
+
+    // The states a connection goes through until it closes, each once for
+    // however many steps it takes
+    fn transitions<S: Send + Sync + 'static>(connection: &mut Connection<S>) -> Vec<ConnState> {
+        let mut states = vec![connection.state];
+        while connection.state != ConnState::Closing {
+            let state = connection.step().unwrap();
+            if states.last() != Some(&state) {
+                states.push(state);
+            }
+        }
+        states
+    }
+
+    // Step the connection until it reaches `state`
+    fn step_until<S: Send + Sync + 'static>(connection: &mut Connection<S>, state: ConnState) {
+        while connection.step().unwrap() != state {
+            assert_ne!(connection.state, ConnState::Closing, "closed before reaching {:?}", state);
+        }
+    }
+
+    // What the server has sent so far, waiting briefly for it to arrive
+    fn received(client: &mut TcpStream) -> String {
+        client.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let mut received = Vec::new();
+        let mut chunk = [0; 4096];
+        while let Ok(size @ 1..) = client.read(&mut chunk) {
+            received.extend_from_slice(&chunk[..size]);
+        }
+        String::from_utf8_lossy(&received).into_owned()
+    }
+
+    #[test]
+    fn pipelined_reads_pause_at_the_cap_until_responses_drain() {
+        let config = Config { max_pipelined_requests: 2, ..test_config() };
+        let router = test_router();
+        let (mut client, server) = socket_pair();
+        let mut connection = Connection::new(server, &config, &router, &router.state);
+        client.write_all(&b"GET / HTTP/1.1\r\nHost: x\r\n\r\n".repeat(3)).unwrap();
+
+        // The first response is held, as the next request is already here
+        step_until(&mut connection, ConnState::Idle);
+        assert_eq!(connection.held, 1);
+        assert_eq!(received(&mut client), "");
+
+        // The second reaches the cap: both go out before the third request
+        // is looked at
+        step_until(&mut connection, ConnState::Idle);
+        assert_eq!((connection.requests, connection.held), (2, 0));
+        assert_eq!(received(&mut client).matches("HTTP/1.1 200 OK").count(), 2);
+
+        // Nothing follows the third, so its response goes out as soon as a
+        // read would wait
+        step_until(&mut connection, ConnState::Idle);
+        assert_eq!(connection.held, 1);
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        step_until(&mut connection, ConnState::Closing);
+        assert_eq!(received(&mut client).matches("HTTP/1.1 200 OK").count(), 1);
+    }
+
+    #[test]
+    fn responses_are_written_one_by_one_by_default() {
+        let (config, router) = (test_config(), test_router());
+        let (mut client, server) = socket_pair();
+        let mut connection = Connection::new(server, &config, &router, &router.state);
+        client.write_all(&b"GET / HTTP/1.1\r\nHost: x\r\n\r\n".repeat(2)).unwrap();
+        step_until(&mut connection, ConnState::Idle);
+        assert_eq!(connection.held, 0);
+        assert_eq!(received(&mut client).matches("HTTP/1.1 200 OK").count(), 1);
+    }
+
+    #[test]
+    fn held_responses_aren_t_overtaken_by_100_continue() {
+        let config = Config { max_pipelined_requests: 4, ..test_config() };
+        let router = test_router();
+        let (mut client, server) = socket_pair();
+        let mut connection = Connection::new(server, &config, &router, &router.state);
+        client.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\nPOST /echo HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n").unwrap();
+        step_until(&mut connection, ConnState::Idle);
+        step_until(&mut connection, ConnState::ReadingBody);
+        client.write_all(b"hi").unwrap();
+        step_until(&mut connection, ConnState::Idle);
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        step_until(&mut connection, ConnState::Closing);
+        let response = received(&mut client);
+        let continued = response.find("HTTP/1.1 100 Continue").unwrap();
+        assert!(response.find("HTTP/1.1 200 OK").unwrap() < continued, "{}", response);
+        assert!(response.ends_with("hi"), "{}", response);
+    }
+
+    fn upload_body(files: &[(&str, &str)]) -> String {
+        let mut body = String::new();
+        for (name, contents) in files {
+            body.push_str(&format!(
+                "--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n{}\r\n",
+                name, contents
+            ));
+        }
+        body.push_str("--XyZ\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nnot a file\r\n--XyZ--\r\n");
+        format!(
+            "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn uploads_are_streamed_to_their_own_files() {
+        let dir = temp_dir("uploads");
+        let config = Arc::new(test_config());
+        let mut router = test_router();
+        router.add_streaming("POST", "/upload", Uploads::new(Arc::clone(&config), &dir));
+
+        let contents = "x".repeat(100_000);
+        let request = upload_body(&[("a.txt", "first\r\n--XyY-- inside"), ("../../b.bin", &contents)]);
+        let response = exchange(&config, &router, format!("{}GET / HTTP/1.1\r\n\r\n", request).as_bytes());
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert!(response.contains("\r\n\r\na.txt\nb.bin\nHTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "first\r\n--XyY-- inside");
+        assert_eq!(fs::read_to_string(dir.join("b.bin")).unwrap(), contents);
+
+        // Names already taken aren't overwritten
+        let response = exchange(&config, &router, upload_body(&[("a.txt", "again")]).as_bytes());
+        assert!(response.starts_with("HTTP/1.1 409 Conflict\r\n"), "{}", response);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "first\r\n--XyY-- inside");
+    }
+
+    #[test]
+    fn uploads_may_be_larger_than_max_body() {
+        let dir = temp_dir("large-uploads");
+        let limits = ParserLimits { max_body: 1000, max_upload: 200_000, ..ParserLimits::default() };
+        let config = Arc::new(Config { limits, ..test_config() });
+        let mut router = test_router();
+        router.add_streaming("POST", "/upload", Uploads::new(Arc::clone(&config), &dir));
+        router.add("POST", "/echo", echo_body);
+
+        let contents = "x".repeat(100_000);
+        let response = exchange(&config, &router, upload_body(&[("large.bin", &contents)]).as_bytes());
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert_eq!(fs::read_to_string(dir.join("large.bin")).unwrap(), contents);
+
+        // Past `max_upload` it's refused, as are bodies read into memory past
+        // `max_body`
+        let response = exchange(&config, &router, b"POST /upload HTTP/1.1\r\nContent-Length: 200001\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", response);
+        let response = exchange(&config, &router, b"POST /echo HTTP/1.1\r\nContent-Length: 1001\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn streaming_routes_read_the_body_from_the_connection() {
+        let config = test_config();
+        let mut router = test_router();
+        router.add_streaming("POST", "/head", |request: &HttpRequest, _state: &ServerState| {
+            assert_eq!(request.body.is_empty(), request.body_stream.is_some());
+            let mut head = [0; 4];
+            request.body_reader().read_exact(&mut head).unwrap();
+            HttpResponse::new(HTTP_OK).with_body(head.to_vec())
+        });
+
+        // What the handler leaves of the body is skipped, the request behind
+        // it is read from where it starts
+        let body = "abcd".repeat(10_000);
+        let request = format!("POST /head HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}GET / HTTP/1.1\r\n\r\n", body.len(), body);
+        let response = exchange(&config, &router, request.as_bytes());
+        assert!(response.contains("\r\n\r\nabcdHTTP/1.1 200 OK\r\n"), "{}", response);
+
+        // Bodies that aren't left to the handler are read as usual
+        let request = "POST /head HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nwxyz\r\n0\r\n\r\n";
+        let response = exchange(&config, &router, request.as_bytes());
+        assert!(response.ends_with("\r\n\r\nwxyz"), "{}", response);
+    }
+
+    #[test]
+    fn a_streamed_body_cut_short_closes_the_connection() {
+        let config = test_config();
+        let mut router = test_router();
+        router.add_streaming("POST", "/skip", |_request: &HttpRequest, _state: &ServerState| HttpResponse::new(HTTP_OK));
+        let response = exchange(&config, &router, b"POST /skip HTTP/1.1\r\nContent-Length: 100\r\n\r\nshort");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn connections_step_through_each_phase_of_a_request() {
+        use ConnState::*;
+        let (config, router) = (test_config(), test_router());
+        let run = |config: &Config, request: &[u8]| {
+            let (mut client, server) = socket_pair();
+            let mut connection = Connection::new(server, config, &router, &router.state);
+            client.write_all(request).unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            let states = transitions(&mut connection);
+            (states, received(&mut client))
+        };
+
+        // Kept alive, the connection waits for another request that never
+        // comes
+        let (states, response) = run(&config, b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi");
+        assert_eq!(states, [ReadingHeaders, ReadingBody, Dispatching, Writing, Idle, Closing]);
+        assert!(response.ends_with("\r\n\r\nhi"), "{}", response);
+        let (states, _) = run(&config, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert_eq!(states, [ReadingHeaders, ReadingBody, Dispatching, Writing, Closing]);
+        let (states, response) = run(&config, &b"GET / HTTP/1.1\r\n\r\n".repeat(2));
+        assert_eq!(states, [ReadingHeaders, ReadingBody, Dispatching, Writing, Idle, ReadingHeaders, ReadingBody,
+            Dispatching, Writing, Idle, Closing]);
+        assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+
+        // A bad head goes straight to writing the error, and a client
+        // leaving before sending anything to closing
+        let (states, response) = run(&config, b"GET / HTTP/1.1\r\nBad Header: x\r\n\r\n");
+        assert_eq!(states, [ReadingHeaders, Writing, Closing]);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        let (states, response) = run(&config, b"");
+        assert_eq!((states, response.as_str()), (vec![ReadingHeaders, Closing], ""));
+
+        // A body cut short is answered with 408 from the body phase
+        let (states, response) = run(&config, b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhi");
+        assert_eq!(states, [ReadingHeaders, ReadingBody, Writing, Closing]);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", response);
+
+        // The PROXY line comes first when expected
+        let config = Config { proxy_protocol: true, ..test_config() };
+        let (states, _) = run(&config, b"PROXY TCP4 192.0.2.1 127.0.0.1 5000 80\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert_eq!(states, [ReadingProxyHeader, ReadingHeaders, ReadingBody, Dispatching, Writing, Closing]);
+        let (states, response) = run(&config, b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!((states, response.as_str()), (vec![ReadingProxyHeader, Closing], ""));
+    }
+
+    #[test]
+    fn metrics_report_requests_per_connection() {
+        let mut router = test_router();
+        router.add_without_body("GET", "/metrics", metrics);
+        // As the accept loop would count the connection
+        router.state.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        let response = exchange(&test_config(), &router, b"GET / HTTP/1.1\r\n\r\nGET /metrics HTTP/1.1\r\n\r\n");
+        let (_, metrics) = response.split_once("\r\n\r\n# HELP").unwrap();
+        assert!(metrics.contains("\nkp_http_connections_accepted_total 1\n"), "{}", metrics);
+        assert!(metrics.contains("\nkp_http_requests_served_total 2\n"), "{}", metrics);
+        assert!(metrics.contains("\nkp_http_requests_per_connection 2.000\n"), "{}", metrics);
+    }
+
+    #[test]
+    fn middleware_answering_itself_skips_the_handler() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.wrap(RateLimit::new(1, Duration::from_secs(60)));
+        let handled = Arc::clone(&calls);
+        router.add("GET", "/", move |_request: &HttpRequest, _state: &ServerState| {
+            handled.fetch_add(1, Ordering::SeqCst);
+            HttpResponse::new(HTTP_OK)
+        });
+
+        let config = test_config();
+        assert!(exchange(&config, &router, b"GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        let response = exchange(&config, &router, b"GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{}", response);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn proxy_lines_give_the_client_address() {
+        let addr = |text: &str| Some(Some(text.parse::<SocketAddr>().unwrap()));
+        assert_eq!(parse_proxy_line("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443"), addr("192.0.2.1:56324"));
+        assert_eq!(parse_proxy_line("PROXY TCP6 2001:db8::1 2001:db8::2 4000 443"), addr("[2001:db8::1]:4000"));
+        assert_eq!(parse_proxy_line("PROXY UNKNOWN"), Some(None));
+        for bad in [
+            "PROXY TCP4 2001:db8::1 2001:db8::2 4000 443",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 70000 443",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 56324",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443 extra",
+            "PROXY  TCP4 192.0.2.1 198.51.100.1 56324 443",
+            "GET / HTTP/1.1",
+        ] {
+            assert_eq!(parse_proxy_line(bad), None, "{:?}", bad);
+        }
+
+        let config = Config { proxy_protocol: true, ..test_config() };
+        let mut router = test_router();
+        router.add("GET", "/peer", |request: &HttpRequest, _state: &ServerState| {
+            let peer = request.remote_addr.map(|peer| peer.to_string()).unwrap_or_default();
+            HttpResponse::new(HTTP_OK).with_body(peer.into_bytes())
+        });
+        let response = exchange(&config, &router, b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /peer HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\n192.0.2.1:56324"), "{}", response);
+        // A malformed line closes the connection without an answer
+        assert_eq!(exchange(&config, &router, b"PROXY TCP4 nonsense\r\nGET /peer HTTP/1.1\r\n\r\n"), "");
+    }
+
+    #[test]
+    fn ip_ranges_match_by_prefix() {
+        let filter = IpFilter::new(&["10.0.0.0/8".to_string(), "::1".to_string()], &["10.1.0.0/16".to_string()]).unwrap();
+        assert!(filter.permits("10.2.3.4".parse().unwrap()));
+        assert!(filter.permits("::1".parse().unwrap()));
+        assert!(!filter.permits("10.1.3.4".parse().unwrap()));
+        assert!(!filter.permits("192.168.0.1".parse().unwrap()));
+        // IPv4-mapped IPv6 addresses are matched as IPv4
+        assert!(filter.permits("::ffff:10.2.3.4".parse().unwrap()));
+        assert!(IpFilter::new(&["10.0.0.0/33".to_string()], &[]).is_err());
+        assert!(IpFilter::new(&[], &["not an ip".to_string()]).is_err());
+    }
+
+    #[test]
+    fn denied_clients_get_403_before_their_request_is_read() {
+        let config = Config { trace_enabled: true, ..test_config() };
+        let mut router = test_router();
+        router.filter_ips(IpFilter::new(&[], &["127.0.0.0/8".to_string()]).unwrap());
+        router.wrap(|_request: &mut HttpRequest, _next: Next| -> HttpResponse { panic!("middleware ran") });
+
+        // The answer comes without the client sending anything, so nothing
+        // it would send is read, routed or traced
+        let (mut client, server) = socket_pair();
+        thread::scope(|scope| {
+            scope.spawn(|| Connection::new(server, &config, &router, &router.state).run());
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+            assert!(response.contains("Connection: close\r\n"), "{}", response);
+        });
+    }
+
+    #[test]
+    fn allowed_clients_proceed() {
+        let mut router = test_router();
+        router.filter_ips(IpFilter::new(&["127.0.0.1/32".to_string()], &["10.0.0.0/8".to_string()]).unwrap());
+        let response = exchange(&test_config(), &router, b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(response.ends_with("hello"), "{}", response);
+    }
+
+    #[test]
+    fn ip_filter_applies_to_the_proxied_client() {
+        let config = Config { proxy_protocol: true, ..test_config() };
+        let mut router = test_router();
+        router.filter_ips(IpFilter::new(&[], &["203.0.113.0/24".to_string()]).unwrap());
+        let proxied = |client: &str| {
+            let raw = format!("PROXY TCP4 {} 127.0.0.1 5000 80\r\nGET / HTTP/1.1\r\n\r\n", client);
+            exchange(&config, &router, raw.as_bytes())
+        };
+        assert!(proxied("203.0.113.9").starts_with("HTTP/1.1 403 "));
+        assert!(proxied("198.51.100.9").starts_with("HTTP/1.1 200 "));
+    }
+
+    #[test]
+    fn reason_phrases_can_be_replaced() {
+        let response = HttpResponse::new(HTTP_SERVICE_UNAVAILABLE).with_reason("Draining");
+        assert_eq!((response.status_code(), response.reason()), (503, "Draining"));
+        assert!(response.build().starts_with(b"HTTP/1.1 503 Draining\r\n"));
+        assert_eq!(HttpResponse::new(HTTP_NOT_FOUND).reason(), "Not Found");
+    }
+
+    #[test]
+    fn health_reports_draining() {
+        let request = parse("GET /health HTTP/1.1\r\nAccept: text/plain\r\n\r\n").unwrap();
+        let response = health_response(&request, false);
+        assert_eq!((response.status_code(), response.reason(), response.body.as_slice()), (200, "OK", &b"healthy"[..]));
+        let response = health_response(&request, true);
+        assert_eq!((response.status_code(), response.reason()), (503, "Draining"));
+    }
+
+    #[test]
+    fn trailers_of_chunked_requests_can_be_read() {
+        let mut router = test_router();
+        router.add("POST", "/checksum", |request: &HttpRequest, _state: &ServerState| {
+            let checksum = format!("{:?} {:?}", request.trailer("checksum"), request.trailer("Missing"));
+            HttpResponse::new(HTTP_OK).with_body(checksum.into_bytes())
+        });
+        let request = "POST /checksum HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n0\r\nChecksum: abc\r\n\r\n";
+        let response = exchange(&test_config(), &router, request.as_bytes());
+        assert!(response.ends_with("\r\n\r\nSome(\"abc\") None"), "{}", response);
+
+        let request = parse("POST / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.trailer("Checksum"), None);
+    }
+
+    #[test]
+    fn any_or_no_accept_gets_the_first_offer() {
+        let offered = ["application/json", "text/plain"];
+        let negotiated = |accept: Option<&str>| {
+            let raw = match accept {
+                Some(accept) => format!("GET / HTTP/1.1\r\nAccept: {}\r\n\r\n", accept),
+                None => "GET / HTTP/1.1\r\n\r\n".to_string(),
+            };
+            parse(&raw).unwrap().negotiate(&offered)
+        };
+        for accept in [None, Some("*/*"), Some(""), Some("*/*;q=0.8"), Some("text/html, */*;q=0.1")] {
+            assert_eq!(negotiated(accept), Some("application/json"), "{:?}", accept);
+        }
+        assert_eq!(negotiated(Some("text/*")), Some("text/plain"));
+        assert_eq!(negotiated(Some("text/plain, application/json;q=0.5")), Some("text/plain"));
+        assert_eq!(negotiated(Some("text/html")), None);
+        assert_eq!(negotiated(Some("*/*, application/json;q=0")), Some("text/plain"));
+
+        // Where the health check answers
+        let response = health_response(&parse("GET /health HTTP/1.1\r\nAccept: */*\r\n\r\n").unwrap(), false);
+        assert_eq!(response.status_code(), 200);
+        let response = health_response(&parse("GET /health HTTP/1.1\r\nAccept: image/png\r\n\r\n").unwrap(), false);
+        assert_eq!(response.status_code(), 406);
+    }
+
+    #[test]
+    fn strict_request_lines_are_single_spaced() {
+        let strict = ParserLimits { strict_request_line: true, ..ParserLimits::default() };
+        for line in ["GET\t/ HTTP/1.1", "GET  / HTTP/1.1", "GET / HTTP/1.1 ", " GET / HTTP/1.1", "GET /\u{b} HTTP/1.1"] {
+            let raw = format!("{}\r\nHost: x\r\n\r\n", line);
+            assert_eq!(HttpRequest::parse(raw.as_bytes(), &strict).unwrap_err(), ParseError::BadRequestLine, "{:?}", line);
+        }
+        assert!(HttpRequest::parse(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n", &strict).is_ok());
+
+        // The default split tolerates the odd spacing
+        let request = parse("GET\t/a  HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("GET", "/a"));
+
+        let config = Config { limits: strict, ..test_config() };
+        let response = exchange(&config, &test_router(), b"GET  / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn recorded_exchanges_match_the_wire() {
+        let dir = temp_dir("record");
+        let config = Config { record_dir: Some(dir.clone()), ..test_config() };
+        let first = b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let second = b"GET / HTTP/1.1\r\n\r\n";
+        let response = exchange(&config, &test_router(), &[&first[..], second].concat());
+
+        let mut names: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        names.sort();
+        assert_eq!(names.len(), 4, "{:?}", names);
+        let read = |path: &PathBuf| fs::read(path).unwrap();
+        assert_eq!(names[0].extension().unwrap(), "request");
+        assert_eq!(read(&names[0]), first);
+        assert_eq!(read(&names[2]), second);
+        // The responses, in order, are exactly what the client received
+        assert_eq!(response.as_bytes(), [read(&names[1]), read(&names[3])].concat());
+    }
+
+    #[test]
+    fn idle_connections_close_on_eof_or_timeout_and_resume_on_data() {
+        let config = Config { idle_timeout: Duration::from_millis(50), ..test_config() };
+        let router = test_router();
+        let idle = || {
+            let (mut client, server) = socket_pair();
+            let mut connection = Connection::new(server, &config, &router, &router.state);
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            step_until(&mut connection, ConnState::Idle);
+            assert!(received(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+            (client, connection)
+        };
+
+        // The client closes while idle
+        let (client, mut connection) = idle();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(connection.step().unwrap(), ConnState::Closing);
+
+        // It sends nothing for the idle timeout
+        let (_client, mut connection) = idle();
+        let started = Instant::now();
+        assert_eq!(connection.step().unwrap(), ConnState::Closing);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // It sends the next request, which is read from its first bytes
+        let (mut client, mut connection) = idle();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(connection.step().unwrap(), ConnState::ReadingHeaders);
+        step_until(&mut connection, ConnState::Idle);
+        assert!(received(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn negotiated_responses_vary_on_what_they_were_negotiated_on() {
+        let mut router = test_router();
+        router.add("GET", "/report", |request: &HttpRequest, _state: &ServerState| {
+            let content_type = request.negotiate(&["application/json", "text/plain"]).unwrap_or("text/plain");
+            HttpResponse::new(HTTP_OK)
+                .with_header("Content-Type", content_type)
+                .with_header("Vary", "Cookie")
+                .with_body(b"report line\n".repeat(100))
+        });
+        let config = test_config();
+
+        let response = exchange(&config, &router, b"GET /report HTTP/1.1\r\nAccept: text/plain\r\nAccept-Encoding: gzip\r\n\r\n");
+        assert!(response.contains("\r\nContent-Encoding: gzip\r\n"), "{}", response);
+        assert!(response.contains("\r\nVary: Cookie, Accept, Accept-Encoding\r\n"), "{}", response);
+        // Whether or not the client could take gzip
+        let response = exchange(&config, &router, b"GET /report HTTP/1.1\r\n\r\n");
+        assert!(!response.contains("Content-Encoding"), "{}", response);
+        assert!(response.contains("\r\nVary: Cookie, Accept, Accept-Encoding\r\n"), "{}", response);
+
+        // Nothing negotiated, nothing added
+        let response = exchange(&config, &router, b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi");
+        assert!(!response.contains("Vary"), "{}", response);
+        assert_eq!(add_vary(HttpResponse::new(HTTP_OK).with_header("Vary", "*"), &["Accept"]).headers, [
+            ("Vary".to_string(), "*".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn responses_over_the_limit_are_aborted() {
+        let config = Config { max_response_bytes: Some(1000), ..test_config() };
+        let mut router = test_router();
+        router.add("GET", "/big", |_request: &HttpRequest, _state: &ServerState| {
+            HttpResponse::new(HTTP_OK).with_body(vec![b'x'; 1001])
+        });
+        router.add("GET", "/endless", |_request: &HttpRequest, _state: &ServerState| {
+            HttpResponse::new(HTTP_OK).with_reader(io::repeat(b'x'))
+        });
+
+        // A buffered body over the limit is never started
+        assert_eq!(exchange(&config, &router, b"GET /big HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n"), "");
+        let mut at_limit = b"POST /echo HTTP/1.1\r\nContent-Length: 1000\r\n\r\n".to_vec();
+        at_limit.extend_from_slice(&[b'y'; 1000]);
+        let response = exchange(&config, &router, &at_limit);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        // A streamed one is cut off at it, without the final chunk that
+        // would make it look complete
+        let response = exchange(&config, &router, b"GET /endless HTTP/1.1\r\n\r\n");
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(body.matches('x').count() <= 1000, "{} bytes of body", body.len());
+        assert!(!body.ends_with("0\r\n\r\n"));
+    }
+
+    #[test]
+    fn idle_connections_are_swept() {
+        // Long enough that only the sweeper can end the wait
+        let config = Config { idle_timeout: Duration::from_secs(60), ..test_config() };
+        let router = test_router();
+        let (mut client, server) = socket_pair();
+        let (mut busy, busy_server) = socket_pair();
+        thread::scope(|scope| {
+            let idle = scope.spawn(|| Connection::new(server, &config, &router, &router.state).run());
+            let _busy = scope.spawn(|| Connection::new(busy_server, &config, &router, &router.state).run());
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            assert!(received(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+            // Partway through its request head, so not idle
+            busy.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+            sweep_idle(&router.state, Duration::from_millis(100));
+            assert_eq!(router.state.connections().len(), 2, "swept before the timeout");
+            thread::sleep(Duration::from_millis(100));
+            sweep_idle(&router.state, Duration::from_millis(100));
+            idle.join().unwrap();
+            assert_eq!(received(&mut client), "");
+            assert_eq!(router.state.connections().len(), 1);
+
+            busy.write_all(b"\r\n").unwrap();
+            assert!(received(&mut busy).starts_with("HTTP/1.1 200 OK\r\n"));
+            busy.shutdown(std::net::Shutdown::Write).unwrap();
+        });
+        assert!(router.state.connections().is_empty());
+    }
+
+    #[test]
+    fn audited_bodies_reach_both_the_sink_and_the_handler() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        let audit = BodyAudit {
+            paths: vec!["/echo".to_string()],
+            content_types: vec!["application/json".to_string()],
+            max_bytes: 4,
+            sink: AuditSink::Callback(Box::new(move |request, body| {
+                sink.lock().unwrap().push((request.path.clone(), body.to_vec()));
+            })),
+        };
+        let config = Config { audit_bodies: Some(audit), ..test_config() };
+        let request = |path: &str, content_type: &str| {
+            let request = format!(
+                "POST {} HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: 9\r\n\r\n[1,2,3,4]",
+                path, content_type,
+            );
+            exchange(&config, &test_router(), request.as_bytes())
+        };
+
+        let response = request("/echo", "application/json; charset=utf-8");
+        assert!(response.ends_with("\r\n\r\n[1,2,3,4]"), "{}", response);
+        // Other paths and types aren't audited
+        request("/", "application/json");
+        request("/echo", "text/plain");
+        assert_eq!(*seen.lock().unwrap(), [("/echo".to_string(), b"[1,2".to_vec())]);
+    }
+
+    #[test]
+    fn audit_entries_are_appended_to_the_file() {
+        let log = temp_dir("audit").join("audit.log");
+        let audit = BodyAudit {
+            paths: vec!["/echo".to_string()],
+            content_types: Vec::new(),
+            max_bytes: 4096,
+            sink: AuditSink::File(log.clone()),
+        };
+        let config = Config { audit_bodies: Some(audit), ..test_config() };
+        for _ in 0..2 {
+            exchange(&config, &test_router(), b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        }
+        let entries = fs::read_to_string(&log).unwrap();
+        let lines: Vec<&str> = entries.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", entries);
+        assert!(lines[0].ends_with("] POST /echo 5/5 bytes") && lines[1] == "hello", "{}", entries);
+    }
+
+    #[test]
+    fn only_compressible_types_are_gzipped() {
+        let mut router = test_router();
+        router.add("GET", "/typed/:ext", |request: &HttpRequest, _state: &ServerState| {
+            let content_type = match request.param("ext") {
+                Some("png") => "image/png",
+                Some("json") => "application/json; charset=utf-8",
+                Some("zip") => "application/zip",
+                _ => "text/csv",
+            };
+            HttpResponse::new(HTTP_OK).with_header("Content-Type", content_type).with_body(vec![b'a'; 4096])
+        });
+        let gzipped = |config: &Config, ext: &str| {
+            let request = format!("GET /typed/{} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n", ext);
+            exchange(config, &router, request.as_bytes()).contains("\r\nContent-Encoding: gzip\r\n")
+        };
+
+        let config = test_config();
+        assert!(gzipped(&config, "json"));
+        assert!(gzipped(&config, "csv"));
+        assert!(!gzipped(&config, "png"));
+        assert!(!gzipped(&config, "zip"));
+
+        let config = Config { compressible_types: vec!["image/png".to_string()], ..test_config() };
+        assert!(gzipped(&config, "png"));
+        assert!(!gzipped(&config, "json"));
+    }
+
+    #[test]
+    fn the_config_endpoint_needs_auth_and_hides_the_token() {
+        let config = Arc::new(Config { auth_token: "t0p-s3cret".to_string(), ..test_config() });
+        let mut router = test_router();
+        router.wrap(authenticate);
+        let served = Arc::clone(&config);
+        router.add_without_body("GET", "/admin/config", move |request: &HttpRequest, _state: &ServerState| {
+            admin_config(request, &served)
+        });
+
+        let response = exchange(&config, &router, b"GET /admin/config HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 401 "), "{}", response);
+        let response = exchange(&config, &router, b"GET /admin/config HTTP/1.1\r\nAuthorization: Bearer t0p-s3cret\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(!response.contains("t0p-s3cret"), "{}", response);
+
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let Ok(JsonValue::Object(members)) = parse_json(body.as_bytes(), &JsonLimits::default()) else {
+            panic!("not a JSON object: {}", body);
+        };
+        let member = |name: &str| members.iter().find(|(key, _)| key == name).map(|(_, value)| value.to_string());
+        assert_eq!(member("auth_token").as_deref(), Some("\"[redacted]\""));
+        assert_eq!(member("bind_addr"), Some(format!("{:?}", config.bind_addr)));
+        assert_eq!(member("max_connections"), Some(config.max_connections.to_string()));
+        assert_eq!(member("max_connections_per_ip").as_deref(), Some("null"));
+        assert!(member("timeouts").is_some_and(|timeouts| timeouts.contains("\"request\":30,")), "{}", body);
+        assert!(member("limits").is_some_and(|limits| limits.contains("\"max_body\":1048576")), "{}", body);
+    }
+
+    #[test]
+    fn the_config_endpoint_is_off_by_default_and_never_under_the_default_token() {
+        assert!(!Config::default().config_endpoint);
+        let state = Arc::new(ServerState::new());
+        let admin_config = |auth_token: &str| {
+            let config = Arc::new(Config { auth_token: auth_token.to_string(), config_endpoint: true, ..test_config() });
+            let router = server_router(&config, Arc::clone(&state)).unwrap();
+            let request = format!("GET /admin/config HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n", auth_token);
+            exchange(&config, &router, request.as_bytes())
+        };
+        let response = admin_config("t0p-s3cret");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let response = admin_config(DEFAULT_AUTH_TOKEN);
+        assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+    }
+
+    #[test]
+    fn each_phase_of_a_request_is_timed() {
+        let mut router = test_router();
+        router.add("POST", "/slow", |request: &HttpRequest, _state: &ServerState| {
+            thread::sleep(Duration::from_millis(80));
+            HttpResponse::new(HTTP_OK).with_body(request.body.clone())
+        });
+        let config = test_config();
+        let (mut client, server) = socket_pair();
+        thread::scope(|scope| {
+            scope.spawn(|| Connection::new(server, &config, &router, &router.state).run());
+            client.write_all(b"POST /slow HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\n").unwrap();
+            // The body phase starts once the server has the head, a little
+            // after this sleep does
+            thread::sleep(Duration::from_millis(60));
+            client.write_all(b"hello").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.ends_with("hello"), "{}", response);
+        });
+
+        let [headers, body, handler, write] = router.state.phase_seconds.each_ref()
+            .map(|histogram| histogram.counts.read(|counts| (counts.count, counts.sum)));
+        assert!([headers, body, handler, write].iter().all(|&(count, _)| count == 1));
+        assert!(body.1 >= 0.04 && handler.1 >= 0.08, "body {}s, handler {}s", body.1, handler.1);
+        assert!(headers.1 < body.1 && write.1 < body.1, "headers {}s, write {}s", headers.1, write.1);
+        let metrics = metrics(&parse("GET /metrics HTTP/1.1\r\n\r\n").unwrap(), &router.state);
+        let metrics = String::from_utf8(metrics.body).unwrap();
+        assert!(metrics.contains("\nkp_http_request_phase_seconds_count{phase=\"handler\"} 1\n"), "{}", metrics);
+    }
+
+    #[test]
+    fn allowed_methods_come_from_the_routes() {
+        let mut router = test_router();
+        router.add("PUT", "/items/:id", echo_body);
+        router.add("GET", "/items/:id", echo_body);
+        router.add("DELETE", "/items/:id", echo_body);
+        router.add("GET", "/about", echo_body);
+        assert_eq!(router.allowed_methods("/items/1"), ["PUT", "GET", "DELETE", "HEAD"]);
+        assert_eq!(router.allowed_methods("/about"), ["GET", "HEAD"]);
+        assert_eq!(router.allowed_methods("/echo"), ["POST"]);
+        assert!(router.allowed_methods("/nothing").is_empty());
+        router.auto_head(false).add("HEAD", "/echo", echo_body);
+        assert_eq!(router.allowed_methods("/about"), ["GET"]);
+        assert_eq!(router.allowed_methods("/echo"), ["POST", "HEAD"]);
+
+        // The same list answers a 405 and OPTIONS
+        let router = test_router();
+        let response = exchange(&test_config(), &router, b"PATCH / HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"), "{}", response);
+        let response = exchange(&test_config(), &router, b"OPTIONS / HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn folded_header_lines_are_refused() {
+        for continuation in [" folded", "\tfolded", "  "] {
+            let raw = format!("GET / HTTP/1.1\r\nX-Long: first\r\n{}\r\nHost: x\r\n\r\n", continuation);
+            assert_eq!(parse(&raw).unwrap_err(), ParseError::FoldedHeader, "{:?}", continuation);
+        }
+        let response = exchange(&test_config(), &test_router(), b"GET / HTTP/1.1\r\nX-Long: first\r\n second\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn html_is_injected_before_the_closing_body_tag() {
+        let config = Config {
+            response_interceptors: vec![ResponseInterceptor::inject_html("<script></script>")],
+            ..test_config()
+        };
+        let mut router = test_router();
+        let page = |body: &'static str| move |_request: &HttpRequest, _state: &ServerState| {
+            HttpResponse::new(HTTP_OK).with_header("Content-Type", "text/html").with_body(body.as_bytes().to_vec())
+        };
+        router.add("GET", "/page", page("<html><body><p>hi</p></BODY></html>"));
+        router.add("GET", "/bare", page("<p>hi</p>"));
+
+        let response = exchange(&config, &router, b"GET /page HTTP/1.1\r\n\r\n");
+        let body = "<html><body><p>hi</p><script></script></BODY></html>";
+        assert!(response.contains(&format!("\r\nContent-Length: {}\r\n", body.len())), "{}", response);
+        assert!(response.ends_with(&format!("\r\n\r\n{}", body)), "{}", response);
+        let response = exchange(&config, &router, b"GET /bare HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\n<p>hi</p><script></script>"), "{}", response);
+
+        // Other types are left alone
+        let response = exchange(&config, &router, b"POST /echo HTTP/1.1\r\nContent-Length: 7\r\n\r\n</body>");
+        assert!(response.ends_with("\r\n\r\n</body>"), "{}", response);
+    }
+
+    #[test]
+    fn slow_requests_get_the_read_timeout_and_idle_waits_the_idle_one() {
+        let config = Config {
+            idle_timeout: Duration::from_millis(150),
+            read_timeout: Duration::from_secs(2),
+            ..test_config()
+        };
+        let router = test_router();
+        let (mut client, server) = socket_pair();
+        thread::scope(|scope| {
+            let connection = scope.spawn(|| Connection::new(server, &config, &router, &router.state).run());
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            assert!(received(&mut client).starts_with("HTTP/1.1 200 OK\r\n"));
+
+            // Once the next request has started, pauses longer than the idle
+            // timeout don't end it
+            for piece in ["P", "OST /echo HTTP/1.1\r\n", "Content-Length: 2\r\n\r\n", "hi"] {
+                client.write_all(piece.as_bytes()).unwrap();
+                thread::sleep(Duration::from_millis(250));
+            }
+            let response = received(&mut client);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\n\r\nhi"), "{}", response);
+
+            // Waiting for another one does
+            let started = Instant::now();
+            connection.join().unwrap();
+            assert!(started.elapsed() < Duration::from_secs(1), "closed after {:?}", started.elapsed());
+            assert_eq!(received(&mut client), "");
+        });
+    }
+
+    #[test]
+    fn zero_or_unset_worker_threads_are_sized_from_the_cpus() {
+        let per_cpu = thread::available_parallelism().map_or(1, |cpus| cpus.get()) * 4;
+        assert_eq!(Config { worker_threads: None, ..test_config() }.worker_count(), per_cpu);
+        assert_eq!(Config { worker_threads: Some(0), ..test_config() }.worker_count(), per_cpu);
+        assert_eq!(Config { worker_threads: Some(3), ..test_config() }.worker_count(), 3);
+    }
+
+    #[test]
+    fn errors_follow_the_configured_format() {
+        let format = |name: &str| test_config().with_env(|var| (var == "KP_HTTP_ERROR_FORMAT").then(|| name.to_string()));
+        let response = exchange(&format("problem"), &test_router(), b"GET /missing HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+        assert!(response.contains("\r\nContent-Type: application/problem+json\r\n"), "{}", response);
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        let Ok(JsonValue::Object(members)) = parse_json(body.as_bytes(), &JsonLimits::default()) else {
+            panic!("not a JSON object: {}", body);
+        };
+        let member = |name: &str| members.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+        assert_eq!(member("status"), Some(JsonValue::Number(404.0)));
+        assert_eq!(member("title"), Some(JsonValue::String("Not Found".to_string())));
+        assert_eq!(member("type"), Some(JsonValue::String("about:blank".to_string())));
+
+        let response = exchange(&format("json"), &test_router(), b"DELETE / HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\n{\"error\":\"Method Not Allowed\",\"status\":405}"), "{}", response);
+        assert_eq!(format("yaml").error_format, ErrorFormat::Text);
+    }
+
+    #[test]
+    fn json_bodies_are_parsed_as_they_are_read() {
+        let config = Arc::new(Config { json_limits: Some(JsonLimits { max_depth: 4, max_size: 1024 * 1024 }), ..test_config() });
+        let mut router = test_router();
+        // Send the document back as the server parsed it, in compact form
+        let echo_json = |_request: &HttpRequest, document: JsonValue, _state: &ServerState| {
+            HttpResponse::new(HTTP_OK).with_body(document.to_string().into_bytes())
+        };
+        router.add("POST", "/json", Typed::new(Arc::clone(&config), echo_json));
+        let post = |body: &str| {
+            let request = format!(
+                "POST /json HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(), body,
+            );
+            exchange(&config, &router, request.as_bytes())
+        };
+
+        let response = post(" { \"a\" : [1, 2.5, true, null], \"b\": \"\\u00e9\" } ");
+        assert!(response.ends_with("\r\n\r\n{\"a\":[1,2.5,true,null],\"b\":\"\u{e9}\"}"), "{}", response);
+        let large = format!("[{}0]", "0,".repeat(100_000));
+        let response = post(&large);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with(",0,0]"), "{}", &response[..100]);
+
+        for bad in ["[[[[[1]]]]]", "{\"a\":}", "[1] [2]"] {
+            let response = post(bad);
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}: {}", bad, response);
+        }
+        let response = exchange(&config, &router, b"POST /json HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}");
+        assert!(response.starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn required_headers_gate_their_prefix() {
+        let mut router = test_router();
+        router.add("GET", "/admin/config", index);
+        router.add("GET", "/administrator", index);
+        router.require_header("/admin/", "X-Internal", "true");
+        let get = |request: &str| exchange(&test_config(), &router, request.as_bytes());
+
+        for path in ["/admin", "/admin/config", "/admin/missing"] {
+            let response = get(&format!("GET {} HTTP/1.1\r\n\r\n", path));
+            assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}: {}", path, response);
+            let response = get(&format!("GET {} HTTP/1.1\r\nX-Internal: yes\r\n\r\n", path));
+            assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}: {}", path, response);
+        }
+        let response = get("GET /admin/config HTTP/1.1\r\nx-internal: true\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let response = get("GET /admin/missing HTTP/1.1\r\nX-Internal: true\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+        // Only whole segments are under the prefix
+        assert!(get("GET /administrator HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn tls_only_routes_ask_plain_requests_to_upgrade() {
+        let config = Config { tls_only_prefixes: vec!["/echo/".to_string()], ..test_config() };
+        let mut router = test_router();
+        router.wrap(require_tls);
+
+        let response = exchange(&config, &router, b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi");
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"), "{}", response);
+        assert!(response.contains("\r\nUpgrade: TLS/1.2, HTTP/1.1\r\nConnection: upgrade\r\n"), "{}", response);
+
+        let request = b"POST /echo HTTP/1.1\r\nX-Forwarded-Proto: HTTPS\r\nContent-Length: 2\r\n\r\nhi";
+        let response = exchange(&config, &router, request);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\n\r\nhi"), "{}", response);
+        let response = exchange(&config, &router, b"GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn requests_per_connection_are_recorded_when_it_closes() {
+        let mut router = test_router();
+        router.add_without_body("GET", "/metrics", metrics);
+        exchange(&test_config(), &router, &b"GET / HTTP/1.1\r\n\r\n".repeat(3));
+        let recorded = router.state.connection_requests.counts.read(|counts| (counts.count, counts.sum));
+        assert_eq!(recorded, (1, 3.0));
+
+        let response = exchange(&test_config(), &router, b"GET /metrics HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.contains("\nkp_http_connection_requests_count 1\n"), "{}", response);
+        assert!(response.contains("\nkp_http_connection_requests_sum 3\n"), "{}", response);
+        assert_eq!(router.state.connection_requests.counts.read(|counts| (counts.count, counts.sum)), (2, 4.0));
+    }
+
+    #[test]
+    fn upgrade_required_names_the_protocol() {
+        let mut router = test_router();
+        router.add("GET", "/secure", |_request: &HttpRequest, _state: &ServerState| {
+            upgrade_required(&test_config(), "TLS/1.2, HTTP/1.1")
+        });
+
+        let response = exchange(&test_config(), &router, b"GET /secure HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"), "{}", response);
+        assert!(response.contains("\r\nUpgrade: TLS/1.2, HTTP/1.1\r\n"), "{}", response);
+        // The connection's own option joins the handler's rather than
+        // making a second header
+        assert!(response.contains("\r\nConnection: upgrade, close\r\n"), "{}", response);
+        assert_eq!(response.matches("\r\nConnection:").count(), 1, "{}", response);
+    }
+
+    #[test]
+    fn connections_over_their_byte_budget_are_closed() {
+        let config = Config { max_connection_bytes: Some(100), ..test_config() };
+        // 36 bytes each, so the third request takes the connection over
+        let request = b"GET / HTTP/1.1\r\nX-Padding: 12345\r\n\r\n";
+        assert_eq!(request.len(), 36);
+        let responses = exchange(&config, &test_router(), &request.repeat(4));
+        assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 3, "{}", responses);
+        let last = responses.rsplit("HTTP/1.1 200 OK\r\n").next().unwrap();
+        assert!(last.contains("\r\nConnection: close\r\n"), "{}", last);
+        assert_eq!(responses.matches("Connection: close").count(), 1, "{}", responses);
+    }
+
+    #[test]
+    fn method_not_allowed_lists_what_is() {
+        let response = method_not_allowed(&test_config(), &["GET", "POST"]);
+        assert_eq!(response.status_code(), 405);
+        assert!(response.headers.contains(&("Allow".to_string(), "GET, POST".to_string())), "{:?}", response.headers);
+        let response = method_not_allowed(&test_config(), &[]);
+        assert!(response.headers.contains(&("Allow".to_string(), String::new())), "{:?}", response.headers);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn listeners_can_share_a_port_with_reuse_port() {
+        let config = Config { bind_addr: "127.0.0.1:0".to_string(), reuse_port: true, ..test_config() };
+        let first = bind_listener(&config).unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+
+        let second = bind_listener(&Config { bind_addr: addr.clone(), reuse_port: true, ..test_config() }).unwrap();
+        assert_eq!(second.local_addr().unwrap(), first.local_addr().unwrap());
+        let refused = bind_listener(&Config { bind_addr: addr, reuse_port: false, ..test_config() }).unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn text_responses_default_to_utf8() {
+        let content_type = |content_type: &str, body: &[u8]| {
+            let mut response = HttpResponse::new(HTTP_OK).with_header("Content-Type", content_type).with_body(body.to_vec());
+            response.set_default_charset();
+            response.headers[0].1.clone()
+        };
+        assert_eq!(content_type("text/html", b"<p>hi</p>"), "text/html; charset=utf-8");
+        assert_eq!(content_type("application/json", b"{}"), "application/json; charset=utf-8");
+        assert_eq!(content_type("text/html; charset=iso-8859-1", b"caf\xe9"), "text/html; charset=iso-8859-1");
+        assert_eq!(content_type("text/plain;Charset=UTF-8", b"x"), "text/plain;Charset=UTF-8");
+        // Bodies that aren't UTF-8, and other types, are left alone
+        assert_eq!(content_type("text/plain", b"caf\xe9"), "text/plain");
+        assert_eq!(content_type("image/svg+xml", b"<svg/>"), "image/svg+xml");
+
+        let mut router = test_router();
+        router.add("GET", "/page", |_request: &HttpRequest, _state: &ServerState| {
+            HttpResponse::new(HTTP_OK).with_header("Content-Type", "text/html").with_body(b"<p>hi</p>".to_vec())
+        });
+        let response = exchange(&test_config(), &router, b"GET /page HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn route_params_are_captured_per_segment() {
+        assert_eq!(
+            match_route("/users/:id/files/:name", "/users/7/files/a%2Fb?x=1"),
+            Some(vec![("id".to_string(), "7".to_string()), ("name".to_string(), "a/b".to_string())])
+        );
+        assert_eq!(match_route("/users/:id", "/users/"), None);
+        assert_eq!(match_route("/users/:id", "/users/7/more"), None);
+        assert_eq!(match_route("/users/:id", "/groups/7"), None);
+    }
+
+    #[test]
+    fn handlers_read_route_params() {
+        let mut router = test_router();
+        router.add("GET", "/hello/:name", |request: &HttpRequest, _state: &ServerState| {
+            let name = request.param("name").unwrap_or_default();
+            HttpResponse::new(HTTP_OK).with_body(format!("Hello, {}!", name).into_bytes())
+        });
+        let response = exchange(&test_config(), &router, b"GET /hello/J%C3%BCrgen%20K HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\nHello, J\u{fc}rgen K!"), "{}", response);
+        let response = exchange(&test_config(), &router, b"GET /hello/ HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn robots_txt_is_served_unless_turned_off() {
+        let state = Arc::new(ServerState::new());
+        let robots = |robots_txt: Option<&str>| {
+            let config = Arc::new(Config { robots_txt: robots_txt.map(str::to_string), ..test_config() });
+            let router = server_router(&config, Arc::clone(&state)).unwrap();
+            exchange(&config, &router, b"GET /robots.txt HTTP/1.1\r\n\r\n")
+        };
+        let response = robots(Some(ROBOTS_TXT));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nContent-Type: text/plain; charset=utf-8\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nUser-agent: *\nDisallow:\n"), "{}", response);
+        assert_eq!(Config::default().robots_txt.as_deref(), Some(ROBOTS_TXT));
+
+        let response = robots(Some("User-agent: *\nDisallow: /admin\n"));
+        assert!(response.ends_with("\r\n\r\nUser-agent: *\nDisallow: /admin\n"), "{}", response);
+        assert!(robots(None).starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn typed_handlers_get_their_parsed_form() {
+        // A form naming who to greet
+        struct GreetingForm {
+            name: String,
+        }
+
+        impl FromBody for GreetingForm {
+            fn from_body(request: &HttpRequest) -> Result<Self, HandlerError> {
+                let form = Form::from_body(request)?;
+                let name = form.get("name").filter(|name| !name.is_empty()).ok_or(HandlerError::Malformed)?;
+                Ok(GreetingForm { name: name.to_string() })
+            }
+        }
+
+        let config = Arc::new(test_config());
+        let mut router = test_router();
+        let greet = |_request: &HttpRequest, form: GreetingForm, _state: &ServerState| {
+            HttpResponse::new(HTTP_OK).with_body(format!("Hello, {}!", form.name).into_bytes())
+        };
+        router.add("POST", "/hello", Typed::new(Arc::clone(&config), greet));
+        let post = |content_type: &str, body: &str| {
+            let request = format!(
+                "POST /hello HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                content_type, body.len(), body,
+            );
+            exchange(&config, &router, request.as_bytes())
+        };
+
+        let form = "application/x-www-form-urlencoded";
+        let response = post(form, "greeting=hi&name=Ada+L%C3%B6vel%2B&name=second");
+        assert!(response.ends_with("\r\n\r\nHello, Ada L\u{f6}vel+!"), "{}", response);
+        let response = post(&format!("{}; charset=utf-8", form.to_uppercase()), "name=x");
+        assert!(response.ends_with("\r\n\r\nHello, x!"), "{}", response);
+
+        // The handler isn't called for other types or a form without a name
+        let response = post("application/json", "{\"name\":\"Ada\"}");
+        assert!(response.starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"), "{}", response);
+        for body in ["", "name=", "other=Ada"] {
+            let response = post(form, body);
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}: {}", body, response);
+        }
+    }
+
+    #[test]
+    fn methods_must_be_short_tokens() {
+        assert_eq!(parse("G(E)T / HTTP/1.1\r\n\r\n").unwrap_err(), ParseError::BadMethod);
+        let long = format!("{} / HTTP/1.1\r\n\r\n", "A".repeat(33));
+        assert_eq!(parse(&long).unwrap_err(), ParseError::BadMethod);
+        assert_eq!(parse("M-SEARCH * HTTP/1.1\r\n\r\n").unwrap().method, "M-SEARCH");
+    }
+
+    #[test]
+    fn strict_parsing_refuses_bare_lf_heads_without_waiting() {
+        let raw = b"GET / HTTP/1.1\nHost: x\n\n";
+        let mut buffer = Vec::new();
+        let limits = ParserLimits::default();
+        let head_end = read_head(&mut &raw[..], &mut buffer, &limits).unwrap();
+        assert_eq!(head_end, Some(raw.len()));
+        assert_eq!(HttpRequest::parse(&buffer, &limits).unwrap_err(), ParseError::BadHeader);
+
+        // Even after an otherwise proper request line
+        assert_eq!(parse("GET / HTTP/1.1\r\n\n").unwrap_err(), ParseError::BadHeader);
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost: x\nY: z\r\n\r\n").unwrap_err(), ParseError::BadHeader);
+        // Blank lines inside a body are the body's business
+        assert_eq!(parse("POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n\n\n").unwrap().body, b"\n\n");
+    }
+
+    #[test]
+    fn lenient_parsing_accepts_bare_lf() {
+        let limits = ParserLimits { lenient_parsing: true, ..ParserLimits::default() };
+        let request = HttpRequest::parse(b"GET /a HTTP/1.1\nHost: x\n\n", &limits).unwrap();
+        assert_eq!((request.path.as_str(), request.header("Host")), ("/a", Some("x")));
+    }
+
+    #[test]
+    fn long_targets_are_refused_before_decoding() {
+        let limits = ParserLimits { max_uri_length: 16, ..ParserLimits::default() };
+        let fits = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(15));
+        assert!(HttpRequest::parse(fits.as_bytes(), &limits).is_ok());
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(16));
+        assert_eq!(HttpRequest::parse(long.as_bytes(), &limits).unwrap_err(), ParseError::UriTooLong);
+
+        // Measured as sent, so an encoded NUL past the limit still counts as
+        // too long rather than a bad path
+        let encoded = format!("GET /{}%00 HTTP/1.1\r\n\r\n", "a".repeat(16));
+        assert_eq!(HttpRequest::parse(encoded.as_bytes(), &limits).unwrap_err(), ParseError::UriTooLong);
+        assert_eq!(ParseError::UriTooLong.status_line(), HTTP_URI_TOO_LONG);
+    }
+
+    #[test]
+    fn trace_is_refused_everywhere_by_default() {
+        let (config, router) = (test_config(), test_router());
+        let response = exchange(&config, &router, b"TRACE / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 "), "{}", response);
+        assert!(response.contains("Allow: GET, HEAD, OPTIONS\r\n"), "{}", response);
+
+        // Not a 404, even where no route exists
+        let response = exchange(&config, &router, b"TRACE /nothing HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 "), "{}", response);
+        assert!(response.contains("Allow: \r\n"), "{}", response);
+    }
+
+    #[test]
+    fn trace_echoes_the_head_when_enabled() {
+        let config = Config { trace_enabled: true, ..test_config() };
+        let raw = b"TRACE /nothing HTTP/1.1\r\nHost: x\r\nX-Probe: 1\r\nCookie: id=secret\r\n\r\n";
+        let response = exchange(&config, &test_router(), raw);
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(response.contains("Content-Type: message/http"), "{}", response);
+        assert!(response.contains("\r\n\r\nTRACE /nothing HTTP/1.1\r\nHost: x\r\nX-Probe: 1\r\n"), "{}", response);
+        assert!(!response.contains("secret"), "{}", response);
+    }
+
+    #[test]
+    fn locked_state_loses_no_updates_under_concurrent_connections() {
+        const CONNECTIONS: usize = 32;
+        const REQUESTS: usize = 200;
+        let mut router = Router::with_state(Arc::new(Locked::new(0u64)));
+        // Read and write back in separate steps, with a yield between them to
+        // widen the window, which would lose updates without the lock
+        router.add("POST", "/hit", |_request: &HttpRequest, hits: &Locked<u64>| {
+            hits.write(|hits| {
+                let seen = *hits;
+                thread::yield_now();
+                *hits = seen + 1;
+            });
+            HttpResponse::new(HTTP_NO_CONTENT)
+        });
+        let (config, server) = (test_config(), ServerState::new());
+
+        thread::scope(|scope| {
+            for _ in 0..CONNECTIONS {
+                let (mut client, stream) = socket_pair();
+                let (config, router, server) = (&config, &router, &server);
+                scope.spawn(move || Connection::new(stream, config, router, server).run());
+                scope.spawn(move || {
+                    client.write_all(&b"POST /hit HTTP/1.1\r\n\r\n".repeat(REQUESTS)).unwrap();
+                    client.shutdown(std::net::Shutdown::Write).unwrap();
+                    let mut responses = String::new();
+                    client.read_to_string(&mut responses).unwrap();
+                    assert_eq!(responses.matches("HTTP/1.1 204 No Content\r\n").count(), REQUESTS);
+                });
+            }
+        });
+        assert_eq!(router.state.read(|hits| *hits), (CONNECTIONS * REQUESTS) as u64);
+    }
+
+    #[test]
+    fn default_headers_reach_every_response() {
+        let default_headers = vec![("Strict-Transport-Security".to_string(), "max-age=63072000".to_string())];
+        let config = Config { default_headers, ..test_config() };
+        let router = test_router();
+        for raw in [&b"GET / HTTP/1.1\r\n\r\n"[..], b"GET /missing HTTP/1.1\r\n\r\n", b"BAD\r\n\r\n"] {
+            let response = exchange(&config, &router, raw);
+            assert!(response.contains("\r\nStrict-Transport-Security: max-age=63072000\r\n"), "{}", response);
+        }
+    }
+
+    #[test]
+    fn control_characters_in_paths_are_refused() {
+        for path in ["/a%00b", "/a%0d%0aSet-Cookie:%20x", "/%1b[31m", "/a%7f", "/a\x01b", "http://host/a%09"] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            assert_eq!(parse(&raw).map(|_| ()), Err(ParseError::BadPath), "{:?}", path);
+        }
+        // Escapes of printable characters, and control characters in the
+        // query, which handlers decode themselves, are fine
+        assert_eq!(parse("GET /a%20b%2F HTTP/1.1\r\n\r\n").unwrap().path, "/a%20b%2F");
+        assert!(parse("GET /search?q=%00 HTTP/1.1\r\n\r\n").is_ok());
+
+        let response = exchange(&test_config(), &test_router(), b"GET /%00 HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn http10_bodies_without_a_length_run_until_the_close() {
+        let config = test_config();
+        let router = test_router();
+        let response = exchange(&config, &router, b"POST /echo HTTP/1.0\r\n\r\nsent in\r\ntwo parts");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nConnection: close\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nsent in\r\ntwo parts"), "{}", response);
+
+        // Only for HTTP/1.0 methods that carry a body, sent without a length
+        assert!(parse("PUT / HTTP/1.0\r\n\r\n").unwrap().body_until_close());
+        for raw in ["GET / HTTP/1.0\r\n\r\n", "POST / HTTP/1.1\r\n\r\n", "POST / HTTP/1.0\r\nContent-Length: 0\r\n\r\n"] {
+            assert!(!parse(raw).unwrap().body_until_close(), "{:?}", raw);
+        }
+        let response = exchange(&config, &router, b"POST /echo HTTP/1.1\r\nConnection: close\r\n\r\nnot a body");
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+
+        // Still held to the body limit
+        let config = Config { limits: ParserLimits { max_body: 8, ..ParserLimits::default() }, ..test_config() };
+        let response = exchange(&config, &router, b"POST /echo HTTP/1.0\r\n\r\nmore than eight bytes");
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+    }
+
+    fn command_router(program: &str, args: &[&str]) -> Router<ServerState> {
+        let mut router = test_router();
+        let subprocess = args.iter().fold(Subprocess::new(Arc::new(test_config()), program), |subprocess, arg| subprocess.arg(arg));
+        router.add("POST", "/run", subprocess);
+        router
+    }
+
+    #[test]
+    fn command_output_is_streamed_chunked() {
+        let router = command_router("echo", &["hello"]);
+        let response = exchange(&test_config(), &router, b"POST /run HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n"), "{}", response);
+        assert!(!response.contains("Content-Length"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n6\r\nhello\n\r\n0\r\n\r\n"), "{}", response);
+
+        let mut router = test_router();
+        let subprocess = Subprocess::new(Arc::new(test_config()), "echo").arg("{}").content_type("application/json");
+        router.add("POST", "/run", subprocess);
+        let response = exchange(&test_config(), &router, b"POST /run HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nContent-Type: application/json\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n3\r\n{}\n\r\n0\r\n\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn commands_read_the_request_body() {
+        let router = command_router("cat", &[]);
+        let request = b"POST /run HTTP/1.1\r\nContent-Length: 5\r\n\r\ninputPOST /run HTTP/1.1\r\n\r\n";
+        let response = exchange(&test_config(), &router, request);
+        // The connection stays usable after a chunked body
+        assert!(response.contains("\r\n\r\n5\r\ninput\r\n0\r\n\r\nHTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn command_output_to_http_1_0_ends_with_the_connection() {
+        let router = command_router("echo", &["hello"]);
+        let response = exchange(&test_config(), &router, b"POST /run HTTP/1.0\r\nContent-Length: 0\r\nConnection: keep-alive\r\n\r\n");
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        assert!(!response.contains("Transfer-Encoding") && !response.contains("Content-Length"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello\n"), "{}", response);
+    }
+
+    #[test]
+    fn command_exit_codes_set_the_status_until_output_is_sent() {
+        let router = command_router("false", &[]);
+        let response = exchange(&test_config(), &router, b"POST /run HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", response);
+
+        let router = command_router("true", &[]);
+        let response = exchange(&test_config(), &router, b"POST /run HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Length: 0\r\n"), "{}", response);
+
+        let router = command_router("kp-http-no-such-command", &[]);
+        let response = exchange(&test_config(), &router, b"POST /run HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", response);
+
+        // After output, failing cuts the chunked body off
+        let router = command_router("sh", &["-c", "echo partial; exit 3"]);
+        let response = exchange(&test_config(), &router, b"POST /run HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n8\r\npartial\n\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn streamed_bodies_are_cut_off_at_the_response_limit() {
+        let config = Config { max_response_bytes: Some(100_000), ..test_config() };
+        let router = command_router("yes", &[]);
+        let response = exchange(&config, &router, b"POST /run HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", &response[..200]);
+        assert!(response.len() < 120_000, "{} bytes sent", response.len());
+        assert!(!response.ends_with("0\r\n\r\n"));
+    }
+
+    #[test]
+    fn headers_are_added_in_bulk_in_order() {
+        let headers = [("X-One", "1"), ("X-Two", "2"), ("X-Three", "3")]
+            .map(|(key, value)| (key.to_string(), value.to_string()));
+        let response = HttpResponse::new(HTTP_OK).with_header("X-Zero", "0").with_headers(headers).build();
+        let response = String::from_utf8(response).unwrap();
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nX-Zero: 0\r\nX-One: 1\r\nX-Two: 2\r\nX-Three: 3\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn default_headers_only_fill_in_what_the_handler_left_out() {
+        let default_headers = [("X-Frame-Options", "DENY"), ("X-Server", "kp")]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .to_vec();
+        let config = Config { default_headers, ..test_config() };
+        let mut out = Vec::new();
+        assert!(send_response(&mut out, HttpResponse::new(HTTP_OK).with_header("x-server", "mine"), &config));
+        let response = String::from_utf8(out).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nx-server: mine\r\nX-Frame-Options: DENY\r\n"), "{}", response);
+        assert!(!response.contains("X-Server: kp"), "{}", response);
+    }
+
+    #[test]
+    fn automatic_head_and_options_can_be_turned_off() {
+        let mut router = test_router();
+        let response = exchange(&test_config(), &router, b"HEAD / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\n\r\n"), "{}", response);
+        let response = exchange(&test_config(), &router, b"OPTIONS / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+        assert!(response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"), "{}", response);
+
+        router.auto_head(false).auto_options(false);
+        let response = exchange(&test_config(), &router, b"HEAD / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+        assert!(response.contains("\r\nAllow: GET\r\n"), "{}", response);
+        let response = exchange(&test_config(), &router, b"OPTIONS / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+
+        // Routes of their own still answer
+        router.add("HEAD", "/", |_request: &HttpRequest, _state: &ServerState| HttpResponse::new(HTTP_NO_CONTENT));
+        let response = exchange(&test_config(), &router, b"HEAD / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn rate_limited_clients_are_disconnected() {
+        let mut router = test_router();
+        router.wrap(RateLimit::new(2, Duration::from_secs(60)));
+        let responses = exchange(&test_config(), &router, &b"GET / HTTP/1.1\r\n\r\n".repeat(4));
+        assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 2, "{}", responses);
+        let (_, limited) = responses.split_once("HTTP/1.1 429 Too Many Requests\r\n").expect(&responses);
+        assert!(limited.contains("\r\nConnection: close\r\n") && limited.contains("\r\nRetry-After: 60\r\n"), "{}", limited);
+        // The fourth request is never answered
+        assert!(!limited.contains("HTTP/1.1"), "{}", limited);
+    }
+
+    #[test]
+    fn handlers_can_close_the_connection() {
+        let mut router = test_router();
+        router.add("GET", "/bye", |request: &HttpRequest, _state: &ServerState| {
+            request.close_connection();
+            HttpResponse::new(HTTP_NO_CONTENT)
+        });
+        let request = b"GET / HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /bye HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let responses = exchange(&test_config(), &router, request);
+        assert_eq!(responses.matches("HTTP/1.1 ").count(), 2, "{}", responses);
+        assert!(responses.ends_with("HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n"), "{}", responses);
+    }
+
+    #[test]
+    fn connections_are_capped_per_client_ip() {
+        let state = Arc::new(ServerState::new());
+        let (client, other): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let first = IpConnectionGuard::acquire(&state, client, 1).unwrap();
+        assert!(IpConnectionGuard::acquire(&state, client, 1).is_none());
+        let second = IpConnectionGuard::acquire(&state, other, 1).unwrap();
+        assert!(IpConnectionGuard::acquire(&state, client, 2).is_some());
+
+        // Closing a connection frees its slot, and IPs left without any are
+        // forgotten
+        drop(first);
+        drop(second);
+        assert!(state.connections_per_ip.lock().unwrap().is_empty());
+        assert!(IpConnectionGuard::acquire(&state, client, 1).is_some());
+    }
+
+    #[test]
+    fn close_delimited_bodies_have_no_length_and_end_the_connection() {
+        let mut router = test_router();
+        router.add("GET", "/stream", |_request: &HttpRequest, _state: &ServerState| {
+            HttpResponse::new(HTTP_OK).with_reader(io::Cursor::new(b"streamed".to_vec()))
+        });
+        router.add("GET", "/closed", |_request: &HttpRequest, _state: &ServerState| {
+            HttpResponse::new(HTTP_OK).with_body(b"buffered".to_vec()).close_delimited()
+        });
+        let config = test_config();
+
+        // HTTP/1.0 clients get a body of unknown length up to the close,
+        // even when asking for keep-alive
+        let response = exchange(&config, &router, b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nConnection: close"), "{}", response);
+        assert!(!head.contains("Content-Length") && !head.contains("Transfer-Encoding"), "{}", response);
+        assert_eq!(body, "streamed");
+        // HTTP/1.1 clients get it chunked
+        let response = exchange(&config, &router, b"GET /stream HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nTransfer-Encoding: chunked\r\n"), "{}", response);
+
+        // Handlers can ask for it too, and the request after it isn't read
+        let response = exchange(&config, &router, b"GET /closed HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nConnection: close") && !head.contains("Content-Length"), "{}", response);
+        assert_eq!(body, "buffered");
+    }
+
+    #[test]
+    fn invalid_content_length_is_refused() {
+        for value in ["-5", "abc", "+5", "5 5", "", "0x10"] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", value);
+            assert_eq!(parse(&raw).unwrap_err(), ParseError::BadContentLength, "accepted {:?}", value);
+        }
+    }
+
+    #[test]
+    fn conflicting_content_lengths_are_refused() {
+        let duplicate = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n";
+        assert_eq!(parse(duplicate).unwrap_err(), ParseError::BadContentLength);
+        let listed = "POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n";
+        assert_eq!(parse(listed).unwrap_err(), ParseError::BadContentLength);
+
+        // Repeating the same length is harmless
+        let repeated = parse("POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5, 5\r\n\r\nhello").unwrap();
+        assert_eq!(repeated.content_length(), 5);
+    }
+
+    #[test]
+    fn cookies_are_split_trimmed_and_unquoted() {
+        let request = parse("GET / HTTP/1.1\r\nCookie: a=1;b=2 ;  c = \"x y\" ;=anon; flag; d=\"open\r\ncookie: e=\"\"; a=3\r\n\r\n").unwrap();
+        assert_eq!(request.cookies(), [("a", "1"), ("b", "2"), ("c", "x y"), ("d", "\"open"), ("e", ""), ("a", "3")]);
+        // The first of several with the same name wins
+        assert_eq!(request.cookie("a"), Some("1"));
+        assert_eq!(request.cookie("flag"), None);
+        assert!(parse("GET / HTTP/1.1\r\n\r\n").unwrap().cookies().is_empty());
+    }
+}
@@ -0,0 +1,291 @@
+use std::io::{self, Read, Write};
+
+use crate::{find_subsequence, ParserLimits};
+
+// Why a multipart body couldn't be read
+#[derive(Debug)]
+pub enum MultipartError {
+    // Reading the body or writing a part to its writer failed
+    Io(io::Error),
+    // Missing or bad delimiter, bad part header, or the body ended before
+    // the closing delimiter
+    Malformed,
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(e: io::Error) -> Self {
+        MultipartError::Io(e)
+    }
+}
+
+// Streaming reader for a `multipart/form-data` (or other `multipart/*`)
+// body. Each call to `next_part` yields a part's headers, and `copy_body`
+// then streams that part's body to a writer, so only a few kilobytes of
+// the body are held at once however large the parts are:
+//
+//     while let Some(headers) = parts.next_part()? {
+//         parts.copy_body(&mut file)?;
+//     }
+//
+// A part whose body isn't copied is skipped by the next `next_part`.
+pub struct Multipart<'a, R> {
+    reader: R,
+    limits: &'a ParserLimits,
+    // `CRLF--boundary`, which ends every part body and the preamble
+    delimiter: Vec<u8>,
+    // Bytes read from `reader` and not yet consumed
+    buffer: Vec<u8>,
+    state: State,
+}
+
+enum State {
+    // Inside the preamble or a part body, before its delimiter
+    Body,
+    // Just past a delimiter, before the part headers or the closing `--`
+    Delimiter,
+    // Past the closing delimiter, the epilogue is ignored
+    Done,
+}
+
+impl<'a, R: Read> Multipart<'a, R> {
+    pub fn new(reader: R, boundary: &str, limits: &'a ParserLimits) -> Self {
+        Multipart {
+            reader,
+            limits,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // The first delimiter may open the body with no CRLF before it,
+            // so the preamble is read as a body that starts with one
+            buffer: b"\r\n".to_vec(),
+            state: State::Body,
+        }
+    }
+
+    // Headers of the next part, or `None` after the last one. Any part body
+    // not yet copied is skipped first.
+    pub fn next_part(&mut self) -> Result<Option<Vec<(String, String)>>, MultipartError> {
+        if let State::Body = self.state {
+            self.copy_body(&mut io::sink())?;
+        }
+        if let State::Done = self.state {
+            return Ok(None);
+        }
+
+        // `--` closes the body, anything else is optional padding and the
+        // CRLF ending the delimiter line
+        self.fill(2)?;
+        if self.buffer.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        let line = self.read_line()?;
+        if !line.iter().all(|&byte| byte == b' ' || byte == b'\t') {
+            return Err(MultipartError::Malformed);
+        }
+
+        let mut headers = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() >= self.limits.max_headers || line.starts_with(b" ") || line.starts_with(b"\t") {
+                return Err(MultipartError::Malformed);
+            }
+            let line = String::from_utf8_lossy(&line);
+            let (key, value) = line.split_once(':').ok_or(MultipartError::Malformed)?;
+            headers.push((key.trim().to_string(), value.trim_matches([' ', '\t']).to_string()));
+        }
+
+        self.state = State::Body;
+        Ok(Some(headers))
+    }
+
+    // Write the body of the part `next_part` just returned to `out`,
+    // returning its length. Does nothing between parts.
+    pub fn copy_body(&mut self, out: &mut impl Write) -> Result<u64, MultipartError> {
+        let State::Body = self.state else {
+            return Ok(0);
+        };
+
+        let mut written = 0;
+        loop {
+            if let Some(pos) = find_subsequence(&self.buffer, &self.delimiter) {
+                out.write_all(&self.buffer[..pos])?;
+                self.buffer.drain(..pos + self.delimiter.len());
+                self.state = State::Delimiter;
+                return Ok(written + pos as u64);
+            }
+
+            // Everything but a tail that could be the start of a delimiter
+            // split across reads is part of the body
+            let safe = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+            out.write_all(&self.buffer[..safe])?;
+            self.buffer.drain(..safe);
+            written += safe as u64;
+            if !self.read_more()? {
+                return Err(MultipartError::Malformed);
+            }
+        }
+    }
+
+    // A line up to its CRLF, which is consumed. Lines longer than
+    // `max_header_line` are malformed.
+    fn read_line(&mut self) -> Result<Vec<u8>, MultipartError> {
+        loop {
+            if let Some(pos) = find_subsequence(&self.buffer, b"\r\n") {
+                let line = self.buffer[..pos].to_vec();
+                self.buffer.drain(..pos + 2);
+                return Ok(line);
+            }
+            if self.buffer.len() > self.limits.max_header_line || !self.read_more()? {
+                return Err(MultipartError::Malformed);
+            }
+        }
+    }
+
+    // Read until the buffer holds at least `len` bytes
+    fn fill(&mut self, len: usize) -> Result<(), MultipartError> {
+        while self.buffer.len() < len {
+            if !self.read_more()? {
+                return Err(MultipartError::Malformed);
+            }
+        }
+        Ok(())
+    }
+
+    // Append a read's worth to the buffer, returning false at the end of
+    // the body
+    fn read_more(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+        let size = loop {
+            match self.reader.read(&mut chunk) {
+                Ok(size) => break size,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        };
+        self.buffer.extend_from_slice(&chunk[..size]);
+        Ok(size > 0)
+    }
+}
+
+// The `boundary` parameter of a multipart `Content-Type`, quoted or not
+pub fn boundary(content_type: &str) -> Option<&str> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"'))
+        .filter(|value| !value.is_empty() && value.len() <= 70)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"a\"\r\n\
+        \r\n\
+        first\r\nbody\r\n\
+        --XyZ \r\n\
+        Content-Disposition: form-data; name=\"b\"; filename=\"b.txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        second -- not --XyZ yet\r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    // Reader handing out a byte at a time, so every delimiter is split
+    // across reads
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            out[0] = first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    // A part's headers and body
+    type Part = (Vec<(String, String)>, Vec<u8>);
+
+    fn parts_of(reader: impl Read) -> Result<Vec<Part>, MultipartError> {
+        let limits = ParserLimits::default();
+        let mut multipart = Multipart::new(reader, "XyZ", &limits);
+        let mut parts = Vec::new();
+        while let Some(headers) = multipart.next_part()? {
+            let mut body = Vec::new();
+            multipart.copy_body(&mut body)?;
+            parts.push((headers, body));
+        }
+        Ok(parts)
+    }
+
+    #[test]
+    fn parts_are_written_to_their_own_writers() {
+        for parts in [parts_of(BODY).unwrap(), parts_of(Trickle(BODY)).unwrap()] {
+            assert_eq!(parts.len(), 2);
+            assert_eq!(parts[0].0, [("Content-Disposition".to_string(), "form-data; name=\"a\"".to_string())]);
+            assert_eq!(parts[0].1, b"first\r\nbody");
+            assert_eq!(parts[1].0.len(), 2);
+            assert_eq!(parts[1].0[1], ("Content-Type".to_string(), "text/plain".to_string()));
+            assert_eq!(parts[1].1, b"second -- not --XyZ yet");
+        }
+    }
+
+    #[test]
+    fn uncopied_parts_are_skipped() {
+        let limits = ParserLimits::default();
+        let mut multipart = Multipart::new(Trickle(BODY), "XyZ", &limits);
+        assert!(multipart.next_part().unwrap().is_some());
+        let headers = multipart.next_part().unwrap().unwrap();
+        assert_eq!(headers[1].1, "text/plain");
+        let mut body = Vec::new();
+        assert_eq!(multipart.copy_body(&mut body).unwrap(), 23);
+        assert!(multipart.next_part().unwrap().is_none());
+        assert_eq!(multipart.copy_body(&mut body).unwrap(), 0);
+    }
+
+    #[test]
+    fn a_delimiter_may_open_the_body() {
+        let parts = parts_of(&b"--XyZ\r\n\r\nonly\r\n--XyZ--"[..]).unwrap();
+        assert_eq!(parts, [(Vec::new(), b"only".to_vec())]);
+    }
+
+    #[test]
+    fn refuses_malformed_bodies() {
+        let malformed: [&[u8]; 5] = [
+            // No closing delimiter
+            b"--XyZ\r\n\r\nbody",
+            // No delimiter at all
+            b"just text",
+            // Junk after a delimiter
+            b"--XyZjunk\r\n\r\nbody\r\n--XyZ--",
+            // A header without a colon
+            b"--XyZ\r\nnot a header\r\n\r\nbody\r\n--XyZ--",
+            // A folded header
+            b"--XyZ\r\nA: b\r\n c\r\n\r\nbody\r\n--XyZ--",
+        ];
+        for body in malformed {
+            assert!(matches!(parts_of(body), Err(MultipartError::Malformed)), "{:?}", String::from_utf8_lossy(body));
+        }
+    }
+
+    #[test]
+    fn finds_the_boundary() {
+        assert_eq!(boundary("multipart/form-data; boundary=abc"), Some("abc"));
+        assert_eq!(boundary("Multipart/Mixed;BOUNDARY=\"a b\""), Some("a b"));
+        assert_eq!(boundary("text/plain; boundary=abc"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/form-data; boundary=\"\""), None);
+        assert_eq!(boundary(&format!("multipart/form-data; boundary={}", "a".repeat(71))), None);
+    }
+}
@@ -0,0 +1,131 @@
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Fixed set of named worker threads running jobs from a shared queue
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<Sender<Job>>,
+}
+
+impl ThreadPool {
+    // Start `size` workers named `<name>-0`, `<name>-1`, ... A pool without
+    // workers would queue jobs forever, so a size of 0 is an error.
+    pub fn new(size: usize, name: &str) -> io::Result<Self> {
+        if size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a thread pool needs at least one worker"));
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|id| Worker::spawn(format!("{}-{}", name, id), Arc::clone(&receiver)))
+            .collect::<io::Result<_>>()?;
+
+        Ok(ThreadPool { workers, sender: Some(sender) })
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        if let Some(sender) = &self.sender {
+            // Fails only once every worker is gone, and the job's captures,
+            // like a connection, are dropped with it
+            if sender.send(Box::new(job)).is_err() {
+                eprintln!("Dropping job, no worker threads are left");
+            }
+        }
+    }
+}
+
+// Closing the queue lets workers finish what is queued, then stop
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            if worker.handle.join().is_err() {
+                eprintln!("Worker {} exited with a panic", worker.name);
+            }
+        }
+    }
+}
+
+struct Worker {
+    name: String,
+    handle: JoinHandle<()>,
+}
+
+impl Worker {
+    fn spawn(name: String, receiver: Arc<Mutex<Receiver<Job>>>) -> io::Result<Self> {
+        let handle = thread::Builder::new().name(name.clone()).spawn(move || loop {
+            // Hold the lock only while waiting, not while running the job
+            let job = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(poisoned) => poisoned.into_inner().recv(),
+            };
+            let Ok(job) = job else {
+                break;
+            };
+
+            // A panicking job is reported and the worker carries on
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                let name = thread::current().name().unwrap_or("unnamed").to_string();
+                eprintln!("Job panicked on worker {}, worker continues", name);
+            }
+        })?;
+
+        Ok(Worker { name, handle })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    #[test]
+    fn runs_every_job_on_named_workers() {
+        let ran = Arc::new(AtomicUsize::new(0));
+        let names = Arc::new(Mutex::new(Vec::new()));
+        // Every worker has to be busy at once to get past the barrier
+        let barrier = Arc::new(Barrier::new(3));
+        let pool = ThreadPool::new(3, "test-worker").unwrap();
+        for _ in 0..3 {
+            let (barrier, names) = (Arc::clone(&barrier), Arc::clone(&names));
+            pool.execute(move || {
+                names.lock().unwrap().push(thread::current().name().unwrap().to_string());
+                barrier.wait();
+            });
+        }
+        for _ in 0..100 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        // Dropping the pool waits for the queue to drain
+        drop(pool);
+        assert_eq!(ran.load(Ordering::Relaxed), 100);
+        let mut names = names.lock().unwrap().clone();
+        names.sort();
+        assert_eq!(names, ["test-worker-0", "test-worker-1", "test-worker-2"]);
+    }
+
+    #[test]
+    fn workers_survive_panicking_jobs() {
+        let pool = ThreadPool::new(1, "test-panic").unwrap();
+        pool.execute(|| panic!("job failed"));
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(thread::current().name().map(String::from)).unwrap());
+        assert_eq!(receiver.recv().unwrap().as_deref(), Some("test-panic-0"));
+    }
+
+    #[test]
+    fn refuses_an_empty_pool() {
+        let error = ThreadPool::new(0, "test-empty").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
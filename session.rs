@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::{HttpRequest, HttpResponse, Middleware, Next};
+
+// Name of the cookie carrying the session ID
+const SESSION_COOKIE: &str = "kp_session";
+
+// Expired sessions are swept once the store has doubled since the last
+// sweep, and never below this size
+const SWEEP_THRESHOLD: usize = 1024;
+
+// A session's values and when it was last used
+struct SessionEntry {
+    values: HashMap<String, String>,
+    last_access: Instant,
+}
+
+// Sessions by ID, shared between the middleware and the `Session` handles
+struct Sessions {
+    entries: Mutex<SessionEntries>,
+    ttl: Duration,
+    max_sessions: usize,
+    // The OS random source session IDs are read from
+    random: File,
+}
+
+struct SessionEntries {
+    by_id: HashMap<String, SessionEntry>,
+    // Size at which the next sweep is due
+    next_sweep: usize,
+}
+
+// In-memory session store keyed by a session ID cookie. Used as middleware,
+// it attaches a `Session` to each request. A session is only created, and
+// its cookie issued, once a handler sets a value in it, so requests that
+// never use one (health probes, assets) cost no more than a cookie lookup.
+// Sessions unused for `ttl` expire, and at most `max_sessions` are kept,
+// the least recently used giving way to new ones. The cookie is marked
+// `Secure` for requests that came over HTTPS.
+pub struct SessionStore {
+    sessions: Arc<Sessions>,
+}
+
+impl SessionStore {
+    // Fails if the OS random source for session IDs can't be opened
+    pub fn new(ttl: Duration, max_sessions: usize) -> io::Result<Self> {
+        Ok(SessionStore {
+            sessions: Arc::new(Sessions {
+                entries: Mutex::new(SessionEntries { by_id: HashMap::new(), next_sweep: SWEEP_THRESHOLD }),
+                ttl,
+                max_sessions: max_sessions.max(1),
+                random: File::open("/dev/urandom")?,
+            }),
+        })
+    }
+}
+
+impl Sessions {
+    fn lock(&self) -> std::sync::MutexGuard<'_, SessionEntries> {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Unguessable session ID: 128 bits from the OS random source, in hex
+    fn new_id(&self) -> String {
+        let mut bytes = [0; 16];
+        (&self.random).read_exact(&mut bytes).expect("failed to read /dev/urandom");
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // Start a new session, making room for it first if the store is due a
+    // sweep or full
+    fn create(&self) -> String {
+        let id = self.new_id();
+        let mut entries = self.lock();
+        if entries.by_id.len() >= entries.next_sweep || entries.by_id.len() >= self.max_sessions {
+            entries.by_id.retain(|_, entry| entry.last_access.elapsed() < self.ttl);
+            entries.next_sweep = (entries.by_id.len() * 2).max(SWEEP_THRESHOLD);
+        }
+
+        // Still full of live sessions: drop the least recently used eighth,
+        // so the next few sessions don't each have to look for a victim
+        if entries.by_id.len() >= self.max_sessions {
+            let mut accessed: Vec<Instant> = entries.by_id.values().map(|entry| entry.last_access).collect();
+            let evict = (accessed.len() / 8).max(1);
+            let (_, &mut cutoff, _) = accessed.select_nth_unstable(evict - 1);
+            entries.by_id.retain(|_, entry| entry.last_access > cutoff);
+        }
+
+        entries.by_id.insert(id.clone(), SessionEntry {
+            values: HashMap::new(),
+            last_access: Instant::now(),
+        });
+        id
+    }
+
+    // Refresh a session's last access, returning whether it is still valid
+    fn touch(&self, id: &str) -> bool {
+        let mut entries = self.lock();
+        match entries.by_id.get_mut(id) {
+            Some(entry) if entry.last_access.elapsed() < self.ttl => {
+                entry.last_access = Instant::now();
+                true
+            },
+            Some(_) => {
+                entries.by_id.remove(id);
+                false
+            },
+            None => false,
+        }
+    }
+}
+
+impl Middleware for SessionStore {
+    fn handle(&self, request: &mut HttpRequest, next: Next) -> HttpResponse {
+        let id = OnceLock::new();
+        if let Some(existing) = request.cookie(SESSION_COOKIE).filter(|id| self.sessions.touch(id)) {
+            let _ = id.set(existing.to_string());
+        }
+        let is_new = id.get().is_none();
+        request.extensions.insert(Session { id, sessions: Arc::clone(&self.sessions) });
+
+        let response = next.run(request);
+        let created = request.extensions.get::<Session>().and_then(Session::id).filter(|_| is_new);
+        match created {
+            Some(id) => {
+                let secure = if request.over_https() { "; Secure" } else { "" };
+                let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax{}", SESSION_COOKIE, id, secure);
+                response.with_header("Set-Cookie", &cookie)
+            },
+            None => response,
+        }
+    }
+}
+
+// Handle to the current request's session, found in the request extensions
+pub struct Session {
+    // Set once the session exists, from the cookie or the first `set`
+    id: OnceLock<String>,
+    sessions: Arc<Sessions>,
+}
+
+impl Session {
+    // The session ID, `None` until a value has been set
+    pub fn id(&self) -> Option<&str> {
+        self.id.get().map(String::as_str)
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let id = self.id.get()?;
+        self.sessions.lock().by_id.get(id)?.values.get(key).cloned()
+    }
+
+    // Store a value, creating the session if the request has none yet. Its
+    // cookie is sent with the response.
+    pub fn set(&self, key: &str, value: &str) {
+        let id = self.id.get_or_init(|| self.sessions.create());
+        let mut entries = self.sessions.lock();
+        // Expired or evicted since the request started: start over
+        let entry = entries.by_id.entry(id.clone()).or_insert_with(|| SessionEntry {
+            values: HashMap::new(),
+            last_access: Instant::now(),
+        });
+        entry.values.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let id = self.id.get()?;
+        self.sessions.lock().by_id.get_mut(id)?.values.remove(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{exchange, test_config};
+    use crate::{Router, ServerState, HTTP_OK};
+
+    // A router keeping a session value: `/set` stores the body under `key`,
+    // `/get` answers with it and `/remove` answers with it as it's removed
+    fn session_router(store: SessionStore) -> Router<ServerState> {
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.wrap(store);
+        router.add("POST", "/set", |request: &HttpRequest, _state: &ServerState| {
+            let session = request.extensions.get::<Session>().unwrap();
+            session.set("key", &String::from_utf8_lossy(&request.body));
+            HttpResponse::new(HTTP_OK)
+        });
+        router.add("GET", "/get", |request: &HttpRequest, _state: &ServerState| {
+            let session = request.extensions.get::<Session>().unwrap();
+            HttpResponse::new(HTTP_OK).with_body(session.get("key").unwrap_or_default().into_bytes())
+        });
+        router.add("POST", "/remove", |request: &HttpRequest, _state: &ServerState| {
+            let session = request.extensions.get::<Session>().unwrap();
+            HttpResponse::new(HTTP_OK).with_body(session.remove("key").unwrap_or_default().into_bytes())
+        });
+        router
+    }
+
+    fn session_cookie(response: &str) -> Option<&str> {
+        response.lines()
+            .find_map(|line| line.strip_prefix("Set-Cookie: kp_session="))
+            .and_then(|cookie| cookie.split(';').next())
+    }
+
+    #[test]
+    fn values_set_in_one_request_are_read_in_the_next() {
+        let config = test_config();
+        let router = session_router(SessionStore::new(Duration::from_secs(60), 100).unwrap());
+        let set = exchange(&config, &router, b"POST /set HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        let id = session_cookie(&set).expect("no session cookie");
+
+        let get = format!("GET /get HTTP/1.1\r\nCookie: kp_session={}\r\n\r\n", id);
+        let response = exchange(&config, &router, get.as_bytes());
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+        assert_eq!(session_cookie(&response), None);
+
+        // Without the cookie it's another client
+        let response = exchange(&config, &router, b"GET /get HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn sessions_are_only_created_when_used() {
+        let store = SessionStore::new(Duration::from_secs(60), 100).unwrap();
+        let sessions = Arc::clone(&store.sessions);
+        let router = session_router(store);
+        for _ in 0..3 {
+            let response = exchange(&test_config(), &router, b"GET /get HTTP/1.1\r\n\r\n");
+            assert_eq!(session_cookie(&response), None, "{}", response);
+        }
+        // An unknown cookie doesn't create one either
+        exchange(&test_config(), &router, b"GET /get HTTP/1.1\r\nCookie: kp_session=forged\r\n\r\n");
+        assert!(sessions.lock().by_id.is_empty());
+    }
+
+    #[test]
+    fn expired_sessions_are_refused() {
+        let store = SessionStore::new(Duration::from_millis(20), 100).unwrap();
+        let id = store.sessions.create();
+        assert!(store.sessions.touch(&id));
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!store.sessions.touch(&id));
+        assert!(store.sessions.lock().by_id.is_empty());
+    }
+
+    #[test]
+    fn the_store_is_capped() {
+        let store = SessionStore::new(Duration::from_secs(60), 64).unwrap();
+        let first = store.sessions.create();
+        for _ in 0..1000 {
+            store.sessions.create();
+        }
+        let entries = store.sessions.lock();
+        assert!(entries.by_id.len() <= 64, "{} sessions kept", entries.by_id.len());
+        assert!(!entries.by_id.contains_key(&first));
+    }
+
+    #[test]
+    fn expired_sessions_are_swept_as_the_store_grows() {
+        let store = SessionStore::new(Duration::from_millis(1), usize::MAX).unwrap();
+        for _ in 0..SWEEP_THRESHOLD {
+            store.sessions.create();
+        }
+        std::thread::sleep(Duration::from_millis(5));
+        store.sessions.create();
+        assert_eq!(store.sessions.lock().by_id.len(), 1);
+    }
+
+    #[test]
+    fn a_poisoned_store_keeps_working() {
+        let store = SessionStore::new(Duration::from_secs(60), 100).unwrap();
+        let sessions = Arc::clone(&store.sessions);
+        let _ = std::thread::spawn(move || {
+            let _entries = sessions.lock();
+            panic!("poison the lock");
+        }).join();
+        let id = store.sessions.create();
+        assert!(store.sessions.touch(&id));
+    }
+
+    #[test]
+    fn removed_values_are_gone_from_the_next_request() {
+        let config = test_config();
+        let router = session_router(SessionStore::new(Duration::from_secs(60), 100).unwrap());
+        let set = exchange(&config, &router, b"POST /set HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        let id = session_cookie(&set).expect("no session cookie").to_string();
+        let request = |method: &str, path: &str| {
+            let request = format!("{} {} HTTP/1.1\r\nCookie: kp_session={}\r\n\r\n", method, path, id);
+            exchange(&config, &router, request.as_bytes())
+        };
+
+        assert!(request("POST", "/remove").ends_with("\r\n\r\nhello"));
+        assert!(request("GET", "/get").ends_with("\r\n\r\n"));
+        assert!(request("POST", "/remove").ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn session_ids_are_random() {
+        let store = SessionStore::new(Duration::from_secs(60), 100).unwrap();
+        let ids: Vec<String> = (0..100).map(|_| store.sessions.new_id()).collect();
+        for id in &ids {
+            assert!(id.len() == 32 && id.bytes().all(|byte| byte.is_ascii_hexdigit()), "{}", id);
+        }
+        let mut distinct = ids.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), ids.len());
+    }
+
+    #[test]
+    fn cookies_are_secure_over_https() {
+        let router = session_router(SessionStore::new(Duration::from_secs(60), 100).unwrap());
+        let set = |headers: &str| {
+            let request = format!("POST /set HTTP/1.1\r\n{}Content-Length: 2\r\n\r\nhi", headers);
+            let response = exchange(&test_config(), &router, request.as_bytes());
+            response.lines().find(|line| line.starts_with("Set-Cookie: ")).unwrap().to_string()
+        };
+
+        assert!(set("X-Forwarded-Proto: https\r\n").ends_with("; HttpOnly; SameSite=Lax; Secure"));
+        assert!(set("").ends_with("; HttpOnly; SameSite=Lax"));
+    }
+}
@@ -0,0 +1,1116 @@
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::headers::{self, ByteRange, RangeSpec};
+use crate::{
+    error_response, http_date, lacks_charset, method_not_allowed, Config, Handler, HttpRequest, HttpResponse,
+    HTTP_CREATED, HTTP_FORBIDDEN, HTTP_INTERNAL_SERVER_ERROR, HTTP_NOT_FOUND, HTTP_NO_CONTENT, HTTP_OK,
+};
+
+const HTTP_PARTIAL_CONTENT: &str = "HTTP/1.1 206 Partial Content\r\n";
+const HTTP_RANGE_NOT_SATISFIABLE: &str = "HTTP/1.1 416 Range Not Satisfiable\r\n";
+
+// Static file serving settings
+pub struct StaticConfig {
+    // URL prefix the files are served under
+    pub mount_path: String,
+    // Directory the files are read from
+    pub root: PathBuf,
+    // Refuse `PUT` and `DELETE` with 403. When false, they write and remove
+    // files under the root.
+    pub read_only: bool,
+    // Single-page-app index, relative to the root, served for HTML requests
+    // to paths that match no file and have no extension so client-side
+    // routing works. Missing assets like `/app.js` still 404.
+    pub spa_fallback: Option<PathBuf>,
+    // Extra content types in the `mime.types` format, a type followed by
+    // its extensions on each line, taking precedence over the built-in table
+    pub mime_types: Option<PathBuf>,
+    // Let browsers and shared caches keep files this long, sent as
+    // `Cache-Control: public, max-age=...`. `None` sends no caching headers.
+    pub max_age: Option<Duration>,
+    // Glob for file names that never change, like build-hashed
+    // `*.[0-9a-f][0-9a-f][0-9a-f][0-9a-f][0-9a-f][0-9a-f]*.*` matching
+    // `app.abc123.js`. Matching files are cached for a year as immutable,
+    // others per `max_age`. Supports `*`, `?` and `[...]` classes.
+    pub immutable_names: Option<String>,
+    // Files, relative to the root, read into memory at startup so their
+    // first request is served without touching the disk. A file changed
+    // since is read from disk again. Failing ones are skipped with a warning.
+    pub warm_files: Vec<PathBuf>,
+}
+
+impl Default for StaticConfig {
+    fn default() -> Self {
+        StaticConfig {
+            mount_path: "/static".to_string(),
+            root: PathBuf::from("public"),
+            read_only: true,
+            spa_fallback: None,
+            mime_types: None,
+            max_age: None,
+            immutable_names: None,
+            warm_files: Vec::new(),
+        }
+    }
+}
+
+// Handler serving the files below the configured static root
+pub struct StaticFiles {
+    config: Arc<Config>,
+    // Lowercase extension to content type, loaded from `mime_types`
+    content_types: HashMap<String, String>,
+    // Contents of the `warm_files` by resolved path
+    warm: HashMap<PathBuf, WarmFile>,
+}
+
+// A file preloaded at startup, with the modification time it was read at
+struct WarmFile {
+    modified: SystemTime,
+    contents: Vec<u8>,
+}
+
+impl StaticFiles {
+    pub fn new(config: Arc<Config>) -> Self {
+        let content_types = match config.static_files.as_ref().and_then(|settings| settings.mime_types.as_ref()) {
+            Some(path) => fs::read_to_string(path)
+                .map(|contents| parse_mime_types(&contents))
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to read {}, using built-in content types: {}", path.display(), e);
+                    HashMap::new()
+                }),
+            None => HashMap::new(),
+        };
+        let warm = config.static_files.as_ref().map(warm_files).unwrap_or_default();
+        StaticFiles { config, content_types, warm }
+    }
+
+    // The preloaded contents of a file, unless it has changed since
+    fn warm_contents(&self, path: &Path) -> Option<&[u8]> {
+        let warm = self.warm.get(path)?;
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok()?;
+        (modified == warm.modified).then_some(warm.contents.as_slice())
+    }
+
+    fn settings(&self) -> &StaticConfig {
+        self.config.static_files.as_ref().expect("static file serving is not configured")
+    }
+
+    // Map a request path onto a file under the root. Anything that could
+    // escape the root, like `..` segments, maps to nothing.
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let settings = self.settings();
+        let path = request_path.split('?').next().unwrap_or("");
+        let relative = path.strip_prefix(settings.mount_path.as_str())?;
+        if !relative.is_empty() && !relative.starts_with('/') {
+            return None;
+        }
+
+        join_under(&settings.root, relative.trim_start_matches('/'))
+    }
+
+    fn content_type(&self, path: &Path) -> &str {
+        let extension = path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+
+        match extension.and_then(|extension| self.content_types.get(&extension)) {
+            Some(content_type) => content_type,
+            None => content_type_for(path),
+        }
+    }
+
+    // `Content-Type` for a file, text labelled UTF-8 by its type alone so
+    // a `HEAD` sends what the `GET` does without reading the file
+    fn content_type_header(&self, path: &Path) -> String {
+        let content_type = self.content_type(path);
+        if lacks_charset(content_type) {
+            format!("{}; charset=utf-8", content_type)
+        } else {
+            content_type.to_string()
+        }
+    }
+}
+
+impl<S> Handler<S> for StaticFiles {
+    fn handle(&self, request: &HttpRequest, _state: &S) -> HttpResponse {
+        match request.method.as_str() {
+            "GET" => self.get(request),
+            "HEAD" => self.head(request),
+            "PUT" | "DELETE" if self.settings().read_only => error_response(&self.config, HTTP_FORBIDDEN),
+            "PUT" => self.put(request),
+            "DELETE" => self.delete(request),
+            _ if self.settings().read_only => method_not_allowed(&self.config, &["GET", "HEAD"]),
+            _ => method_not_allowed(&self.config, &["GET", "HEAD", "PUT", "DELETE"]),
+        }
+    }
+}
+
+impl StaticFiles {
+    // The SPA index, if configured and the request is a page navigation
+    fn spa_index(&self, request: &HttpRequest) -> Option<PathBuf> {
+        let settings = self.settings();
+        let index = settings.spa_fallback.as_ref()?;
+
+        let accepts_html = request.header("Accept").is_some_and(|accept| accept.contains("text/html"));
+        let path = request.path.split('?').next().unwrap_or("");
+        let has_extension = path.rsplit('/').next().is_some_and(|segment| segment.contains('.'));
+        if !accepts_html || has_extension {
+            return None;
+        }
+        Some(settings.root.join(index))
+    }
+
+    // The file a `GET` or `HEAD` for the request's path serves
+    fn file_for(&self, request: &HttpRequest) -> Option<PathBuf> {
+        let file = self.resolve(&request.path).filter(|path| path.is_file());
+        file.or_else(|| self.spa_index(request))
+    }
+
+    fn get(&self, request: &HttpRequest) -> HttpResponse {
+        let Some(path) = self.file_for(request) else {
+            return error_response(&self.config, HTTP_NOT_FOUND);
+        };
+        let content_type = self.content_type_header(&path);
+        let validators = Validators::for_file(&path);
+        let response = match self.warm_contents(&path) {
+            Some(contents) => serve_range(&self.config, request, contents, &content_type, &validators),
+            None => serve_range(&self.config, request, path.as_path(), &content_type, &validators),
+        };
+        if response.status_code() >= 300 {
+            return response;
+        }
+        self.with_caching(&path, response)
+    }
+
+    // The headers a `GET` would send, from the file's metadata alone. The
+    // file is never opened, and ranges are ignored as the whole file would
+    // be sent. Compression and `Vary` are added on the way out as for `GET`.
+    fn head(&self, request: &HttpRequest) -> HttpResponse {
+        let Some(path) = self.file_for(request) else {
+            return error_response(&self.config, HTTP_NOT_FOUND);
+        };
+        let Ok(len) = path.as_path().len() else {
+            return error_response(&self.config, HTTP_NOT_FOUND);
+        };
+        let response = Validators::for_file(&path).apply(HttpResponse::new(HTTP_OK))
+            .with_header("Content-Type", &self.content_type_header(&path))
+            .with_header("Accept-Ranges", "bytes")
+            .headers_only(len);
+        self.with_caching(&path, response)
+    }
+
+    // Caching headers for a successful response serving `path`
+    fn with_caching(&self, path: &Path, response: HttpResponse) -> HttpResponse {
+        let settings = self.settings();
+        let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        if settings.immutable_names.as_ref().is_some_and(|pattern| glob_matches(pattern, &name)) {
+            return response.cache_control(&["public", "max-age=31536000", "immutable"]);
+        }
+        match settings.max_age {
+            Some(max_age) => response.cache_control(&["public", &format!("max-age={}", max_age.as_secs())]),
+            None => response,
+        }
+    }
+
+    // Create or replace a file with the request body: 201 if it is new,
+    // 204 if it replaced an existing file
+    fn put(&self, request: &HttpRequest) -> HttpResponse {
+        let Some(path) = self.resolve(&request.path) else {
+            return error_response(&self.config, HTTP_FORBIDDEN);
+        };
+        if path.is_dir() {
+            return error_response(&self.config, HTTP_FORBIDDEN);
+        }
+
+        let existed = path.is_file();
+        let written = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|()| fs::write(&path, &request.body));
+
+        match written {
+            Ok(()) if existed => HttpResponse::new(HTTP_NO_CONTENT),
+            Ok(()) => HttpResponse::new(HTTP_CREATED),
+            Err(e) => {
+                eprintln!("Failed to write {}: {}", path.display(), e);
+                error_response(&self.config, HTTP_INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    fn delete(&self, request: &HttpRequest) -> HttpResponse {
+        let Some(path) = self.resolve(&request.path) else {
+            return error_response(&self.config, HTTP_NOT_FOUND);
+        };
+
+        match fs::remove_file(&path) {
+            Ok(()) => HttpResponse::new(HTTP_NO_CONTENT),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => error_response(&self.config, HTTP_NOT_FOUND),
+            Err(e) => {
+                eprintln!("Failed to delete {}: {}", path.display(), e);
+                error_response(&self.config, HTTP_INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+// Match a whole name against a glob: `*` is any run of characters, `?` any
+// one, and `[a-f0-9]` or `[!.]` one in or not in a set
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    glob_matches_at(&pattern, &name)
+}
+
+fn glob_matches_at(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| glob_matches_at(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && glob_matches_at(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let Some(close) = pattern.iter().skip(2).position(|&c| c == ']').map(|pos| pos + 2) else {
+                // An unclosed `[` is just a character
+                return name.first() == Some(&'[') && glob_matches_at(&pattern[1..], &name[1..]);
+            };
+            let Some(&c) = name.first() else {
+                return false;
+            };
+            let (negated, set) = match &pattern[1..close] {
+                ['!', set @ ..] => (true, set),
+                set => (false, set),
+            };
+            let mut in_set = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    in_set |= (set[i]..=set[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    in_set |= set[i] == c;
+                    i += 1;
+                }
+            }
+            in_set != negated && glob_matches_at(&pattern[close + 1..], &name[1..])
+        },
+        Some(&literal) => name.first() == Some(&literal) && glob_matches_at(&pattern[1..], &name[1..]),
+    }
+}
+
+// Read the configured `warm_files`, warning about those that can't be
+fn warm_files(settings: &StaticConfig) -> HashMap<PathBuf, WarmFile> {
+    let mut warm = HashMap::new();
+    for relative in &settings.warm_files {
+        let Some(path) = join_under(&settings.root, &relative.to_string_lossy()) else {
+            eprintln!("Not preloading {}: outside the static root", relative.display());
+            continue;
+        };
+        let loaded = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .and_then(|modified| Ok(WarmFile { modified, contents: fs::read(&path)? }));
+        match loaded {
+            Ok(file) => {
+                warm.insert(path, file);
+            },
+            Err(e) => eprintln!("Failed to preload {}: {}", path.display(), e),
+        }
+    }
+    warm
+}
+
+// Join a relative path onto a root. Anything that could escape the root,
+// like `..` segments or absolute paths, maps to nothing.
+fn join_under(root: &Path, relative: &str) -> Option<PathBuf> {
+    let relative = Path::new(relative);
+    if relative.as_os_str().is_empty()
+        || relative.to_string_lossy().contains('\\')
+        || relative.components().any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(root.join(relative))
+}
+
+// Replace the body of a response carrying `X-Sendfile: /path` with that
+// file from the sendfile root, so a handler can gate a download without
+// reading the file itself. The header never reaches the client.
+pub fn apply_sendfile(config: &Config, mut response: HttpResponse) -> HttpResponse {
+    let Some(index) = response.headers.iter().position(|(key, _)| key.eq_ignore_ascii_case("X-Sendfile")) else {
+        return response;
+    };
+    let (_, target) = response.headers.remove(index);
+    let Some(root) = &config.sendfile_root else {
+        eprintln!("Ignoring X-Sendfile for {}, no sendfile root is configured", target);
+        return response;
+    };
+    let Some(path) = join_under(root, target.trim_start_matches('/')) else {
+        return error_response(config, HTTP_FORBIDDEN);
+    };
+
+    match fs::read(&path) {
+        Ok(body) => {
+            if !response.headers.iter().any(|(key, _)| key.eq_ignore_ascii_case("Content-Type")) {
+                response = response.with_header("Content-Type", content_type_for(&path));
+            }
+            response.with_body(body)
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => error_response(config, HTTP_NOT_FOUND),
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path.display(), e);
+            error_response(config, HTTP_INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// Handler serving a blob compiled into the binary or built at startup, with
+// the same range support as files on disk
+pub struct EmbeddedAsset {
+    config: Arc<Config>,
+    bytes: Cow<'static, [u8]>,
+    content_type: &'static str,
+}
+
+impl EmbeddedAsset {
+    pub fn new(config: Arc<Config>, bytes: impl Into<Cow<'static, [u8]>>, content_type: &'static str) -> Self {
+        EmbeddedAsset { config, bytes: bytes.into(), content_type }
+    }
+}
+
+impl<S> Handler<S> for EmbeddedAsset {
+    fn handle(&self, request: &HttpRequest, _state: &S) -> HttpResponse {
+        serve_range(&self.config, request, &*self.bytes, self.content_type, &Validators::default())
+    }
+}
+
+// A resource that can be served whole or as a byte range
+trait RangeSource {
+    // Total length in bytes
+    fn len(&self) -> io::Result<u64>;
+    // The half-open byte range `start..end`, which lies within `len`
+    fn read_range(&self, start: u64, end: u64) -> io::Result<Vec<u8>>;
+
+    // `response` with the range as its body, read into memory unless the
+    // source has a cheaper way to send it
+    fn respond_with(&self, response: HttpResponse, start: u64, end: u64) -> io::Result<HttpResponse> {
+        Ok(response.with_body(self.read_range(start, end)?))
+    }
+}
+
+impl RangeSource for Path {
+    fn len(&self) -> io::Result<u64> {
+        let metadata = fs::metadata(self)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "not a file"));
+        }
+        Ok(metadata.len())
+    }
+
+    fn read_range(&self, start: u64, end: u64) -> io::Result<Vec<u8>> {
+        read_file_range(self, start, end)
+    }
+
+    // With the `mmap` feature the file is memory-mapped and the range
+    // written to the socket from the map, so only the pages sent are
+    // touched and nothing is read into a buffer first. Empty ranges, which
+    // can't be mapped, and files that fail to map are read instead.
+    fn respond_with(&self, response: HttpResponse, start: u64, end: u64) -> io::Result<HttpResponse> {
+        #[cfg(feature = "mmap")]
+        if end > start {
+            let file = File::open(self)?;
+            // SAFETY: the map is only read. A file truncated while it's
+            // mapped can still fault, which is the usual caveat of mapping
+            // files.
+            match unsafe { memmap2::Mmap::map(&file) } {
+                Ok(map) => {
+                    if let (Ok(start), Ok(end)) = (usize::try_from(start), usize::try_from(end)) {
+                        if end <= map.len() {
+                            return Ok(response.with_mapped(map, start..end));
+                        }
+                    }
+                },
+                Err(e) => eprintln!("Failed to map {}, reading instead: {}", self.display(), e),
+            }
+        }
+        Ok(response.with_body(read_file_range(self, start, end)?))
+    }
+}
+
+impl RangeSource for [u8] {
+    fn len(&self) -> io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+
+    fn read_range(&self, start: u64, end: u64) -> io::Result<Vec<u8>> {
+        Ok(self[start as usize..end as usize].to_vec())
+    }
+}
+
+// What identifies the current version of a resource, sent as `ETag` and
+// `Last-Modified` and checked against `If-Range`
+#[derive(Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    // From a file's size and modification time, like `"1f4-5e0f3c2a1b"`
+    fn for_file(path: &Path) -> Self {
+        let Ok(metadata) = fs::metadata(path) else {
+            return Validators::default();
+        };
+        let modified = metadata.modified().ok();
+        Validators {
+            etag: modified.map(|modified| {
+                let micros = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros();
+                format!("\"{:x}-{:x}\"", metadata.len(), micros)
+            }),
+            last_modified: modified.map(http_date),
+        }
+    }
+
+    // Whether an `If-Range` value names this version. It needs a strong
+    // match, so weak ETags never match and a date has to be exactly the
+    // `Last-Modified` sent.
+    fn if_range_matches(&self, value: &str) -> bool {
+        let value = value.trim();
+        if value.starts_with('"') {
+            self.etag.as_deref() == Some(value)
+        } else {
+            !value.starts_with("W/") && self.last_modified.as_deref() == Some(value)
+        }
+    }
+
+    fn apply(&self, mut response: HttpResponse) -> HttpResponse {
+        if let Some(etag) = &self.etag {
+            response = response.with_header("ETag", etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            response = response.with_header("Last-Modified", last_modified);
+        }
+        response
+    }
+}
+
+// Answer a `GET` for a source, honouring a `Range` header with 206 or 416.
+// With `If-Range`, only while the source is still the version it names,
+// otherwise the whole of the changed source is sent.
+fn serve_range<S: RangeSource + ?Sized>(
+    config: &Config,
+    request: &HttpRequest,
+    source: &S,
+    content_type: &str,
+    validators: &Validators,
+) -> HttpResponse {
+    let len = match source.len() {
+        Ok(len) => len,
+        Err(_) => return error_response(config, HTTP_NOT_FOUND),
+    };
+
+    let current = request.header("If-Range").is_none_or(|value| validators.if_range_matches(value));
+    let range = match headers::range(request) {
+        Some(spec) if current => fit_range(&spec, len),
+        _ => RangeRequest::Full,
+    };
+    let (status_line, start, end) = match range {
+        RangeRequest::Full => (HTTP_OK, 0, len),
+        RangeRequest::Partial(start, end) => (HTTP_PARTIAL_CONTENT, start, end),
+        RangeRequest::Multiple(ranges) => {
+            let boundary = multipart_boundary();
+            return match multipart_byteranges(source, &ranges, len, content_type, &boundary) {
+                Ok(body) => validators.apply(HttpResponse::new(HTTP_PARTIAL_CONTENT))
+                    .with_header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary))
+                    .with_header("Accept-Ranges", "bytes")
+                    .with_body(body),
+                Err(e) => {
+                    eprintln!("Failed to read {}: {}", request.path, e);
+                    error_response(config, HTTP_NOT_FOUND)
+                }
+            };
+        },
+        RangeRequest::Unsatisfiable => {
+            return error_response(config, HTTP_RANGE_NOT_SATISFIABLE)
+                .with_header("Content-Range", &format!("bytes */{}", len));
+        }
+    };
+
+    let mut response = validators.apply(HttpResponse::new(status_line))
+        .with_header("Content-Type", content_type)
+        .with_header("Accept-Ranges", "bytes");
+    if status_line == HTTP_PARTIAL_CONTENT {
+        response = response.with_header("Content-Range", &format!("bytes {}-{}/{}", start, end - 1, len));
+    }
+    match source.respond_with(response, start, end) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", request.path, e);
+            error_response(config, HTTP_NOT_FOUND)
+        }
+    }
+}
+
+// Outcome of applying a `Range` header to a resource of known length
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+    // Serve the whole resource
+    Full,
+    // Serve the half-open byte range `start..end`
+    Partial(u64, u64),
+    // Serve several half-open ranges as `multipart/byteranges`
+    Multiple(Vec<(u64, u64)>),
+    // Reply 416, no part of the range exists
+    Unsatisfiable,
+}
+
+// More ranges than this in one header are ignored and the full resource is
+// served, so a request can't make us assemble thousands of tiny parts
+const MAX_RANGES: usize = 16;
+
+// Fit a range set to a resource of `len` bytes. Sets we don't understand,
+// and oversized ones, are ignored and the full resource is served. Ranges
+// lying wholly past the end are dropped, and if none remain the result is
+// unsatisfiable.
+fn fit_range(spec: &RangeSpec, len: u64) -> RangeRequest {
+    if spec.0.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    let ranges: Vec<(u64, u64)> = spec.0.iter()
+        .filter_map(|range| {
+            let (start, end) = match *range {
+                ByteRange::Suffix(0) => return None,
+                ByteRange::Suffix(suffix) => (len.saturating_sub(suffix), len),
+                ByteRange::From(start) => (start, len),
+                ByteRange::FromTo(start, end) => (start, end.saturating_add(1).min(len)),
+            };
+            (start < len).then_some((start, end))
+        })
+        .collect();
+
+    match ranges.len() {
+        0 => RangeRequest::Unsatisfiable,
+        1 => RangeRequest::Partial(ranges[0].0, ranges[0].1),
+        _ => RangeRequest::Multiple(ranges),
+    }
+}
+
+// Assemble a `multipart/byteranges` body, each part with its own
+// `Content-Type` and `Content-Range`
+fn multipart_byteranges<S: RangeSource + ?Sized>(
+    source: &S,
+    ranges: &[(u64, u64)],
+    len: u64,
+    content_type: &str,
+    boundary: &str,
+) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    for &(start, end) in ranges {
+        let part_head = format!(
+            "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, start, end - 1, len,
+        );
+        body.extend_from_slice(part_head.as_bytes());
+        body.extend_from_slice(&source.read_range(start, end)?);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    Ok(body)
+}
+
+// Boundary for a multipart body. It only has to be unlikely to appear in
+// the content, so a random hash is plenty.
+fn multipart_boundary() -> String {
+    format!("kp-http-{:016x}", RandomState::new().build_hasher().finish())
+}
+
+// Read the half-open byte range `start..end` of a file
+fn read_file_range(path: &Path, start: u64, end: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut body = Vec::with_capacity((end - start) as usize);
+    file.take(end - start).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+// Parse `mime.types` lines like `application/json json map`, skipping blank
+// lines and `#` comments
+fn parse_mime_types(contents: &str) -> HashMap<String, String> {
+    let mut content_types = HashMap::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let Some(content_type) = fields.next() else {
+            continue;
+        };
+        for extension in fields {
+            content_types.insert(extension.trim_start_matches('.').to_ascii_lowercase(), content_type.to_string());
+        }
+    }
+    content_types
+}
+
+// Content type for a file, based on its extension
+pub fn content_type_for(path: &Path) -> &'static str {
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "application/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{exchange, temp_dir, test_config};
+    use crate::{Router, ServerState};
+
+    // A router serving `root` under `/static`
+    fn static_setup(root: &Path) -> (Arc<Config>, Router<ServerState>) {
+        let settings = StaticConfig { root: root.to_path_buf(), ..StaticConfig::default() };
+        let config = Arc::new(Config { static_files: Some(settings), ..test_config() });
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.mount("/static", StaticFiles::new(Arc::clone(&config)));
+        (config, router)
+    }
+
+    // The head of a response as its lines, without the status line
+    fn header_lines(response: &str) -> Vec<&str> {
+        let head = response.split("\r\n\r\n").next().unwrap();
+        head.split("\r\n").skip(1).collect()
+    }
+
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        header_lines(response).into_iter()
+            .find_map(|line| line.split_once(": ").filter(|(key, _)| key.eq_ignore_ascii_case(name)))
+            .map(|(_, value)| value)
+    }
+
+    // Letters no two ranges of which are alike, so a misplaced slice shows
+    fn patterned(len: usize) -> Vec<u8> {
+        (0..len).map(|i| b'a' + ((i * 7 + i / 251) % 26) as u8).collect()
+    }
+
+    #[test]
+    fn files_are_served_whole_and_in_ranges() {
+        let root = temp_dir("static-ranges");
+        let contents = patterned(300_000);
+        fs::write(root.join("data.bin"), &contents).unwrap();
+        fs::write(root.join("empty.txt"), b"").unwrap();
+        let (config, router) = static_setup(&root);
+
+        let response = exchange(&config, &router, b"GET /static/data.bin HTTP/1.1\r\n\r\n");
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(header(&response, "Content-Length"), Some("300000"));
+        assert!(body.as_bytes() == contents);
+
+        let response = exchange(&config, &router, b"GET /static/data.bin HTTP/1.1\r\nRange: bytes=1000-1009\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Range"), Some("bytes 1000-1009/300000"));
+        assert!(response.as_bytes().ends_with(&contents[1000..1010]), "{}", response);
+
+        let response = exchange(&config, &router, b"GET /static/empty.txt HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Length"), Some("0"));
+    }
+
+    #[test]
+    fn writes_are_opt_in() {
+        let root = temp_dir("static-writes");
+        let put = b"PUT /static/notes/a.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let (config, router) = static_setup(&root);
+        let response = exchange(&config, &router, put);
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", response);
+        let response = exchange(&config, &router, b"DELETE /static/notes/a.txt HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", response);
+        assert!(!root.join("notes").exists());
+
+        let settings = StaticConfig { root: root.clone(), read_only: false, ..StaticConfig::default() };
+        let config = Arc::new(Config { static_files: Some(settings), ..test_config() });
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.mount("/static", StaticFiles::new(Arc::clone(&config)));
+
+        let response = exchange(&config, &router, put);
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        let response = exchange(&config, &router, b"PUT /static/notes/a.txt HTTP/1.1\r\nContent-Length: 3\r\n\r\nbye");
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+        let response = exchange(&config, &router, b"GET /static/notes/a.txt HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\nbye"), "{}", response);
+
+        // Nothing outside the root is written
+        let response = exchange(&config, &router, b"PUT /static/../escape.txt HTTP/1.1\r\nContent-Length: 1\r\n\r\nx");
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", response);
+        assert!(!root.parent().unwrap().join("escape.txt").exists());
+
+        let response = exchange(&config, &router, b"DELETE /static/notes/a.txt HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+        let response = exchange(&config, &router, b"DELETE /static/notes/a.txt HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn page_navigations_fall_back_to_the_spa_index() {
+        let root = temp_dir("static-spa");
+        fs::write(root.join("index.html"), "<div id=app></div>").unwrap();
+        let settings = StaticConfig { root, spa_fallback: Some(PathBuf::from("index.html")), ..StaticConfig::default() };
+        let config = Arc::new(Config { static_files: Some(settings), ..test_config() });
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.mount("/static", StaticFiles::new(Arc::clone(&config)));
+        let get = |path: &str, accept: &str| {
+            let request = format!("GET {} HTTP/1.1\r\nAccept: {}\r\n\r\n", path, accept);
+            exchange(&config, &router, request.as_bytes())
+        };
+
+        let response = get("/static/some/route", "text/html,application/xhtml+xml");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Type"), Some("text/html; charset=utf-8"));
+        assert!(response.ends_with("<div id=app></div>"), "{}", response);
+
+        assert!(get("/static/missing.js", "text/html").starts_with("HTTP/1.1 404 "));
+        assert!(get("/static/some/route", "application/json").starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn embedded_assets_are_served_in_ranges() {
+        let config = Arc::new(test_config());
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.add("GET", "/logo.txt", EmbeddedAsset::new(Arc::clone(&config), &b"0123456789"[..], "text/plain"));
+        let get = |range: &str| {
+            let request = format!("GET /logo.txt HTTP/1.1\r\nRange: {}\r\n\r\n", range);
+            exchange(&config, &router, request.as_bytes())
+        };
+
+        let response = get("bytes=2-4");
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Range"), Some("bytes 2-4/10"));
+        assert!(response.ends_with("\r\n\r\n234"), "{}", response);
+
+        let response = get("bytes=-3");
+        assert_eq!(header(&response, "Content-Range"), Some("bytes 7-9/10"));
+        assert!(response.ends_with("\r\n\r\n789"), "{}", response);
+
+        let response = get("bytes=10-");
+        assert!(response.starts_with("HTTP/1.1 416 "), "{}", response);
+        assert_eq!(header(&response, "Content-Range"), Some("bytes */10"));
+
+        let response = get("items=0-1");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n0123456789"), "{}", response);
+    }
+
+    #[test]
+    fn mime_types_files_override_the_built_in_table() {
+        let root = temp_dir("static-mime-types");
+        let mime_types = root.join("mime.types");
+        fs::write(&mime_types, "# overrides\napplication/json map .MAP\n\ntext/x-custom kpx # ours\ntext/markdown\n").unwrap();
+        for name in ["app.js.map", "notes.kpx", "app.js", "page.HTML"] {
+            fs::write(root.join(name), "x").unwrap();
+        }
+        let settings = StaticConfig { root, mime_types: Some(mime_types), ..StaticConfig::default() };
+        let files = StaticFiles::new(Arc::new(Config { static_files: Some(settings), ..test_config() }));
+
+        assert_eq!(files.content_type(Path::new("app.js.map")), "application/json");
+        assert_eq!(files.content_type(Path::new("notes.kpx")), "text/x-custom");
+        assert_eq!(files.content_type(Path::new("app.js")), "application/javascript");
+        assert_eq!(files.content_type(Path::new("page.HTML")), "text/html");
+        assert_eq!(files.content_type_header(Path::new("notes.kpx")), "text/x-custom; charset=utf-8");
+
+        let router = {
+            let mut router = Router::with_state(Arc::new(ServerState::new()));
+            router.mount("/static", files);
+            router
+        };
+        let response = exchange(&test_config(), &router, b"GET /static/app.js.map HTTP/1.1\r\n\r\n");
+        assert_eq!(header(&response, "Content-Type"), Some("application/json; charset=utf-8"));
+    }
+
+    #[test]
+    fn files_are_cached_for_the_configured_max_age() {
+        let root = temp_dir("static-max-age");
+        fs::write(root.join("app.js"), "x").unwrap();
+        let (config, router) = static_setup(&root);
+        let response = exchange(&config, &router, b"GET /static/app.js HTTP/1.1\r\n\r\n");
+        assert_eq!(header(&response, "Cache-Control"), None);
+
+        let settings = StaticConfig { root, max_age: Some(Duration::from_secs(600)), ..StaticConfig::default() };
+        let config = Arc::new(Config { static_files: Some(settings), ..test_config() });
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.mount("/static", StaticFiles::new(Arc::clone(&config)));
+        for method in ["GET", "HEAD"] {
+            let request = format!("{} /static/app.js HTTP/1.1\r\n\r\n", method);
+            let response = exchange(&config, &router, request.as_bytes());
+            assert_eq!(header(&response, "Cache-Control"), Some("public, max-age=600"), "{}", method);
+        }
+        // Errors aren't cached
+        let response = exchange(&config, &router, b"GET /static/missing.js HTTP/1.1\r\n\r\n");
+        assert_eq!(header(&response, "Cache-Control"), None);
+    }
+
+    #[test]
+    fn several_ranges_are_sent_as_multipart_byteranges() {
+        let root = temp_dir("static-multirange");
+        let contents = patterned(1000);
+        fs::write(root.join("data.txt"), &contents).unwrap();
+        let (config, router) = static_setup(&root);
+        let get = |range: &str| {
+            let request = format!("GET /static/data.txt HTTP/1.1\r\nRange: {}\r\n\r\n", range);
+            exchange(&config, &router, request.as_bytes())
+        };
+
+        let response = get("bytes=0-99,200-299");
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{}", response);
+        let content_type = header(&response, "Content-Type").unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let parts: Vec<&str> = body.split(&format!("--{}", boundary)).collect();
+        assert_eq!(parts.len(), 4, "{}", body);
+        assert_eq!((parts[0], parts[3]), ("", "--\r\n"));
+        let text = String::from_utf8(contents).unwrap();
+        for (part, (start, end)) in parts[1..3].iter().zip([(0, 99), (200, 299)]) {
+            let expected = format!(
+                "\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes {}-{}/1000\r\n\r\n{}\r\n",
+                start, end, &text[start..=end],
+            );
+            assert_eq!(*part, expected);
+        }
+
+        // Ranges past the end are dropped, leaving a single range or none
+        let response = get("bytes=900-,5000-6000");
+        assert_eq!(header(&response, "Content-Range"), Some("bytes 900-999/1000"));
+        assert!(get("bytes=1000-,5000-").starts_with("HTTP/1.1 416 "));
+        // Too many ranges get the whole file
+        let many = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 10, i * 10)).collect::<Vec<_>>().join(",");
+        let response = get(&format!("bytes={}", many));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn handlers_can_delegate_sending_a_file() {
+        let root = temp_dir("sendfile");
+        fs::write(root.join("report.pdf"), "%PDF-1.7 report").unwrap();
+        let config = Config { sendfile_root: Some(root), ..test_config() };
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.add("GET", "/download/:name", |request: &HttpRequest, _state: &ServerState| {
+            let target = format!("/{}", request.param("name").unwrap_or_default());
+            HttpResponse::new(HTTP_OK).with_header("X-Sendfile", &target)
+        });
+
+        let response = exchange(&config, &router, b"GET /download/report.pdf HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Type"), Some("application/pdf"));
+        assert_eq!(header(&response, "Content-Length"), Some("15"));
+        assert_eq!(header(&response, "X-Sendfile"), None);
+        assert!(response.ends_with("\r\n\r\n%PDF-1.7 report"), "{}", response);
+
+        assert!(exchange(&config, &router, b"GET /download/missing.pdf HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 "));
+        assert!(exchange(&config, &router, b"GET /download/.. HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 403 "));
+
+        // Without a root the header is dropped and the handler's body sent
+        let response = exchange(&test_config(), &router, b"GET /download/report.pdf HTTP/1.1\r\n\r\n");
+        assert_eq!(header(&response, "X-Sendfile"), None);
+        assert_eq!(header(&response, "Content-Length"), Some("0"));
+    }
+
+    #[test]
+    fn hashed_names_are_cached_as_immutable() {
+        let root = temp_dir("static-immutable");
+        for name in ["app.abc123.js", "app.js", "logo.1f2e3d4c.png"] {
+            fs::write(root.join(name), "x").unwrap();
+        }
+        let settings = StaticConfig {
+            root,
+            max_age: Some(Duration::from_secs(60)),
+            immutable_names: Some("*.[0-9a-f][0-9a-f][0-9a-f][0-9a-f][0-9a-f][0-9a-f]*.*".to_string()),
+            ..StaticConfig::default()
+        };
+        let config = Arc::new(Config { static_files: Some(settings), ..test_config() });
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.mount("/static", StaticFiles::new(Arc::clone(&config)));
+        let cache_control = |name: &str| {
+            let request = format!("GET /static/{} HTTP/1.1\r\n\r\n", name);
+            header(&exchange(&config, &router, request.as_bytes()), "Cache-Control").map(str::to_string)
+        };
+
+        let immutable = Some("public, max-age=31536000, immutable".to_string());
+        assert_eq!(cache_control("app.abc123.js"), immutable);
+        assert_eq!(cache_control("logo.1f2e3d4c.png"), immutable);
+        assert_eq!(cache_control("app.js"), Some("public, max-age=60".to_string()));
+    }
+
+    #[test]
+    fn globs_match_whole_names() {
+        assert!(glob_matches("*.js", "app.js"));
+        assert!(!glob_matches("*.js", "app.json"));
+        assert!(glob_matches("a?c", "abc") && !glob_matches("a?c", "ac"));
+        assert!(glob_matches("[a-c]x", "bx") && !glob_matches("[a-c]x", "dx"));
+        assert!(glob_matches("[!.]*", "app") && !glob_matches("[!.]*", ".hidden"));
+        assert!(glob_matches("[x", "[x"));
+        assert!(!glob_matches("", "a") && glob_matches("*", ""));
+    }
+
+    #[test]
+    fn warmed_files_are_served_from_memory_until_they_change() {
+        let root = temp_dir("static-warm");
+        let path = root.join("index.html");
+        fs::write(&path, "<p>warm</p>").unwrap();
+        let warm_files = ["index.html", "missing.html", "../outside.html"].map(PathBuf::from).to_vec();
+        let settings = StaticConfig { root: root.clone(), warm_files, ..StaticConfig::default() };
+        let mut files = StaticFiles::new(Arc::new(Config { static_files: Some(settings), ..test_config() }));
+        // The files that couldn't be read are skipped
+        assert_eq!(files.warm.keys().collect::<Vec<_>>(), [&path]);
+        assert_eq!(files.warm_contents(&path), Some(&b"<p>warm</p>"[..]));
+
+        // Bytes only the preloaded copy has show it is what's served
+        files.warm.get_mut(&path).unwrap().contents = b"<p>from memory</p>".to_vec();
+        let config = test_config();
+        let mut router = Router::with_state(Arc::new(ServerState::new()));
+        router.mount("/static", files);
+        let response = exchange(&config, &router, b"GET /static/index.html HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\n<p>from memory</p>"), "{}", response);
+
+        // A changed file is read from disk again
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        let response = exchange(&config, &router, b"GET /static/index.html HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\n<p>warm</p>"), "{}", response);
+    }
+
+    #[test]
+    fn ranges_are_only_served_while_if_range_is_current() {
+        let root = temp_dir("static-if-range");
+        fs::write(root.join("data.txt"), "0123456789").unwrap();
+        let (config, router) = static_setup(&root);
+        let get = |if_range: &str| {
+            let request = format!("GET /static/data.txt HTTP/1.1\r\nRange: bytes=0-3\r\nIf-Range: {}\r\n\r\n", if_range);
+            exchange(&config, &router, request.as_bytes())
+        };
+        let whole = exchange(&config, &router, b"GET /static/data.txt HTTP/1.1\r\n\r\n");
+        let etag = header(&whole, "ETag").unwrap().to_string();
+        let last_modified = header(&whole, "Last-Modified").unwrap().to_string();
+
+        for current in [etag.as_str(), last_modified.as_str()] {
+            let response = get(current);
+            assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{}", response);
+            assert!(response.ends_with("\r\n\r\n0123"), "{}", response);
+        }
+        let stale_date = http_date(UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+        for stale in ["\"0-0\"", &format!("W/{}", etag), &stale_date] {
+            let response = get(stale);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", stale, response);
+            assert!(response.ends_with("\r\n\r\n0123456789"), "{}", response);
+        }
+    }
+
+    // The bytes a response puts on the wire
+    #[cfg(feature = "mmap")]
+    fn sent(response: HttpResponse) -> Vec<u8> {
+        let mut out = Vec::new();
+        assert!(crate::send_response(&mut out, response, &test_config()));
+        out
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped_files_send_the_bytes_reads_do() {
+        let root = temp_dir("static-mmap");
+        let path = root.join("data.bin");
+        fs::write(&path, patterned(300_000)).unwrap();
+
+        for (start, end) in [(0, 300_000), (0, 1), (4095, 4097), (123_456, 299_999), (299_999, 300_000)] {
+            let mapped = path.as_path().respond_with(HttpResponse::new(crate::HTTP_OK), start, end).unwrap();
+            assert!(matches!(mapped.stream, Some(crate::StreamedBody::Mapped(..))), "{}..{} wasn't mapped", start, end);
+            let read = HttpResponse::new(crate::HTTP_OK).with_body(read_file_range(&path, start, end).unwrap());
+            assert!(sent(mapped) == sent(read), "{}..{} differs", start, end);
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn empty_files_are_read_not_mapped() {
+        let root = temp_dir("static-mmap-empty");
+        let path = root.join("empty.txt");
+        fs::write(&path, b"").unwrap();
+        let response = path.as_path().respond_with(HttpResponse::new(crate::HTTP_OK), 0, 0).unwrap();
+        assert!(response.stream.is_none());
+        assert_eq!(sent(response), b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn head_answers_from_metadata_alone() {
+        // A sparse terabyte: reading it to answer the `HEAD` would never
+        // finish, let alone fit in memory
+        let root = temp_dir("static-head-large");
+        File::create(root.join("huge.bin")).unwrap().set_len(1 << 40).unwrap();
+        let (config, router) = static_setup(&root);
+        let response = exchange(&config, &router, b"HEAD /static/huge.bin HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Length"), Some("1099511627776"));
+        assert_eq!(header(&response, "Content-Type"), Some("application/octet-stream"));
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn head_sends_the_headers_get_does() {
+        let root = temp_dir("static-head-parity");
+        fs::write(root.join("page.html"), "<p>hello</p>\n".repeat(200)).unwrap();
+        let (config, router) = static_setup(&root);
+
+        for accept in ["", "Accept-Encoding: gzip\r\n"] {
+            let request = |method: &str| format!("{} /static/page.html HTTP/1.1\r\nHost: x\r\n{}\r\n", method, accept);
+            let get = exchange(&config, &router, request("GET").as_bytes());
+            let head = exchange(&config, &router, request("HEAD").as_bytes());
+            assert!(head.ends_with("\r\n\r\n"), "{}", head);
+            let get_headers: Vec<_> = header_lines(&get).into_iter()
+                .filter(|line| accept.is_empty() || !line.starts_with("Content-Length:"))
+                .collect();
+            assert_eq!(header_lines(&head), get_headers, "with {:?}", accept);
+            assert_eq!(header(&head, "Content-Type"), Some("text/html; charset=utf-8"));
+        }
+    }
+
+    #[test]
+    fn gzipped_responses_get_their_own_etag() {
+        let root = temp_dir("static-etag");
+        fs::write(root.join("app.js"), "console.log('hello');\n".repeat(100)).unwrap();
+        let (config, router) = static_setup(&root);
+
+        let plain = exchange(&config, &router, b"GET /static/app.js HTTP/1.1\r\nHost: x\r\n\r\n");
+        let gzipped = exchange(&config, &router, b"GET /static/app.js HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n");
+        let head = exchange(&config, &router, b"HEAD /static/app.js HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n");
+        let etag = header(&plain, "ETag").unwrap();
+        assert_eq!(header(&gzipped, "Content-Encoding"), Some("gzip"));
+        assert_eq!(header(&gzipped, "ETag"), Some(format!("{}-gzip\"", &etag[..etag.len() - 1]).as_str()));
+        assert_eq!(header(&head, "ETag"), header(&gzipped, "ETag"));
+        assert_eq!(header(&gzipped, "Vary"), Some("Accept-Encoding"));
+        assert_eq!(header(&plain, "Vary"), Some("Accept-Encoding"));
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+// Certificate chain and private key files, both PEM
+pub struct CertKeyPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+// Picks the certificate for a TLS handshake from the server name the client
+// sent with SNI, `None` when it sent none. The server doesn't terminate TLS
+// itself yet; this is what an acceptor asks for on each ClientHello.
+pub trait CertResolver: Send + Sync {
+    fn resolve(&self, server_name: Option<&str>) -> &CertKeyPaths;
+}
+
+// Certificates by hostname, with a default for clients that send no SNI or
+// a name without its own certificate
+pub struct SniCertificates {
+    by_host: HashMap<String, CertKeyPaths>,
+    default: CertKeyPaths,
+}
+
+impl SniCertificates {
+    pub fn new(default: CertKeyPaths) -> Self {
+        SniCertificates { by_host: HashMap::new(), default }
+    }
+
+    // Serve `paths` to clients asking for `host`. Hostnames are case
+    // insensitive and a trailing dot is ignored.
+    pub fn with_host(mut self, host: &str, paths: CertKeyPaths) -> Self {
+        self.by_host.insert(normalize_host(host), paths);
+        self
+    }
+
+    // Parse `cert:key,host=cert:key,...`: the files of the default
+    // certificate, then those of each hostname with its own
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut entries = spec.split(',').map(str::trim);
+        let default = entries.next()
+            .filter(|entry| !entry.contains('='))
+            .and_then(parse_paths)
+            .ok_or("missing the default certificate")?;
+        entries.try_fold(SniCertificates::new(default), |certificates, entry| {
+            let (host, paths) = entry.split_once('=')
+                .filter(|(host, _)| !host.is_empty())
+                .and_then(|(host, paths)| Some((host, parse_paths(paths)?)))
+                .ok_or_else(|| format!("bad certificate entry {:?}", entry))?;
+            Ok(certificates.with_host(host, paths))
+        })
+    }
+
+    // Make sure every configured certificate and key can be opened, so a
+    // typo fails at startup rather than on some client's first handshake
+    pub fn check_readable(&self) -> io::Result<()> {
+        let names = self.by_host.keys().map(|host| Some(host.as_str()));
+        for name in names.chain([None]) {
+            let paths = self.resolve(name);
+            for path in [&paths.cert, &paths.key] {
+                File::open(path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CertResolver for SniCertificates {
+    fn resolve(&self, server_name: Option<&str>) -> &CertKeyPaths {
+        server_name
+            .and_then(|name| self.by_host.get(&normalize_host(name)))
+            .unwrap_or(&self.default)
+    }
+}
+
+// `cert:key`, neither of them empty
+fn parse_paths(entry: &str) -> Option<CertKeyPaths> {
+    let (cert, key) = entry.split_once(':')?;
+    if cert.is_empty() || key.is_empty() {
+        return None;
+    }
+    Some(CertKeyPaths { cert: PathBuf::from(cert), key: PathBuf::from(key) })
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_dir;
+    use std::fs;
+    use std::path::Path;
+
+    fn paths(dir: &Path, name: &str) -> CertKeyPaths {
+        CertKeyPaths { cert: dir.join(format!("{}.crt", name)), key: dir.join(format!("{}.key", name)) }
+    }
+
+    #[test]
+    fn each_hostname_gets_its_own_certificate() {
+        let dir = temp_dir("tls-sni");
+        let certificates = SniCertificates::new(paths(&dir, "default"))
+            .with_host("a.example", paths(&dir, "a"))
+            .with_host("B.example", paths(&dir, "b"));
+
+        assert_eq!(certificates.resolve(Some("a.example")).cert, dir.join("a.crt"));
+        assert_eq!(certificates.resolve(Some("b.example")).key, dir.join("b.key"));
+        assert_eq!(certificates.resolve(Some("A.Example.")).cert, dir.join("a.crt"));
+        // No SNI, or a name without its own, get the default
+        assert_eq!(certificates.resolve(None).cert, dir.join("default.crt"));
+        assert_eq!(certificates.resolve(Some("c.example")).cert, dir.join("default.crt"));
+    }
+
+    #[test]
+    fn certificate_lists_name_the_default_first() {
+        let certificates = SniCertificates::parse("d.crt:d.key, a.example=a.crt:a.key").unwrap();
+        assert_eq!(certificates.resolve(None).key, PathBuf::from("d.key"));
+        assert_eq!(certificates.resolve(Some("a.example")).cert, PathBuf::from("a.crt"));
+
+        for spec in ["", "a.example=a.crt:a.key", "d.crt", "d.crt:d.key,a.crt:a.key", "d.crt:d.key,=a.crt:a.key",
+            "d.crt:d.key,a.example=a.crt:"] {
+            assert!(SniCertificates::parse(spec).is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn unreadable_certificates_are_found_up_front() {
+        let dir = temp_dir("tls-check");
+        for name in ["default.crt", "default.key", "a.crt", "a.key"] {
+            fs::write(dir.join(name), "PEM").unwrap();
+        }
+        let certificates = SniCertificates::new(paths(&dir, "default")).with_host("a.example", paths(&dir, "a"));
+        certificates.check_readable().unwrap();
+
+        let certificates = certificates.with_host("b.example", paths(&dir, "b"));
+        let error = certificates.check_readable().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("b.crt"), "{}", error);
+    }
+}